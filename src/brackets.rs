@@ -0,0 +1,187 @@
+// Just enough lexing to tell the brackets in code from the ones
+// in comments and string literals.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScanState {
+    Code,
+    BlockComment,
+}
+
+pub struct Syntax {
+    pub line_comment: Option<&'static str>,
+    pub block_comment: Option<(&'static str, &'static str)>,
+    // a string ends with the same quote or at the end of the line
+    pub quotes: &'static str,
+    // 'x' is a char literal, but 'a alone is a lifetime
+    pub char_literals: bool,
+}
+
+pub fn syntax_for(ext: &str) -> Syntax {
+    let c_like = |quotes| Syntax {
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        quotes,
+        char_literals: false,
+    };
+    match ext {
+        "rs" => Syntax { char_literals: true, ..c_like("\"") },
+        "c" | "h" | "cpp" | "cc" | "hpp" | "cs" | "java" | "go" | "kt" | "swift" => c_like("\"'"),
+        "js" | "ts" | "jsx" | "tsx" => c_like("\"'`"),
+        "py" | "sh" | "rb" | "pl" | "toml" | "yaml" | "yml" => Syntax {
+            line_comment: Some("#"),
+            block_comment: None,
+            quotes: "\"'",
+            char_literals: false,
+        },
+        _ => Syntax {
+            line_comment: None,
+            block_comment: None,
+            quotes: "",
+            char_literals: false,
+        },
+    }
+}
+
+pub fn closing(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        _ => None,
+    }
+}
+
+pub fn is_bracket(c: char) -> bool {
+    "()[]{}".contains(c)
+}
+
+fn starts_with_at(chars: &[char], i: usize, s: &str) -> bool {
+    s.chars().enumerate().all(|(j, c)| chars.get(i + j) == Some(&c))
+}
+
+pub struct LineScan {
+    pub start_state: ScanState,
+    pub end_state: ScanState,
+    // char offsets in the line
+    pub brackets: Vec<(usize, char)>,
+}
+
+pub fn scan_line(line: &[char], start_state: ScanState, syntax: &Syntax) -> LineScan {
+    let mut state = start_state;
+    let mut brackets = Vec::new();
+    let n = line.len();
+    let mut i = 0;
+    while i < n {
+        if state == ScanState::BlockComment {
+            let end = syntax.block_comment.unwrap().1;
+            if starts_with_at(line, i, end) {
+                state = ScanState::Code;
+                i += end.chars().count();
+            } else {
+                i += 1;
+            }
+            continue;
+        }
+        if let Some(lc) = syntax.line_comment {
+            if starts_with_at(line, i, lc) {
+                break;
+            }
+        }
+        if let Some((start, _)) = syntax.block_comment {
+            if starts_with_at(line, i, start) {
+                state = ScanState::BlockComment;
+                i += start.chars().count();
+                continue;
+            }
+        }
+        let c = line[i];
+        if syntax.char_literals && c == '\'' {
+            if line.get(i + 1) == Some(&'\\') {
+                i += 2;
+                while i < n && line[i] != '\'' {
+                    i += 1;
+                }
+                i += 1;
+            } else if line.get(i + 2) == Some(&'\'') {
+                i += 3;
+            } else {
+                i += 1;
+            }
+            continue;
+        }
+        if syntax.quotes.contains(c) {
+            i += 1;
+            while i < n && line[i] != c {
+                if line[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            continue;
+        }
+        if is_bracket(c) {
+            brackets.push((i, c));
+        }
+        i += 1;
+    }
+    LineScan { start_state, end_state: state, brackets }
+}
+
+// For every line, the line of the bracket matching the first one
+// left open on it, the same one a search from that bracket finds.
+// Takes the brackets of each line, and goes over them once.
+pub fn matching_lines(lines: impl Iterator<Item = Vec<char>>, max_distance: usize) -> Vec<Option<usize>> {
+    let mut result = Vec::new();
+    // open brackets with their lines, and whether it's the first
+    // one left open on its line
+    let mut stack: Vec<(usize, char, bool)> = Vec::new();
+    for (i, brackets) in lines.enumerate() {
+        result.push(None);
+        for c in brackets {
+            if closing(c).is_some() {
+                let first = stack.last().is_none_or(|&(line, _, _)| line != i);
+                stack.push((i, c, first));
+            } else if let Some((line, open, first)) = stack.pop() {
+                if first && line < i && i - line <= max_distance && closing(open) == Some(c) {
+                    result[line] = Some(i);
+                }
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn brackets(line: &str, ext: &str) -> (String, ScanState) {
+        let chars: Vec<char> = line.chars().collect();
+        let scan = scan_line(&chars, ScanState::Code, &syntax_for(ext));
+        (scan.brackets.iter().map(|&(_, c)| c).collect(), scan.end_state)
+    }
+
+    #[test]
+    fn scanning() {
+        assert_eq!(brackets("f(a[1], \"(\", ')') // )", "rs"), ("([])".to_owned(), ScanState::Code));
+        assert_eq!(brackets("fn f<'a>(x: &'a str) {", "rs"), ("(){".to_owned(), ScanState::Code));
+        assert_eq!(brackets("x = '\\''; y(", "c"), ("(".to_owned(), ScanState::Code));
+        assert_eq!(brackets("a(/* ) */ b) /* {", "js"), ("()".to_owned(), ScanState::BlockComment));
+        assert_eq!(brackets("d = {'}': 1}  # }", "py"), ("{}".to_owned(), ScanState::Code));
+        assert_eq!(brackets("it's (fine)", "txt"), ("()".to_owned(), ScanState::Code));
+
+        let chars: Vec<char> = "still */ (".chars().collect();
+        let scan = scan_line(&chars, ScanState::BlockComment, &syntax_for("c"));
+        assert_eq!(scan.brackets, vec![(9, '(')]);
+    }
+
+    #[test]
+    fn matching() {
+        let lines = ["(){", "[(", "", ")]", "}{", "(", "}"];
+        let m = matching_lines(lines.iter().map(|l| l.chars().collect()), 10);
+        assert_eq!(m, vec![Some(4), Some(3), None, None, None, None, None]);
+        let m = matching_lines(lines.iter().map(|l| l.chars().collect()), 3);
+        assert_eq!(m[0], None);
+    }
+}
@@ -0,0 +1,189 @@
+// Diagnostics from build commands. Understands cargo/rustc JSON
+// (--message-format=json), rustc human-readable output, and the common
+// "file:line:col: error: message" format of gcc, clang, tsc and friends.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use super::lsp::Severity;
+
+// Lines and columns are 0-based, columns are in chars.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+pub struct BuildResult {
+    pub success: bool,
+    pub messages: Vec<Message>,
+}
+
+fn parse_severity(s: &str) -> Option<Severity> {
+    let s = s.trim();
+    if s.starts_with("error") || s.starts_with("fatal error") {
+        Some(Severity::Error)
+    } else if s.starts_with("warning") {
+        Some(Severity::Warning)
+    } else if s.starts_with("note") || s.starts_with("info") || s.starts_with("help") {
+        Some(Severity::Info)
+    } else {
+        None
+    }
+}
+
+fn json_message(line: &str, root: &Path) -> Option<Message> {
+    let v: Value = serde_json::from_str(line).ok()?;
+    if v["reason"] != "compiler-message" {
+        return None;
+    }
+    let m = &v["message"];
+    let severity = parse_severity(m["level"].as_str()?)?;
+    let span = m["spans"].as_array()?.iter().find(|s| s["is_primary"] == true)?;
+    let num = |key: &str| span[key].as_u64().unwrap_or(1).max(1) as usize - 1;
+    let mut message = m["message"].as_str()?.to_owned();
+    if let Some(label) = span["label"].as_str() {
+        message = format!("{} ({})", message, label);
+    }
+    Some(Message {
+        path: root.join(span["file_name"].as_str()?),
+        line: num("line_start"),
+        column: num("column_start"),
+        end_line: num("line_end"),
+        end_column: num("column_end"),
+        severity,
+        message,
+    })
+}
+
+// "path:line:col: severity: message" or "path:line: severity: message"
+fn gcc_message(line: &str, root: &Path) -> Option<Message> {
+    // don't mistake drive letter for a separator
+    let skip = if line.as_bytes().get(1) == Some(&b':') { 2 } else { 0 };
+    let mut parts = line[skip..].splitn(4, ':');
+    let path = &line[..skip + parts.next()?.len()];
+    let line_no: usize = parts.next()?.trim().parse().ok()?;
+    let mut rest = parts.next()?;
+    let mut tail = parts.next().unwrap_or("");
+    let column = match rest.trim().parse::<usize>() {
+        Ok(c) => {
+            rest = tail;
+            tail = "";
+            c
+        }
+        Err(_) => 1,
+    };
+    let rest = if tail.is_empty() { rest.to_owned() } else { format!("{}:{}", rest, tail) };
+    let p = rest.find(':')?;
+    let severity = parse_severity(&rest[..p])?;
+    let line_no = line_no.max(1) - 1;
+    let column = column.max(1) - 1;
+    Some(Message {
+        path: root.join(path.trim()),
+        line: line_no,
+        column,
+        end_line: line_no,
+        end_column: column,
+        severity,
+        message: rest[p + 1..].trim().to_owned(),
+    })
+}
+
+// Returns messages in the order of appearance, without duplicates.
+pub fn parse(output: &str, root: &Path) -> Vec<Message> {
+    let mut result: Vec<Message> = Vec::new();
+    // rustc human-readable format has the message on one line
+    // and the location on the next one:
+    //     error[E0425]: cannot find value `x` in this scope
+    //       --> src/main.rs:2:5
+    let mut header: Option<(Severity, String)> = None;
+    for line in output.lines() {
+        let trimmed = line.trim_start();
+        let m = if trimmed.starts_with('{') {
+            json_message(trimmed, root)
+        } else if let Some(location) = trimmed.strip_prefix("--> ") {
+            header.take().and_then(|(severity, message)| {
+                let mut m = gcc_message(&format!("{}: error: x", location), root)?;
+                m.severity = severity;
+                m.message = message;
+                Some(m)
+            })
+        } else {
+            gcc_message(line, root)
+        };
+        if let Some(m) = m {
+            if !result.contains(&m) {
+                result.push(m);
+            }
+            continue;
+        }
+        header = None;
+        if !line.starts_with(' ') {
+            if let Some(p) = line.find(": ") {
+                if let Some(severity) = parse_severity(&line[..p]) {
+                    header = Some((severity, line[p + 2..].to_owned()));
+                }
+            }
+        }
+    }
+    result
+}
+
+// Runs the command in the root directory, blocking.
+pub fn run(command_line: &str, root: &Path) -> Result<BuildResult, String> {
+    let output = super::filter::shell_command(command_line)
+        .current_dir(root)
+        .output()
+        .map_err(|e| format!("Can't run {:?}.\n{}", command_line, e))?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push('\n');
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(BuildResult {
+        success: output.status.success(),
+        messages: parse(&text, root),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let root = Path::new("proj");
+        let output = r#"
+{"reason":"compiler-artifact","target":{}}
+{"reason":"compiler-message","message":{"level":"warning","message":"unused variable: `x`","spans":[{"file_name":"src/main.rs","line_start":2,"line_end":2,"column_start":9,"column_end":10,"is_primary":true,"label":null}]}}
+{"reason":"compiler-message","message":{"level":"error","message":"aborting due to previous error","spans":[]}}
+error[E0425]: cannot find value `y` in this scope
+  --> src/lib.rs:10:5
+   |
+lib.c:3:7: error: expected ';' before '}' token
+C:\src\a.c:4: warning: implicit declaration: foo
+Compiling foo v0.1.0
+error: could not compile `foo`
+"#;
+        let messages = parse(output, root);
+        let m = |path: &str, line, column, end_column, severity, message: &str| Message {
+            path: root.join(path),
+            line,
+            column,
+            end_line: line,
+            end_column,
+            severity,
+            message: message.to_owned(),
+        };
+        assert_eq!(messages, vec![
+            m("src/main.rs", 1, 8, 9, Severity::Warning, "unused variable: `x`"),
+            m("src/lib.rs", 9, 4, 4, Severity::Error, "cannot find value `y` in this scope"),
+            m("lib.c", 2, 6, 6, Severity::Error, "expected ';' before '}' token"),
+            m("C:\\src\\a.c", 3, 0, 0, Severity::Warning, "implicit declaration: foo"),
+        ]);
+    }
+}
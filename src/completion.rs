@@ -0,0 +1,198 @@
+// Completion popup state and word completion.
+// Words come from the document itself: each occurrence of a word scores
+// 1 / (1 + distance in lines from the cursor), so frequent words
+// and words used nearby come first.
+
+use std::collections::HashMap;
+
+use super::lsp::CompletionItem;
+
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Identifiers in the line, in order, with repetitions.
+// Single chars and numbers are not worth completing.
+pub fn split_words(line: &str) -> Vec<String> {
+    line.split(|c: char| !is_word_char(c))
+        .filter(|w| w.chars().nth(1).is_some() && !w.starts_with(|c: char| c.is_numeric()))
+        .map(|w| w.to_owned())
+        .collect()
+}
+
+pub struct WordRanker<'a> {
+    prefix: &'a str,
+    // the word being typed, it doesn't count as an occurrence
+    current_word: &'a str,
+    cursor_line: usize,
+    scores: HashMap<String, f64>,
+}
+
+impl<'a> WordRanker<'a> {
+    pub fn new(prefix: &'a str, current_word: &'a str, cursor_line: usize) -> Self {
+        WordRanker {
+            prefix,
+            current_word,
+            cursor_line,
+            scores: HashMap::new(),
+        }
+    }
+
+    pub fn add_line(&mut self, line_no: usize, words: &[String]) {
+        let distance = line_no.abs_diff(self.cursor_line);
+        let mut skip_current = line_no == self.cursor_line;
+        for w in words {
+            if skip_current && w == self.current_word {
+                skip_current = false;
+                continue;
+            }
+            if w.len() > self.prefix.len() && w.starts_with(self.prefix) {
+                *self.scores.entry(w.clone()).or_insert(0.0) += 1.0 / (1.0 + distance as f64);
+            }
+        }
+    }
+
+    // Best first.
+    pub fn finish(self) -> Vec<String> {
+        let mut words: Vec<(String, f64)> = self.scores.into_iter().collect();
+        words.sort_by(|(w1, s1), (w2, s2)| s2.partial_cmp(s1).unwrap().then_with(|| w1.cmp(w2)));
+        words.into_iter().map(|(w, _)| w).collect()
+    }
+}
+
+pub const MAX_VISIBLE_ITEMS: usize = 10;
+
+pub struct Popup {
+    // where the word being completed starts
+    pub start: usize,
+    items: Vec<CompletionItem>,
+    prefix: String,
+    // indices of the items matching the prefix
+    matching: Vec<usize>,
+    selected: usize,
+    first_visible: usize,
+}
+
+fn matches_prefix(item: &CompletionItem, prefix: &str) -> bool {
+    let prefix = prefix.to_lowercase();
+    item.label.to_lowercase().starts_with(&prefix)
+        || item.insert_text.to_lowercase().starts_with(&prefix)
+}
+
+impl Popup {
+    // None if nothing matches.
+    pub fn new(start: usize, items: Vec<CompletionItem>, prefix: &str) -> Option<Popup> {
+        let mut popup = Popup {
+            start,
+            items,
+            prefix: String::new(),
+            matching: Vec::new(),
+            selected: 0,
+            first_visible: 0,
+        };
+        popup.filter_items(prefix);
+        if !popup.matching.is_empty() {
+            Some(popup)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_words(start: usize, words: Vec<String>, prefix: &str) -> Option<Popup> {
+        let items = words.into_iter()
+            .map(|w| CompletionItem { label: w.clone(), insert_text: w })
+            .collect();
+        Popup::new(start, items, prefix)
+    }
+
+    // Returns false if nothing matches anymore.
+    pub fn filter(&mut self, prefix: &str) -> bool {
+        if prefix != self.prefix {
+            self.filter_items(prefix);
+        }
+        !self.matching.is_empty()
+    }
+
+    fn filter_items(&mut self, prefix: &str) {
+        self.prefix = prefix.to_owned();
+        self.matching = (0..self.items.len())
+            .filter(|&i| matches_prefix(&self.items[i], prefix))
+            .collect();
+        self.selected = 0;
+        self.first_visible = 0;
+    }
+
+    pub fn move_selection(&mut self, delta: isize) {
+        let n = self.matching.len() as isize;
+        self.selected = (self.selected as isize + delta).max(0).min(n - 1) as usize;
+        if self.selected < self.first_visible {
+            self.first_visible = self.selected;
+        }
+        if self.selected >= self.first_visible + MAX_VISIBLE_ITEMS {
+            self.first_visible = self.selected + 1 - MAX_VISIBLE_ITEMS;
+        }
+    }
+
+    pub fn selected_item(&self) -> &CompletionItem {
+        &self.items[self.matching[self.selected]]
+    }
+
+    // Labels on screen and the index of the selected one among them.
+    pub fn visible_labels(&self) -> (Vec<&str>, usize) {
+        let labels = self.matching.iter()
+            .skip(self.first_visible)
+            .take(MAX_VISIBLE_ITEMS)
+            .map(|&i| self.items[i].label.as_str())
+            .collect();
+        (labels, self.selected - self.first_visible)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ranking() {
+        assert_eq!(split_words("let x = foo_bar(42, y2, 3d);"), vec!["let", "foo_bar", "y2"]);
+
+        let lines = [
+            "fn print_all(items: &[Item]) {",
+            "    for item in items {",
+            "        println!(\"{}\", item);",
+            "        pri",
+            "    }",
+            "}",
+            "// primary",
+        ];
+        let mut ranker = WordRanker::new("pri", "pri", 3);
+        for (i, line) in lines.iter().enumerate() {
+            ranker.add_line(i, &split_words(line));
+        }
+        assert_eq!(ranker.finish(), vec!["println", "primary", "print_all"]);
+
+        // the word under the cursor is not suggested unless it's used elsewhere
+        let mut ranker = WordRanker::new("it", "items", 0);
+        ranker.add_line(0, &split_words("items"));
+        assert_eq!(ranker.finish(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn popup() {
+        let words: Vec<String> = (0..15).map(|i| format!("word{}", i)).collect();
+        let mut popup = Popup::from_words(0, words, "w").unwrap();
+        popup.move_selection(12);
+        assert!(popup.filter("w"));
+        assert_eq!(popup.selected_item().label, "word12");
+        let (labels, selected) = popup.visible_labels();
+        assert_eq!(labels.len(), MAX_VISIBLE_ITEMS);
+        assert_eq!(labels[selected], "word12");
+        popup.move_selection(100);
+        assert_eq!(popup.selected_item().label, "word14");
+
+        assert!(popup.filter("Word1"));
+        assert_eq!(popup.visible_labels().0, vec!["word1", "word10", "word11", "word12", "word13", "word14"]);
+        assert!(!popup.filter("wordx"));
+        assert!(Popup::from_words(0, vec![], "").is_none());
+    }
+}
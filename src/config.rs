@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use super::indent::IndentStyle;
+use super::view_state::{self, LineNumbers, Wrap};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Keymap {
+    Default,
+    Emacs,
+}
+
+pub struct Config {
+    pub keymap: Keymap,
+    // for filter commands and formatters
+    pub filter_timeout: Duration,
+    // "format.rs = rustfmt --emit stdout" maps extension "rs" to the command,
+    // see formatter_for()
+    formatters: HashMap<String, String>,
+    pub format_on_save: bool,
+    // "lsp.rs = rust-analyzer", the same way
+    language_servers: HashMap<String, String>,
+    // run in the project root (see lsp::find_root())
+    pub build_command: String,
+    // word length that brings up the completion popup, 0 to disable
+    pub complete_after: usize,
+    // for new files and the files where it can't be detected
+    pub indent: IndentStyle,
+    // insert closing brackets and quotes
+    pub auto_close: bool,
+    pub line_numbers: LineNumbers,
+    // what the wrap toggle switches to, when it's not None
+    pub wrap: Wrap,
+    // wrapped rows start at the indentation of the line
+    pub wrap_indent: bool,
+    // how many lines keep their text layouts
+    pub layout_cache: usize,
+    // files from this size on (in MB) are opened read only
+    pub large_file_mb: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            keymap: Keymap::Default,
+            filter_timeout: Duration::from_secs(10),
+            formatters: HashMap::new(),
+            format_on_save: true,
+            language_servers: HashMap::new(),
+            build_command: "cargo check --message-format=json".to_owned(),
+            complete_after: 3,
+            indent: IndentStyle::Spaces(4),
+            auto_close: true,
+            line_numbers: LineNumbers::Absolute,
+            wrap: Wrap::Window,
+            wrap_indent: false,
+            layout_cache: view_state::DEFAULT_LAYOUT_CACHE_CAPACITY,
+            large_file_mb: 256,
+        }
+    }
+}
+
+// %APPDATA%\an_editor
+pub fn config_dir() -> Option<PathBuf> {
+    std::env::var_os("APPDATA").map(|d| PathBuf::from(d).join("an_editor"))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("expected true or false, got {:?}", value)),
+    }
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let mut config = Config::default();
+        let path = match config_dir() {
+            Some(dir) => dir.join("config.txt"),
+            None => return config,
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(s) => {
+                info!("loading {}", path.to_string_lossy());
+                for e in config.parse(&s) {
+                    warn!("{}: {}", path.to_string_lossy(), e);
+                }
+            }
+            Err(e) => info!("can't read {}: {}", path.to_string_lossy(), e),
+        }
+        config
+    }
+
+    // Format is one "key = value" per line, '#' starts a comment line.
+    // Returns errors, but applies all the valid lines anyway.
+    fn parse(&mut self, s: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let res = match line.find('=') {
+                Some(p) => self.set(line[..p].trim(), line[p + 1..].trim()),
+                None => Err("expected 'key = value'".to_owned()),
+            };
+            if let Err(e) = res {
+                errors.push(format!("line {}: {}", i + 1, e));
+            }
+        }
+        errors
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "keymap" => {
+                self.keymap = match value {
+                    "default" => Keymap::Default,
+                    "emacs" => Keymap::Emacs,
+                    _ => return Err(format!("unknown keymap {:?}", value)),
+                };
+            }
+            "filter_timeout" => {
+                let secs: f64 = value.parse()
+                    .map_err(|_| format!("expected number of seconds, got {:?}", value))?;
+                if !(secs > 0.0 && secs < 1e6) {
+                    return Err(format!("bad timeout {}", secs));
+                }
+                self.filter_timeout = Duration::from_secs_f64(secs);
+            }
+            "format_on_save" => self.format_on_save = parse_bool(value)?,
+            "auto_close" => self.auto_close = parse_bool(value)?,
+            "line_numbers" => {
+                self.line_numbers = match value {
+                    "off" => LineNumbers::Off,
+                    "absolute" => LineNumbers::Absolute,
+                    "relative" => LineNumbers::Relative,
+                    _ => return Err(format!("expected off, absolute or relative, got {:?}", value)),
+                };
+            }
+            "wrap" => {
+                self.wrap = match value {
+                    "none" => Wrap::None,
+                    "window" => Wrap::Window,
+                    _ => match value.parse() {
+                        Ok(n) if n > 0 => Wrap::Column(n),
+                        _ => return Err(format!("expected none, window or column number, got {:?}", value)),
+                    },
+                };
+            }
+            "wrap_indent" => self.wrap_indent = parse_bool(value)?,
+            "layout_cache" => {
+                self.layout_cache = match value.parse() {
+                    // at least a screenful, the lines on screen can't be evicted
+                    Ok(n) if n >= 100 => n,
+                    _ => return Err(format!("expected number of lines, at least 100, got {:?}", value)),
+                };
+            }
+            "large_file_mb" => {
+                self.large_file_mb = value.parse()
+                    .map_err(|_| format!("expected size in MB, got {:?}", value))?;
+            }
+            "build_command" => self.build_command = value.to_owned(),
+            "indent" => self.indent = IndentStyle::parse(value)?,
+            "complete_after" => {
+                self.complete_after = value.parse()
+                    .map_err(|_| format!("expected number of chars, got {:?}", value))?;
+            }
+            _ => {
+                let (map, ext) = if let Some(ext) = key.strip_prefix("format.") {
+                    (&mut self.formatters, ext)
+                } else if let Some(ext) = key.strip_prefix("lsp.") {
+                    (&mut self.language_servers, ext)
+                } else {
+                    return Err(format!("unknown key {:?}", key));
+                };
+                if ext.is_empty() {
+                    return Err(format!("no extension in {:?}", key));
+                }
+                map.insert(ext.to_lowercase(), value.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    // The formatter reads the document from stdin and writes
+    // the result to stdout. "{file}" in the command is replaced
+    // with the file path, for formatters that want to know it.
+    pub fn formatter_for(&self, path: &Path) -> Option<String> {
+        let command = command_for_extension(&self.formatters, path)?;
+        Some(command.replace("{file}", &format!("\"{}\"", path.to_string_lossy())))
+    }
+
+    pub fn language_server_for(&self, path: &Path) -> Option<String> {
+        command_for_extension(&self.language_servers, path).cloned()
+    }
+}
+
+fn command_for_extension<'a>(map: &'a HashMap<String, String>, path: &Path) -> Option<&'a String> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    map.get(&ext)
+}
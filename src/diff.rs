@@ -0,0 +1,192 @@
+// Minimal-ish diff to turn one text into another with few small edits.
+// Lines are matched with Myers' algorithm, then each changed hunk
+// is narrowed down by trimming its common prefix and suffix chars.
+
+// Replace old[start..end] with text, positions are in chars.
+#[derive(Debug, PartialEq)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+// Beyond that many differing lines, the whole changed region
+// becomes one edit, to keep time and memory in check.
+const MAX_LINE_EDITS: usize = 1000;
+
+// Returns pairs of matching line indices in ascending order,
+// or None if the texts are too different.
+fn match_lines(a: &[&str], b: &[&str]) -> Option<Vec<(usize, usize)>> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max_d = MAX_LINE_EDITS as isize;
+    let offset = max_d + 1;
+    let mut v = vec![0isize; 2 * max_d as usize + 3];
+    // trace[d] is v[-d - 1..=d + 1] before step d
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut found = false;
+    for d in 0..=max_d.min(n + m) {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                found = true;
+                break;
+            }
+        }
+        if found {
+            break;
+        }
+    }
+    if !found {
+        return None;
+    }
+
+    let mut result = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let get = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+                k + 1
+            } else {
+                k - 1
+            };
+            (get(prev_k), get(prev_k) - prev_k)
+        };
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            result.push((x as usize, y as usize));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    result.reverse();
+    Some(result)
+}
+
+fn common_prefix_len(a: &[char], b: &[char]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+pub fn diff(old: &str, new: &str) -> Vec<Edit> {
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+
+    let mut matches: Vec<(usize, usize)> = match_lines(a_mid, b_mid)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(i, j)| (i + prefix, j + prefix))
+        .collect();
+    // sentinel so that the trailing hunk is handled like the others
+    matches.push((a.len() - suffix, b.len() - suffix));
+
+    let mut line_starts = Vec::with_capacity(a.len() + 1);
+    let mut pos = 0;
+    line_starts.push(pos);
+    for line in &a {
+        pos += line.chars().count();
+        line_starts.push(pos);
+    }
+
+    let mut result = Vec::new();
+    let (mut i, mut j) = (prefix, prefix);
+    for (mi, mj) in matches {
+        if mi > i || mj > j {
+            let old_chars: Vec<char> = a[i..mi].iter().flat_map(|s| s.chars()).collect();
+            let new_chars: Vec<char> = b[j..mj].iter().flat_map(|s| s.chars()).collect();
+            let p = common_prefix_len(&old_chars, &new_chars);
+            let s = old_chars[p..].iter().rev()
+                .zip(new_chars[p..].iter().rev())
+                .take_while(|(x, y)| x == y)
+                .count();
+            result.push(Edit {
+                start: line_starts[i] + p,
+                end: line_starts[mi] - s,
+                text: new_chars[p..new_chars.len() - s].iter().collect(),
+            });
+        }
+        i = mi + 1;
+        j = mj + 1;
+    }
+    result
+}
+
+// The edits are in ascending order and don't overlap.
+pub fn apply(old: &str, edits: &[Edit]) -> String {
+    let mut chars: Vec<char> = old.chars().collect();
+    for e in edits.iter().rev() {
+        chars.splice(e.start..e.end, e.text.chars());
+    }
+    chars.into_iter().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn small_edits() {
+        let cases = [
+            ("", ""),
+            ("", "a\nb"),
+            ("a\nb\n", ""),
+            ("a\nb\nc\n", "a\nc\n"),
+            ("a\nb\nc", "x\na\nb\nc\ny"),
+            ("fn f() {\n  1\n}\n", "fn f() {\n    1\n}\n"),
+            ("a\nb\nc\nd\ne\n", "b\na\nc\ne\nd\n"),
+            ("а\nб\nв", "а\nбб\nв"),
+        ];
+        for &(old, new) in &cases {
+            let edits = diff(old, new);
+            assert_eq!(apply(old, &edits), new, "{:?} -> {:?}", old, new);
+        }
+
+        let edits = diff("fn f() {\n  1\n}\n", "fn f() {\n    1\n}\n");
+        assert_eq!(edits, vec![Edit { start: 11, end: 11, text: "  ".to_owned() }]);
+        assert_eq!(diff("same\n", "same\n"), vec![]);
+    }
+
+    #[test]
+    fn large() {
+        let old: String = (0..3000).map(|i| format!("line {}\n", i)).collect();
+        let new = old.replace("line 1500\n", "line 1500!\n");
+        assert_eq!(diff(&old, &new).len(), 1);
+
+        // too many changes, falls back to one big edit
+        let new: String = (0..3000).map(|i| format!("line {}\n", i * 2)).collect();
+        let edits = diff(&old, &new);
+        assert_eq!(apply(&old, &edits), new);
+
+        let new: String = (0..3000)
+            .map(|i| if i % 10 == 0 { format!("changed {}\n", i) } else { format!("line {}\n", i) })
+            .collect();
+        let edits = diff(&old, &new);
+        assert_eq!(edits.len(), 300);
+        assert_eq!(apply(&old, &edits), new);
+    }
+}
@@ -0,0 +1,576 @@
+// EditorConfig (https://editorconfig.org): .editorconfig files
+// from the file's directory up to the one with "root = true",
+// the closer ones take precedence.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::diff::Edit;
+use super::indent::IndentStyle;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EndOfLine {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Charset {
+    Utf8,
+    Utf8Bom,
+    Latin1,
+    Utf16Be,
+    Utf16Le,
+}
+
+// Names as in .editorconfig.
+impl EndOfLine {
+    pub fn parse(s: &str) -> Option<EndOfLine> {
+        match s {
+            "lf" => Some(EndOfLine::Lf),
+            "crlf" => Some(EndOfLine::CrLf),
+            "cr" => Some(EndOfLine::Cr),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EndOfLine::Lf => "lf",
+            EndOfLine::CrLf => "crlf",
+            EndOfLine::Cr => "cr",
+        }
+    }
+}
+
+impl Charset {
+    pub fn parse(s: &str) -> Option<Charset> {
+        match s {
+            "utf-8" => Some(Charset::Utf8),
+            "utf-8-bom" => Some(Charset::Utf8Bom),
+            "latin1" => Some(Charset::Latin1),
+            "utf-16be" => Some(Charset::Utf16Be),
+            "utf-16le" => Some(Charset::Utf16Le),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Charset::Utf8 => "utf-8",
+            Charset::Utf8Bom => "utf-8-bom",
+            Charset::Latin1 => "latin1",
+            Charset::Utf16Be => "utf-16be",
+            Charset::Utf16Le => "utf-16le",
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Settings {
+    // "tab" or "space"
+    pub indent_style: Option<String>,
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub end_of_line: Option<EndOfLine>,
+    pub charset: Option<Charset>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
+}
+
+impl Settings {
+    // Unknown values are ignored, as the spec says.
+    fn from_properties(p: &HashMap<String, String>) -> Settings {
+        let get = |key: &str| p.get(key).map(|s| s.as_str());
+        let number = |key: &str| get(key).and_then(|s| s.parse().ok()).filter(|&n| n > 0);
+        let boolean = |key: &str| match get(key) {
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            _ => None,
+        };
+        let tab_width = number("tab_width");
+        Settings {
+            indent_style: get("indent_style").filter(|&s| s == "tab" || s == "space").map(|s| s.to_owned()),
+            indent_size: if get("indent_size") == Some("tab") { tab_width } else { number("indent_size") },
+            tab_width: tab_width.or_else(|| number("indent_size")),
+            end_of_line: get("end_of_line").and_then(EndOfLine::parse),
+            charset: get("charset").and_then(Charset::parse),
+            trim_trailing_whitespace: boolean("trim_trailing_whitespace"),
+            insert_final_newline: boolean("insert_final_newline"),
+        }
+    }
+
+    // What's not specified comes from the fallback (detected or configured).
+    pub fn indent(&self, fallback: IndentStyle) -> IndentStyle {
+        let size = match fallback {
+            IndentStyle::Spaces(n) => self.indent_size.unwrap_or(n),
+            IndentStyle::Tabs => self.indent_size.unwrap_or(4),
+        };
+        match self.indent_style.as_deref() {
+            Some("tab") => IndentStyle::Tabs,
+            Some(_) => IndentStyle::Spaces(size),
+            None => match fallback {
+                IndentStyle::Spaces(_) => IndentStyle::Spaces(size),
+                IndentStyle::Tabs => IndentStyle::Tabs,
+            },
+        }
+    }
+}
+
+struct Section {
+    glob: String,
+    properties: Vec<(String, String)>,
+}
+
+struct File {
+    root: bool,
+    sections: Vec<Section>,
+}
+
+fn parse(s: &str) -> File {
+    let mut file = File { root: false, sections: Vec::new() };
+    for line in s.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            file.sections.push(Section {
+                glob: line[1..line.len() - 1].to_owned(),
+                properties: Vec::new(),
+            });
+            continue;
+        }
+        let p = match line.find('=') {
+            Some(p) => p,
+            None => continue,
+        };
+        let key = line[..p].trim().to_lowercase();
+        let value = line[p + 1..].trim().to_owned();
+        match file.sections.last_mut() {
+            Some(section) => section.properties.push((key, value)),
+            None => if key == "root" {
+                file.root = value.eq_ignore_ascii_case("true");
+            }
+        }
+    }
+    file
+}
+
+#[derive(Clone, Debug)]
+enum Token {
+    Char(char),
+    // ?
+    AnyChar,
+    // *
+    Star,
+    // **
+    DoubleStar,
+    // [...] or [!...]
+    Class { negated: bool, ranges: Vec<(char, char)> },
+    // {a,b,c}
+    Alternatives(Vec<Vec<Token>>),
+    // {1..10}
+    Range(i64, i64),
+}
+
+// Index of the closing bracket, skipping escaped chars and nested pairs.
+fn find_closing(chars: &[char], start: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Splits on the commas that are not nested in braces.
+fn split_alternatives(chars: &[char]) -> Vec<&[char]> {
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(&chars[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    result.push(&chars[start..]);
+    result
+}
+
+fn parse_range(s: &str) -> Option<(i64, i64)> {
+    let p = s.find("..")?;
+    Some((s[..p].parse().ok()?, s[p + 2..].parse().ok()?))
+}
+
+fn parse_glob(chars: &[char]) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Char(chars[i + 1]));
+                i += 1;
+            }
+            '?' => tokens.push(Token::AnyChar),
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                tokens.push(Token::DoubleStar);
+                i += 1;
+            }
+            '*' => tokens.push(Token::Star),
+            '[' => match chars[i + 1..].iter().position(|&c| c == ']') {
+                Some(len) => {
+                    let mut class = &chars[i + 1..i + 1 + len];
+                    let negated = class.first() == Some(&'!');
+                    if negated {
+                        class = &class[1..];
+                    }
+                    let mut ranges = Vec::new();
+                    let mut j = 0;
+                    while j < class.len() {
+                        if j + 2 < class.len() && class[j + 1] == '-' {
+                            ranges.push((class[j], class[j + 2]));
+                            j += 3;
+                        } else {
+                            ranges.push((class[j], class[j]));
+                            j += 1;
+                        }
+                    }
+                    tokens.push(Token::Class { negated, ranges });
+                    i += len + 1;
+                }
+                None => tokens.push(Token::Char('[')),
+            },
+            '{' => match find_closing(chars, i, '{', '}') {
+                Some(end) => {
+                    let inner = &chars[i + 1..end];
+                    let alternatives = split_alternatives(inner);
+                    if let Some((a, b)) = parse_range(&inner.iter().collect::<String>()) {
+                        tokens.push(Token::Range(a, b));
+                    } else if alternatives.len() > 1 {
+                        tokens.push(Token::Alternatives(alternatives.into_iter().map(parse_glob).collect()));
+                    } else {
+                        // "{single}" is literal
+                        tokens.push(Token::Char('{'));
+                        tokens.extend(parse_glob(inner));
+                        tokens.push(Token::Char('}'));
+                    }
+                    i = end;
+                }
+                None => tokens.push(Token::Char('{')),
+            },
+            c => tokens.push(Token::Char(c)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+fn match_tokens(tokens: &[Token], s: &[char]) -> bool {
+    let (t, rest) = match tokens.split_first() {
+        Some(x) => x,
+        None => return s.is_empty(),
+    };
+    match t {
+        Token::Char(c) => s.first() == Some(c) && match_tokens(rest, &s[1..]),
+        Token::AnyChar => matches!(s.first(), Some(&c) if c != '/') && match_tokens(rest, &s[1..]),
+        Token::Star => {
+            let max = s.iter().position(|&c| c == '/').unwrap_or(s.len());
+            (0..=max).any(|k| match_tokens(rest, &s[k..]))
+        }
+        Token::DoubleStar => (0..=s.len()).any(|k| match_tokens(rest, &s[k..])),
+        Token::Class { negated, ranges } => match s.first() {
+            Some(&c) if c != '/' => {
+                let inside = ranges.iter().any(|&(a, b)| a <= c && c <= b);
+                inside != *negated && match_tokens(rest, &s[1..])
+            }
+            _ => false,
+        },
+        Token::Alternatives(alternatives) => alternatives.iter().any(|a| {
+            let mut seq = a.clone();
+            seq.extend_from_slice(rest);
+            match_tokens(&seq, s)
+        }),
+        Token::Range(a, b) => {
+            let (lo, hi) = (*a.min(b), *a.max(b));
+            let sign = if s.first() == Some(&'-') { 1 } else { 0 };
+            let digits = s[sign..].iter().take_while(|c| c.is_ascii_digit()).count();
+            (1..=digits).any(|k| {
+                // too many digits for i64 is out of any range
+                match s[..sign + k].iter().collect::<String>().parse::<i64>() {
+                    Ok(n) => lo <= n && n <= hi && match_tokens(rest, &s[sign + k..]),
+                    Err(_) => false,
+                }
+            })
+        }
+    }
+}
+
+// Path is relative to the directory of .editorconfig, with '/' separators.
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    // without a slash, the glob matches the file name in any directory
+    let glob = if !glob.contains('/') {
+        format!("**/{}", glob)
+    } else {
+        glob.trim_start_matches('/').to_owned()
+    };
+    matches_from_root(&glob, path)
+}
+
+fn matches_from_root(glob: &str, path: &str) -> bool {
+    let chars: Vec<char> = glob.chars().collect();
+    let path_chars: Vec<char> = path.chars().collect();
+    match_tokens(&parse_glob(&chars), &path_chars)
+        // "**/" can match no directories at all
+        || glob.strip_prefix("**/").map(|g| matches_from_root(g, path)) == Some(true)
+}
+
+// Files are given from the innermost directory outwards, with the path
+// of the document relative to each of them.
+fn resolve(files: &[(File, String)]) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    for (file, path) in files.iter().rev() {
+        for section in &file.sections {
+            if glob_matches(&section.glob, path) {
+                for (key, value) in &section.properties {
+                    properties.insert(key.clone(), value.to_lowercase());
+                }
+            }
+        }
+    }
+    properties
+}
+
+pub fn settings_for(path: &Path) -> Settings {
+    let path = match path.canonicalize() {
+        Ok(p) => p,
+        Err(_) => path.to_owned(),
+    };
+    let mut files = Vec::new();
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if let Ok(s) = std::fs::read_to_string(d.join(".editorconfig")) {
+            let rel = path.strip_prefix(d).unwrap().to_string_lossy().replace('\\', "/");
+            let file = parse(&s);
+            let root = file.root;
+            files.push((file, rel));
+            if root {
+                break;
+            }
+        }
+        dir = d.parent();
+    }
+    Settings::from_properties(&resolve(&files))
+}
+
+// Returns the text and whether something had to be replaced
+// because the data is not valid in the charset. Line breaks are left as is.
+pub fn decode(data: &[u8], charset: Option<Charset>) -> (String, bool) {
+    let utf16 = |data: &[u8], be: bool| {
+        let units: Vec<u16> = data.chunks(2)
+            .map(|c| {
+                let (a, b) = (c[0], *c.get(1).unwrap_or(&0));
+                if be { u16::from_be_bytes([a, b]) } else { u16::from_le_bytes([a, b]) }
+            })
+            .collect();
+        let s = String::from_utf16_lossy(&units);
+        let lossy = data.len() % 2 == 1 || s.contains('\u{FFFD}');
+        (s.trim_start_matches('\u{FEFF}').to_owned(), lossy)
+    };
+    match charset {
+        Some(Charset::Latin1) => (data.iter().map(|&b| b as char).collect(), false),
+        Some(Charset::Utf16Be) => utf16(data, true),
+        Some(Charset::Utf16Le) => utf16(data, false),
+        Some(Charset::Utf8Bom) => {
+            let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+            let s = String::from_utf8_lossy(data);
+            (s.to_string(), matches!(s, std::borrow::Cow::Owned(_)))
+        }
+        _ => {
+            let s = String::from_utf8_lossy(data);
+            (s.to_string(), matches!(s, std::borrow::Cow::Owned(_)))
+        }
+    }
+}
+
+// The first char the charset has no code for, if any.
+pub fn unencodable_char(text: &str, charset: Option<Charset>) -> Option<char> {
+    match charset {
+        Some(Charset::Latin1) => text.chars().find(|&c| c as u32 > 0xFF),
+        _ => None,
+    }
+}
+
+// Chars the charset has no code for are written as '?',
+// check with unencodable_char() first.
+pub fn encode(text: &str, settings: &Settings) -> Vec<u8> {
+    let text = match settings.end_of_line {
+        Some(EndOfLine::CrLf) => text.replace('\n', "\r\n"),
+        Some(EndOfLine::Cr) => text.replace('\n', "\r"),
+        _ => text.to_owned(),
+    };
+    match settings.charset {
+        Some(Charset::Latin1) => text.chars().map(|c| if (c as u32) < 256 { c as u8 } else { b'?' }).collect(),
+        Some(Charset::Utf16Be) => text.encode_utf16().flat_map(|u| u.to_be_bytes()).collect(),
+        Some(Charset::Utf16Le) => text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect(),
+        Some(Charset::Utf8Bom) => {
+            let mut data = b"\xEF\xBB\xBF".to_vec();
+            data.extend_from_slice(text.as_bytes());
+            data
+        }
+        _ => text.into_bytes(),
+    }
+}
+
+// Trailing whitespace and final newline fixes, for save.
+pub fn whitespace_edits(text: &str, settings: &Settings) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let mut pos = 0;
+    if settings.trim_trailing_whitespace == Some(true) {
+        for line in text.split('\n') {
+            let len = line.chars().count();
+            let trimmed = line.trim_end_matches([' ', '\t']).chars().count();
+            if trimmed < len {
+                edits.push(Edit { start: pos + trimmed, end: pos + len, text: String::new() });
+            }
+            pos += len + 1;
+        }
+    }
+    let len = text.chars().count();
+    match settings.insert_final_newline {
+        Some(true) if !text.is_empty() && !text.ends_with('\n') => {
+            edits.push(Edit { start: len, end: len, text: "\n".to_owned() });
+        }
+        Some(false) => {
+            let n = len - text.trim_end_matches('\n').chars().count();
+            if n > 0 {
+                edits.push(Edit { start: len - n, end: len, text: String::new() });
+            }
+        }
+        _ => {}
+    }
+    edits
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "a.txt", true),
+            ("*", "dir/a.txt", true),
+            ("*.py", "a.py", true),
+            ("*.py", "src/deep/a.py", true),
+            ("*.py", "a.pyc", false),
+            ("Makefile", "sub/Makefile", true),
+            ("Makefile", "Makefile.am", false),
+            ("lib/**.js", "lib/a/b/c.js", true),
+            ("lib/**.js", "other/lib/c.js", false),
+            ("/lib/*.js", "lib/c.js", true),
+            ("lib/*.js", "lib/a/c.js", false),
+            ("a?c", "abc", true),
+            ("a?c", "a/c", false),
+            ("*.{js,py}", "x.py", true),
+            ("*.{js,py}", "x.rs", false),
+            ("{package.json,.travis.yml}", ".travis.yml", true),
+            ("*.{a,{b,c}}", "x.c", true),
+            ("{single}", "{single}", true),
+            ("{single}", "single", false),
+            ("file{1..3}.txt", "file2.txt", true),
+            ("file{1..3}.txt", "file4.txt", false),
+            ("file{1..3}.txt", "file12.txt", false),
+            ("file{-3..-1}", "file-2", true),
+            ("file{1..3}.txt", "file1000000000000000000000000.txt", false),
+            ("file{1..3}*", "file1000000000000000000000000.txt", true),
+            ("[abc].txt", "b.txt", true),
+            ("[!abc].txt", "b.txt", false),
+            ("[a-c]x", "cx", true),
+            ("[a-c]x", "dx", false),
+            ("\\*.txt", "*.txt", true),
+            ("\\*.txt", "a.txt", false),
+            ("[unclosed", "[unclosed", true),
+            ("src/**/test_*.rs", "src/test_a.rs", false),
+            ("src/**/test_*.rs", "src/x/test_a.rs", true),
+            ("**/x", "x", true),
+        ];
+        for &(glob, path, expected) in cases {
+            assert_eq!(glob_matches(glob, path), expected, "{:?} {:?}", glob, path);
+        }
+    }
+
+    #[test]
+    fn resolution() {
+        let outer = parse("root = true\n[*]\nindent_style = tab\ntab_width = 8\n[*.rs]\nindent_size = 2\n");
+        let inner = parse("; comment\n[*.rs]\nindent_style = space\nend_of_line = CRLF\n[foo.rs]\nindent_size = 4\n");
+        assert!(outer.root);
+        let files = vec![(inner, "foo.rs".to_owned()), (outer, "src/foo.rs".to_owned())];
+        let s = Settings::from_properties(&resolve(&files));
+        assert_eq!(s.indent_style.as_deref(), Some("space"));
+        assert_eq!(s.indent_size, Some(4));
+        assert_eq!(s.tab_width, Some(8));
+        assert_eq!(s.end_of_line, Some(EndOfLine::CrLf));
+        assert_eq!(s.indent(IndentStyle::Tabs), IndentStyle::Spaces(4));
+        assert_eq!(Settings::default().indent(IndentStyle::Spaces(2)), IndentStyle::Spaces(2));
+    }
+
+    #[test]
+    fn whitespace() {
+        let s = Settings {
+            trim_trailing_whitespace: Some(true),
+            insert_final_newline: Some(true),
+            ..Settings::default()
+        };
+        let edits = whitespace_edits("a \nb\t\t\nc  ", &s);
+        assert_eq!(edits, vec![
+            Edit { start: 1, end: 2, text: String::new() },
+            Edit { start: 4, end: 6, text: String::new() },
+            Edit { start: 8, end: 10, text: String::new() },
+            Edit { start: 10, end: 10, text: "\n".to_owned() },
+        ]);
+        let s = Settings { insert_final_newline: Some(false), ..Settings::default() };
+        assert_eq!(whitespace_edits("a\n\n", &s), vec![Edit { start: 1, end: 3, text: String::new() }]);
+        assert!(whitespace_edits("", &Settings::default()).is_empty());
+    }
+
+    #[test]
+    fn charsets() {
+        let s = Settings {
+            end_of_line: Some(EndOfLine::CrLf),
+            charset: Some(Charset::Utf16Le),
+            ..Settings::default()
+        };
+        let data = encode("a\nж", &s);
+        assert_eq!(data, vec![b'a', 0, b'\r', 0, b'\n', 0, 0x36, 0x04]);
+        assert_eq!(decode(&data, s.charset), ("a\r\nж".to_owned(), false));
+        assert_eq!(decode(b"\xE9t\xE9", Some(Charset::Latin1)).0, "été");
+        assert_eq!(unencodable_char("été", Some(Charset::Latin1)), None);
+        assert_eq!(unencodable_char("été €1", Some(Charset::Latin1)), Some('€'));
+        assert_eq!(unencodable_char("€", Some(Charset::Utf16Be)), None);
+        assert_eq!(decode(b"\xEF\xBB\xBFx", Some(Charset::Utf8Bom)).0, "x");
+        assert!(decode(b"\xE9", Some(Charset::Utf8)).1);
+        assert_eq!(Charset::parse(Charset::Utf8Bom.name()), Some(Charset::Utf8Bom));
+    }
+}
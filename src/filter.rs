@@ -0,0 +1,145 @@
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+pub struct FilterOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[cfg(windows)]
+pub fn shell_command(command_line: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    let mut c = Command::new("cmd");
+    // cmd.exe has its own quoting rules, so pass the command line as is
+    c.raw_arg(format!("/C {}", command_line));
+    c
+}
+
+#[cfg(not(windows))]
+pub fn shell_command(command_line: &str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(command_line);
+    c
+}
+
+fn normalize_output(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).replace("\r\n", "\n")
+}
+
+// Runs the command line through the shell, feeding the input to its stdin.
+// It's an error if the command fails to start, times out,
+// or exits with non-zero code. The timeout covers reading the output
+// too, grandchildren can hold the pipes open after the command exits.
+pub fn run_filter(command_line: &str, input: &str, timeout: Duration) -> Result<FilterOutput, String> {
+    let mut child = shell_command(command_line)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Can't run {:?}.\n{}", command_line, e))?;
+
+    // Writing and reading in separate threads,
+    // otherwise the child could block on a full pipe.
+    // None of them are joined, they might never finish.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_owned();
+    std::thread::spawn(move || {
+        // The child is free to exit without reading everything.
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let (sender, receiver) = mpsc::channel();
+    let mut stdout = child.stdout.take().unwrap();
+    let stdout_sender = sender.clone();
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stdout.read_to_end(&mut buf);
+        let _ = stdout_sender.send((false, buf));
+    });
+    let mut stderr = child.stderr.take().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stderr.read_to_end(&mut buf);
+        let _ = sender.send((true, buf));
+    });
+
+    let timed_out = || format!("{:?} timed out after {:?}.", command_line, timeout);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(timed_out());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    for _ in 0..2 {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(timeout) {
+            Ok((false, buf)) => stdout = buf,
+            Ok((true, buf)) => stderr = buf,
+            Err(_) => return Err(timed_out()),
+        }
+    }
+    let stdout = normalize_output(&stdout);
+    let stderr = normalize_output(&stderr);
+
+    if !status.success() {
+        let mut msg = format!("{:?} failed ({}).", command_line, status);
+        if !stderr.is_empty() {
+            msg.push('\n');
+            msg.push_str(stderr.trim_end());
+        }
+        return Err(msg);
+    }
+    Ok(FilterOutput { stdout, stderr })
+}
+
+// If the input doesn't end with a line break, the trailing line break
+// of the output is dropped too (for things like sort that add it).
+pub fn match_trailing_newline(input: &str, mut output: String) -> String {
+    if !input.ends_with('\n') && output.ends_with('\n') {
+        output.pop();
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn sort() {
+        let out = run_filter("sort", "b\nc\na\n", TIMEOUT).ok().unwrap();
+        assert_eq!(out.stdout, "a\nb\nc\n");
+
+        let out = run_filter("sort", "b\na", TIMEOUT).ok().unwrap();
+        assert_eq!(match_trailing_newline("b\na", out.stdout), "a\nb");
+        assert_eq!(match_trailing_newline("b\na\n", "a\nb\n".to_owned()), "a\nb\n");
+    }
+
+    #[test]
+    fn errors() {
+        let e = run_filter("echo oops 1>&2 && exit 3", "", TIMEOUT).err().unwrap();
+        assert!(e.contains("oops"), "{}", e);
+
+        let cmd = if cfg!(windows) { "ping -n 10 127.0.0.1 > nul" } else { "sleep 10" };
+        let e = run_filter(cmd, "", Duration::from_millis(200)).err().unwrap();
+        assert!(e.contains("timed out"), "{}", e);
+
+        // exits right away, leaving a child with its stdout
+        let cmd = if cfg!(windows) { "start /b ping -n 10 127.0.0.1" } else { "sleep 10 &" };
+        let start = Instant::now();
+        let e = run_filter(cmd, "", Duration::from_millis(200)).err().unwrap();
+        assert!(e.contains("timed out"), "{}", e);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
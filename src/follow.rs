@@ -0,0 +1,131 @@
+// Following a growing file, like a log. The file is polled for bytes
+// appended past what the document has. When it gets shorter or is
+// replaced by a new file (log rotation), it has to be reloaded.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use super::editorconfig::Charset;
+use super::loader::Decoder;
+
+pub enum Change {
+    None,
+    // decoded text with \n line breaks
+    Appended(String),
+    Replaced,
+}
+
+pub struct Follower {
+    path: PathBuf,
+    // bytes of the file that are in the document
+    offset: u64,
+    created: Option<SystemTime>,
+    // NTFS keeps the created time of a file that is deleted or renamed
+    // away for a new one with the same name, the file index differs
+    id: (u64, u64),
+    decoder: Decoder,
+}
+
+// Volume and file index.
+#[cfg(windows)]
+fn file_id(file: &File) -> std::io::Result<(u64, u64)> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    let res = unsafe { GetFileInformationByHandle(file.as_raw_handle() as *mut _, &mut info) };
+    if res == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((info.dwVolumeSerialNumber as u64, (info.nFileIndexHigh as u64) << 32 | info.nFileIndexLow as u64))
+}
+
+#[cfg(unix)]
+fn file_id(file: &File) -> std::io::Result<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = file.metadata()?;
+    Ok((meta.dev(), meta.ino()))
+}
+
+impl Follower {
+    pub fn new(path: PathBuf, offset: u64, charset: Option<Charset>) -> std::io::Result<Follower> {
+        let file = File::open(&path)?;
+        let created = file.metadata()?.created().ok();
+        let id = file_id(&file)?;
+        Ok(Follower {
+            path,
+            offset,
+            created,
+            id,
+            decoder: Decoder::new(charset),
+        })
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn poll(&mut self) -> std::io::Result<Change> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            // renamed away, and the new one is not there yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Change::None),
+            Err(e) => return Err(e),
+        };
+        let meta = file.metadata()?;
+        if meta.len() < self.offset || meta.created().ok() != self.created || file_id(&file)? != self.id {
+            return Ok(Change::Replaced);
+        }
+        if meta.len() == self.offset {
+            return Ok(Change::None);
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut data = Vec::new();
+        file.take(meta.len() - self.offset).read_to_end(&mut data)?;
+        self.offset += data.len() as u64;
+        Ok(Change::Appended(self.decoder.decode(&data, false).0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn appended(change: Change) -> Option<String> {
+        match change {
+            Change::Appended(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn following() {
+        let path = std::env::temp_dir().join(format!("an_editor_follow_{}.log", std::process::id()));
+        std::fs::write(&path, "first\r\n").unwrap();
+        let mut follower = Follower::new(path.clone(), 7, None).unwrap();
+        assert!(matches!(follower.poll().unwrap(), Change::None));
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        // the euro sign is cut in half
+        file.write_all(b"second\r\n\xE2\x82").unwrap();
+        assert_eq!(appended(follower.poll().unwrap()).as_deref(), Some("second\n"));
+        file.write_all(b"\xAC\n").unwrap();
+        assert_eq!(appended(follower.poll().unwrap()).as_deref(), Some("€\n"));
+        drop(file);
+
+        std::fs::write(&path, "new\n").unwrap();
+        assert!(matches!(follower.poll().unwrap(), Change::Replaced));
+
+        // rotated, the new file is already longer than the old one was
+        let mut follower = Follower::new(path.clone(), 4, None).unwrap();
+        let rotated = path.with_extension("log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        assert!(matches!(follower.poll().unwrap(), Change::None));
+        std::fs::write(&path, "newer and longer\n").unwrap();
+        assert!(matches!(follower.poll().unwrap(), Change::Replaced));
+        std::fs::remove_file(&rotated).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,357 @@
+// Hex mode for binary files. The bytes are shown as rows of offset,
+// hex and ASCII columns, and edited in place (typing overwrites),
+// so that they are saved exactly as they are.
+
+use super::editorconfig::Charset;
+
+pub const BYTES_PER_ROW: usize = 16;
+// how much of the file is checked for being binary
+pub const SAMPLE_SIZE: usize = 64 << 10;
+
+// NULs, or mostly invalid UTF-8 (random bytes are about half invalid).
+pub fn is_binary(data: &[u8], charset: Option<Charset>) -> bool {
+    match charset {
+        Some(Charset::Utf16Be) | Some(Charset::Utf16Le) => return false,
+        Some(Charset::Latin1) => return data.contains(&0),
+        _ => {}
+    }
+    if data.contains(&0) {
+        return true;
+    }
+    let mut invalid = 0;
+    let mut rest = data;
+    while let Err(e) = std::str::from_utf8(rest) {
+        let n = e.error_len().unwrap_or(rest.len() - e.valid_up_to());
+        invalid += n;
+        rest = &rest[e.valid_up_to() + n..];
+    }
+    invalid * 4 > data.len()
+}
+
+// Hex digits of the offset column, at least 8, more for files past 4 GB.
+pub fn offset_digits(len: usize) -> usize {
+    let last = len.saturating_sub(1);
+    ((usize::BITS - last.leading_zeros()).div_ceil(4) as usize).max(8)
+}
+
+// Char column of the i-th byte of a row in the hex column,
+// there is an extra space in the middle.
+pub fn hex_col(i: usize, digits: usize) -> usize {
+    digits + 2 + 3 * i + i / 8
+}
+
+pub fn ascii_col(i: usize, digits: usize) -> usize {
+    hex_col(BYTES_PER_ROW, digits) + i
+}
+
+// The byte of the row at a char column, and whether it's in the ASCII column.
+pub fn cell_at(col: usize, digits: usize) -> Option<(usize, bool)> {
+    if col >= ascii_col(0, digits) {
+        return (col < ascii_col(BYTES_PER_ROW, digits)).then(|| (col - ascii_col(0, digits), true));
+    }
+    if col < hex_col(0, digits) {
+        return None;
+    }
+    let c = col - hex_col(0, digits);
+    let i = if c > hex_col(8, 0) - hex_col(0, 0) - 1 { (c - 1) / 3 } else { c / 3 };
+    Some((i.min(BYTES_PER_ROW - 1), false))
+}
+
+// Hex digits, spaces between them are fine, or text in double quotes.
+pub fn parse_pattern(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        return Some(s.as_bytes()[1..s.len() - 1].to_vec());
+    }
+    let digits: Vec<u8> = s.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()?;
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(digits.chunks(2).map(|d| d[0] << 4 | d[1]).collect())
+}
+
+// In hex, like they are shown, 0x is optional.
+pub fn parse_offset(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    usize::from_str_radix(s, 16).ok()
+}
+
+pub struct HexDocument {
+    data: Vec<u8>,
+    cursor: usize,
+    // the first digit of the cursor byte was typed
+    low_nibble: bool,
+    // typing goes to the ASCII column
+    pub ascii: bool,
+    top_row: usize,
+    page_rows: usize,
+    // offsets and the bytes that were there
+    undo: Vec<(usize, u8)>,
+    // undo length when saved, None if that state is lost
+    unmodified: Option<usize>,
+}
+
+impl HexDocument {
+    pub fn new(data: Vec<u8>) -> HexDocument {
+        HexDocument {
+            data,
+            cursor: 0,
+            low_nibble: false,
+            ascii: false,
+            top_row: 0,
+            page_rows: 1,
+            undo: Vec::new(),
+            unmodified: Some(0),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn low_nibble(&self) -> bool {
+        self.low_nibble
+    }
+
+    pub fn modified(&self) -> bool {
+        self.unmodified != Some(self.undo.len())
+    }
+
+    pub fn set_unmodified(&mut self) {
+        self.unmodified = Some(self.undo.len());
+    }
+
+    pub fn mark_modified(&mut self) {
+        self.unmodified = None;
+    }
+
+    pub fn offset_digits(&self) -> usize {
+        offset_digits(self.data.len())
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.data.len().div_ceil(BYTES_PER_ROW).max(1)
+    }
+
+    pub fn top_row(&self) -> usize {
+        self.top_row
+    }
+
+    pub fn page_rows(&self) -> usize {
+        self.page_rows
+    }
+
+    pub fn set_page_rows(&mut self, n: usize) {
+        self.page_rows = n.max(1);
+    }
+
+    pub fn format_row(&self, row: usize) -> String {
+        let start = row * BYTES_PER_ROW;
+        let bytes = &self.data[start.min(self.data.len())..(start + BYTES_PER_ROW).min(self.data.len())];
+        let mut s = format!("{:01$X}  ", start, self.offset_digits());
+        for i in 0..BYTES_PER_ROW {
+            if i == 8 {
+                s.push(' ');
+            }
+            match bytes.get(i) {
+                Some(b) => s.push_str(&format!("{:02X} ", b)),
+                None => s.push_str("   "),
+            }
+        }
+        s.push(' ');
+        s.extend(bytes.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }));
+        s
+    }
+
+    // Clamped to the last byte.
+    pub fn move_to(&mut self, offset: usize) {
+        self.cursor = offset.min(self.data.len().saturating_sub(1));
+        self.low_nibble = false;
+        let row = self.cursor / BYTES_PER_ROW;
+        if row < self.top_row {
+            self.top_row = row;
+        } else if row >= self.top_row + self.page_rows {
+            self.top_row = row + 1 - self.page_rows;
+        }
+    }
+
+    pub fn move_by(&mut self, delta: isize) {
+        self.move_to(self.cursor.saturating_add_signed(delta));
+    }
+
+    // At a row on screen and a char column.
+    pub fn click(&mut self, row: usize, col: usize) {
+        if let Some((i, ascii)) = cell_at(col, self.offset_digits()) {
+            self.ascii = ascii;
+            self.move_to((self.top_row + row) * BYTES_PER_ROW + i);
+        }
+    }
+
+    // Hex digits in the hex column, printable ASCII in the other.
+    // Returns false if the char can't be typed there.
+    pub fn type_char(&mut self, c: char) -> bool {
+        if self.cursor >= self.data.len() {
+            return false;
+        }
+        let old = self.data[self.cursor];
+        let new = if self.ascii {
+            if !(' '..='~').contains(&c) {
+                return false;
+            }
+            c as u8
+        } else {
+            let d = match c.to_digit(16) {
+                Some(d) => d as u8,
+                None => return false,
+            };
+            if self.low_nibble { old & 0xF0 | d } else { d << 4 | old & 0x0F }
+        };
+        // the saved state was undone past
+        if self.unmodified.is_some_and(|n| n > self.undo.len()) {
+            self.unmodified = None;
+        }
+        self.undo.push((self.cursor, old));
+        self.data[self.cursor] = new;
+        if self.ascii || self.low_nibble {
+            self.move_by(1);
+        } else {
+            self.low_nibble = true;
+        }
+        true
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.undo.pop() {
+            Some((offset, old)) => {
+                self.data[offset] = old;
+                self.move_to(offset);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The next occurrence after the cursor, wrapping around.
+    pub fn find(&mut self, pattern: &[u8]) -> bool {
+        let n = self.data.len();
+        if pattern.is_empty() || pattern.len() > n {
+            return false;
+        }
+        let found = (1..=n)
+            .map(|i| (self.cursor + i) % n)
+            .find(|&p| self.data[p..].starts_with(pattern));
+        if let Some(p) = found {
+            self.move_to(p);
+        }
+        found.is_some()
+    }
+
+    // In rows, positive is up.
+    pub fn scroll(&mut self, delta: f32) {
+        self.scroll_to(self.top_row as f32 - delta.round());
+    }
+
+    pub fn scroll_to(&mut self, top: f32) {
+        let last = self.num_rows().saturating_sub(self.page_rows);
+        self.top_row = (top.max(0.0) as usize).min(last);
+    }
+
+    // Range, top and page, in rows.
+    pub fn scroll_position(&self) -> (f32, f32, f32) {
+        (self.num_rows() as f32, self.top_row as f32, self.page_rows as f32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detection() {
+        assert!(!is_binary(b"fn main() {}\n", None));
+        assert!(is_binary(b"ELF\x02\x01\x00", None));
+        assert!(is_binary(b"\xFF\xFEa\x80\x81bcde", None));
+        // a few invalid bytes in text are a bad encoding, not binary
+        assert!(!is_binary(b"caf\xE9 and more text", None));
+        assert!(!is_binary(b"a\x00b\x00", Some(Charset::Utf16Le)));
+    }
+
+    #[test]
+    fn rows() {
+        let doc = HexDocument::new((0x41..0x41 + 20).chain([0, 0x7F]).collect());
+        assert_eq!(doc.num_rows(), 2);
+        assert_eq!(doc.format_row(0),
+            "00000000  41 42 43 44 45 46 47 48  49 4A 4B 4C 4D 4E 4F 50  ABCDEFGHIJKLMNOP");
+        assert_eq!(doc.format_row(1),
+            "00000010  51 52 53 54 00 7F                                 QRST..");
+        let row = doc.format_row(0);
+        for digits in [8, 9, 16] {
+            for i in 0..BYTES_PER_ROW {
+                assert_eq!(cell_at(hex_col(i, digits), digits), Some((i, false)));
+                assert_eq!(cell_at(hex_col(i, digits) + 1, digits), Some((i, false)));
+                assert_eq!(cell_at(ascii_col(i, digits), digits), Some((i, true)));
+            }
+            assert_eq!(cell_at(digits - 5, digits), None);
+            assert_eq!(cell_at(ascii_col(16, digits), digits), None);
+        }
+        for i in 0..BYTES_PER_ROW {
+            assert_eq!(&row[hex_col(i, 8)..hex_col(i, 8) + 2], format!("{:02X}", 0x41 + i));
+        }
+
+        assert_eq!(offset_digits(0), 8);
+        assert_eq!(offset_digits(1 << 32), 8);
+        assert_eq!(offset_digits((1 << 32) + 1), 9);
+        assert_eq!(offset_digits(usize::MAX), 16);
+    }
+
+    #[test]
+    fn editing() {
+        let mut doc = HexDocument::new(vec![0x12, 0x34, 0x56]);
+        assert!(doc.type_char('a'));
+        assert_eq!((doc.data()[0], doc.cursor(), doc.low_nibble()), (0xA2, 0, true));
+        assert!(doc.type_char('B'));
+        assert_eq!((doc.data()[0], doc.cursor()), (0xAB, 1));
+        assert!(!doc.type_char('g'));
+        doc.ascii = true;
+        assert!(doc.type_char('z'));
+        assert!(!doc.type_char('\u{e9}'));
+        assert_eq!(doc.data(), &[0xAB, b'z', 0x56]);
+        assert!(doc.modified());
+
+        while doc.undo() {}
+        assert_eq!(doc.data(), &[0x12, 0x34, 0x56]);
+        assert!(!doc.modified());
+
+        // overwrite only, the last byte stays the last
+        doc.move_to(2);
+        assert!(doc.type_char('x'));
+        assert!(doc.type_char('y'));
+        assert_eq!(doc.data(), &[0x12, 0x34, b'y']);
+    }
+
+    #[test]
+    fn searching() {
+        assert_eq!(parse_pattern("de ad BEEF"), Some(vec![0xDE, 0xAD, 0xBE, 0xEF]));
+        assert_eq!(parse_pattern("\"PK\""), Some(b"PK".to_vec()));
+        assert_eq!(parse_pattern("abc"), None);
+        assert_eq!(parse_pattern("xy"), None);
+        assert_eq!(parse_offset("0x1f"), Some(0x1F));
+        assert_eq!(parse_offset("100"), Some(0x100));
+
+        let mut doc = HexDocument::new(b"PK..PK..".to_vec());
+        assert!(doc.find(b"PK"));
+        assert_eq!(doc.cursor(), 4);
+        // wraps around
+        assert!(doc.find(b"PK"));
+        assert_eq!(doc.cursor(), 0);
+        assert!(!doc.find(b"PKZ"));
+    }
+}
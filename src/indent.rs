@@ -0,0 +1,237 @@
+// Indentation style, either configured or guessed from the file content.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IndentStyle {
+    Tabs,
+    Spaces(usize),
+}
+
+impl IndentStyle {
+    // "tab" or the number of spaces
+    pub fn parse(s: &str) -> Result<IndentStyle, String> {
+        if s == "tab" {
+            return Ok(IndentStyle::Tabs);
+        }
+        match s.parse() {
+            Ok(n) if n > 0 && n <= 16 => Ok(IndentStyle::Spaces(n)),
+            _ => Err(format!("expected \"tab\" or number of spaces, got {:?}", s)),
+        }
+    }
+
+    pub fn unit(self) -> String {
+        match self {
+            IndentStyle::Tabs => "\t".to_owned(),
+            IndentStyle::Spaces(n) => " ".repeat(n),
+        }
+    }
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    let n = line.len() - line.trim_start_matches([' ', '\t']).len();
+    &line[..n]
+}
+
+// Most lines indented with tabs mean tabs. Otherwise the indent size
+// is the most common increase of indentation from one line to the next.
+// None if there is not enough indented lines to tell.
+pub fn detect(text: &str) -> Option<IndentStyle> {
+    let mut tab_lines = 0;
+    let mut space_lines = 0;
+    // increases of 1..=8 spaces
+    let mut increases = [0; 9];
+    let mut prev = 0;
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let ws = leading_whitespace(line);
+        if ws.starts_with('\t') {
+            tab_lines += 1;
+            continue;
+        }
+        let n = ws.len();
+        if n > 0 {
+            space_lines += 1;
+        }
+        if n > prev && n - prev < increases.len() {
+            increases[n - prev] += 1;
+        }
+        prev = n;
+    }
+    if tab_lines == 0 && space_lines == 0 {
+        return None;
+    }
+    if tab_lines > space_lines {
+        return Some(IndentStyle::Tabs);
+    }
+    // on a tie, the larger size
+    let best = (1..increases.len()).max_by_key(|&i| increases[i])?;
+    if increases[best] == 0 {
+        return None;
+    }
+    Some(IndentStyle::Spaces(best))
+}
+
+// Indentation for the new line when the line is broken
+// after line_before_cursor.
+pub fn newline_indent(line_before_cursor: &str, style: IndentStyle) -> String {
+    let mut indent = leading_whitespace(line_before_cursor).to_owned();
+    let trimmed = line_before_cursor.trim_end();
+    if trimmed.ends_with('{') || trimmed.ends_with(':') {
+        indent.push_str(&style.unit());
+    }
+    indent
+}
+
+// Width of the text in columns, tabs go to the next tab stop.
+pub fn columns(text: &str, tab_width: usize) -> usize {
+    text.chars().fold(0, |col, c| if c == '\t' { col / tab_width * tab_width + tab_width } else { col + 1 })
+}
+
+// How many chars of the leading whitespace to remove to go one level back.
+pub fn outdent_len(ws: &str, style: IndentStyle, tab_width: usize) -> usize {
+    match style {
+        IndentStyle::Tabs => ws.chars().next().map_or(0, |_| 1),
+        IndentStyle::Spaces(n) => {
+            let w = columns(ws, tab_width);
+            if w == 0 {
+                return 0;
+            }
+            let target = (w - 1) / n * n;
+            let mut removed = 0;
+            let mut chars: Vec<char> = ws.chars().collect();
+            while columns(&chars.iter().collect::<String>(), tab_width) > target {
+                chars.pop();
+                removed += 1;
+            }
+            removed
+        }
+    }
+}
+
+// Last line of the block indented deeper than line_no, for folding.
+// Blank lines belong to the block unless they end it.
+pub fn block_end(line_no: usize, num_lines: usize, mut line_text: impl FnMut(usize) -> String, tab_width: usize) -> Option<usize> {
+    let mut indent_width = |i| {
+        let line = line_text(i);
+        if line.trim().is_empty() { None } else { Some(columns(leading_whitespace(&line), tab_width)) }
+    };
+    let base = indent_width(line_no)?;
+    let mut last = None;
+    for i in line_no + 1..num_lines {
+        match indent_width(i) {
+            None => {}
+            Some(w) if w > base => last = Some(i),
+            Some(_) => break,
+        }
+    }
+    last
+}
+
+// block_end() of every line, in one pass.
+pub fn block_ends(lines: impl Iterator<Item = String>, tab_width: usize) -> Vec<Option<usize>> {
+    let mut result = Vec::new();
+    // lines that can still have deeper ones after them, with their widths,
+    // the widths go up
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    let mut last_non_blank = 0;
+    let end_blocks = |stack: &mut Vec<(usize, usize)>, result: &mut Vec<Option<usize>>, w, last| {
+        while let Some(&(h, base)) = stack.last() {
+            if base < w {
+                break;
+            }
+            stack.pop();
+            if last > h {
+                result[h] = Some(last);
+            }
+        }
+    };
+    for (i, line) in lines.enumerate() {
+        result.push(None);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let w = columns(leading_whitespace(&line), tab_width);
+        end_blocks(&mut stack, &mut result, w, last_non_blank);
+        stack.push((i, w));
+        last_non_blank = i;
+    }
+    end_blocks(&mut stack, &mut result, 0, last_non_blank);
+    result
+}
+
+// What Tab inserts at the given column.
+pub fn tab_text(column: usize, style: IndentStyle) -> String {
+    match style {
+        IndentStyle::Tabs => "\t".to_owned(),
+        IndentStyle::Spaces(n) => " ".repeat(n - column % n),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detection() {
+        assert_eq!(detect("fn f() {\n    a;\n    if x {\n        b;\n    }\n}\n"), Some(IndentStyle::Spaces(4)));
+        assert_eq!(detect("a:\n  b:\n    c\n  d\n"), Some(IndentStyle::Spaces(2)));
+        assert_eq!(detect("f() {\n\ta;\n\tb;\n}\n"), Some(IndentStyle::Tabs));
+        assert_eq!(detect("no\nindentation\n"), None);
+        assert_eq!(detect(""), None);
+    }
+
+    #[test]
+    fn editing() {
+        let s4 = IndentStyle::Spaces(4);
+        assert_eq!(newline_indent("    if x {", s4), "        ");
+        assert_eq!(newline_indent("    x = 1;", s4), "    ");
+        assert_eq!(newline_indent("\tdef f():", IndentStyle::Tabs), "\t\t");
+
+        assert_eq!(outdent_len("        ", s4, 4), 4);
+        assert_eq!(outdent_len("      ", s4, 4), 2);
+        assert_eq!(outdent_len("  ", s4, 4), 2);
+        assert_eq!(outdent_len("", s4, 4), 0);
+        assert_eq!(outdent_len("\t ", s4, 4), 1);
+        assert_eq!(outdent_len("\t\t", IndentStyle::Tabs, 4), 1);
+        // with 8 column tabs
+        assert_eq!(outdent_len("\t", s4, 8), 1);
+        assert_eq!(outdent_len("\t  ", IndentStyle::Spaces(2), 8), 2);
+        assert_eq!(outdent_len("\t    ", s4, 8), 4);
+
+        assert_eq!(columns("a\tb\t", 4), 8);
+
+        assert_eq!(tab_text(1, s4), "   ");
+        assert_eq!(tab_text(4, IndentStyle::Spaces(2)), "  ");
+    }
+
+    #[test]
+    fn blocks() {
+        let lines = ["def f():", "    if x:", "        a", "", "    b", "", "c", "  "];
+        let end = |i| block_end(i, lines.len(), |j| lines[j].to_owned(), 4);
+        assert_eq!(end(0), Some(4));
+        assert_eq!(end(1), Some(2));
+        assert_eq!(end(2), None);
+        assert_eq!(end(3), None);
+        assert_eq!(end(6), None);
+
+        // tabs and spaces mixed, it depends on the tab width
+        let lines = ["\tif x {", "        a;", "\t}"];
+        let end = |i, tab_width| block_end(i, lines.len(), |j| lines[j].to_owned(), tab_width);
+        assert_eq!(end(0, 4), Some(1));
+        assert_eq!(end(0, 8), None);
+
+        let lines = ["def f():", "    if x:", "        a", "", "    b", "", "c", "  ", "    d", "  e", "", "f"];
+        let ends = block_ends(lines.iter().map(|l| l.to_string()), 4);
+        for (i, &e) in ends.iter().enumerate() {
+            assert_eq!(e, block_end(i, lines.len(), |j| lines[j].to_owned(), 4), "{}", i);
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(IndentStyle::parse("tab"), Ok(IndentStyle::Tabs));
+        assert_eq!(IndentStyle::parse("2"), Ok(IndentStyle::Spaces(2)));
+        assert!(IndentStyle::parse("0").is_err());
+    }
+}
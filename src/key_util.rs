@@ -3,6 +3,7 @@ use std::fmt::{Debug, Formatter, Result};
 use winapi::shared::minwindef::*;
 use winapi::um::winuser::*;
 
+#[derive(Clone)]
 pub struct KeyEvent {
     pub ctrl_pressed: bool,
     pub shift_pressed: bool,
@@ -38,8 +39,20 @@ impl KeyEvent {
             scan_code,
         }
     }
+
+    // Modifier keys generate their own WM_KEYDOWN messages,
+    // they should not interrupt key sequences like "C-x C-s".
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self.key_code,
+            VK_CONTROL | VK_SHIFT | VK_MENU |
+            VK_LCONTROL | VK_RCONTROL |
+            VK_LSHIFT | VK_RSHIFT |
+            VK_LMENU | VK_RMENU)
+    }
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub struct KeyMatcher {
     ctrl: bool,
     shift: bool,
@@ -80,6 +93,9 @@ impl KeyMatcher {
         if self.shift != ke.shift_pressed {
             return false;
         }
+        if self.alt != ke.alt_pressed {
+            return false;
+        }
         if let Some(x) = self.key_code {
             if x != ke.key_code {
                 return false;
@@ -94,6 +110,71 @@ impl KeyMatcher {
     }
 }
 
+// Parses key descriptions like "Ctrl+Shift+K" or "Alt+F5".
+pub fn parse_key_spec(spec: &str) -> std::result::Result<KeyMatcher, String> {
+    let parts: Vec<&str> = spec.split('+').map(str::trim).collect();
+    let (&key, modifiers) = parts.split_last().unwrap();
+    let mut chars = key.chars();
+    let mut km = match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphanumeric() =>
+            KeyMatcher::from_char_to_scan_code(c.to_ascii_uppercase()),
+        _ => {
+            let key_code = match key.to_ascii_lowercase().as_str() {
+                "enter" | "return" => VK_RETURN,
+                "tab" => VK_TAB,
+                "space" => VK_SPACE,
+                "esc" | "escape" => VK_ESCAPE,
+                "backspace" => VK_BACK,
+                "insert" | "ins" => VK_INSERT,
+                "delete" | "del" => VK_DELETE,
+                "home" => VK_HOME,
+                "end" => VK_END,
+                "pgup" | "pageup" => VK_PRIOR,
+                "pgdn" | "pagedown" => VK_NEXT,
+                "left" => VK_LEFT,
+                "right" => VK_RIGHT,
+                "up" => VK_UP,
+                "down" => VK_DOWN,
+                k => match k.strip_prefix('f').and_then(|n| n.parse::<i32>().ok()) {
+                    Some(n) if (1..=24).contains(&n) => VK_F1 + n - 1,
+                    _ => return Err(format!("unknown key {:?} in {:?}", key, spec)),
+                },
+            };
+            KeyMatcher::from_key_code(key_code)
+        }
+    };
+    for m in modifiers {
+        match m.to_ascii_lowercase().as_str() {
+            "ctrl" => km.ctrl = true,
+            "shift" => km.shift = true,
+            "alt" => km.alt = true,
+            _ => return Err(format!("unknown modifier {:?} in {:?}", m, spec)),
+        }
+    }
+    Ok(km)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SequenceMatch {
+    No,
+    Prefix,
+    Full,
+}
+
+pub fn match_sequence(seq: &[KeyMatcher], keys: &[KeyEvent]) -> SequenceMatch {
+    if keys.len() > seq.len() {
+        return SequenceMatch::No;
+    }
+    if !seq.iter().zip(keys).all(|(km, ke)| km.matches(ke)) {
+        return SequenceMatch::No;
+    }
+    if keys.len() == seq.len() {
+        SequenceMatch::Full
+    } else {
+        SequenceMatch::Prefix
+    }
+}
+
 pub struct Modifier {
     ctrl: bool,
     shift: bool,
@@ -120,3 +201,34 @@ impl std::ops::Add<KeyMatcher> for Modifier {
 pub const CTRL: Modifier = Modifier { ctrl: true, shift: false, alt: false };
 pub const SHIFT: Modifier = Modifier { ctrl: false, shift: true, alt: false };
 pub const ALT: Modifier = Modifier { ctrl: false, shift: false, alt: true };
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(ctrl: bool, key_code: i32) -> KeyEvent {
+        KeyEvent {
+            ctrl_pressed: ctrl,
+            shift_pressed: false,
+            alt_pressed: false,
+            key_code,
+            scan_code: 0,
+        }
+    }
+
+    #[test]
+    fn sequences() {
+        // C-x C-s
+        let seq = [CTRL + KeyMatcher::from_key_code(0x58), CTRL + KeyMatcher::from_key_code(0x53)];
+        assert_eq!(match_sequence(&seq, &[]), SequenceMatch::Prefix);
+        assert_eq!(match_sequence(&seq, &[key(true, 0x58)]), SequenceMatch::Prefix);
+        assert_eq!(match_sequence(&seq, &[key(true, 0x58), key(true, 0x53)]), SequenceMatch::Full);
+        // modifiers have to match too
+        assert_eq!(match_sequence(&seq, &[key(true, 0x58), key(false, 0x53)]), SequenceMatch::No);
+        assert_eq!(match_sequence(&seq, &[key(true, 0x53)]), SequenceMatch::No);
+        // the caller starts over after a mismatch
+        assert_eq!(match_sequence(&seq, &[key(true, 0x58), key(true, 0x58)]), SequenceMatch::No);
+        assert_eq!(match_sequence(&seq, &[key(true, 0x58), key(true, 0x53), key(true, 0x53)]), SequenceMatch::No);
+        assert_eq!(match_sequence(&seq[..1], &[key(true, 0x58)]), SequenceMatch::Full);
+    }
+}
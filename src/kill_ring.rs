@@ -0,0 +1,84 @@
+const MAX_ENTRIES: usize = 60;
+
+// Emacs-style kill ring.
+// The most recent entry is the last one.
+pub struct KillRing {
+    entries: Vec<String>,
+    // counted from the end, advanced by consecutive yank_pop() calls
+    yank_index: usize,
+}
+
+impl KillRing {
+    pub fn new() -> Self {
+        KillRing {
+            entries: Vec::new(),
+            yank_index: 0,
+        }
+    }
+
+    // Consecutive kills are accumulated into a single entry.
+    pub fn kill(&mut self, text: &str, append: bool) {
+        match self.entries.last_mut() {
+            Some(last) if append => last.push_str(text),
+            _ => {
+                if self.entries.len() == MAX_ENTRIES {
+                    self.entries.remove(0);
+                }
+                self.entries.push(text.to_owned());
+            }
+        }
+        self.yank_index = 0;
+    }
+
+    // If something else was put to the clipboard since our last kill,
+    // it becomes the most recent entry.
+    pub fn sync_with_clipboard(&mut self, clipboard: Option<String>) {
+        if let Some(s) = clipboard {
+            if self.entries.last() != Some(&s) {
+                self.kill(&s, false);
+            }
+        }
+    }
+
+    pub fn top(&self) -> Option<&str> {
+        self.entries.last().map(String::as_str)
+    }
+
+    pub fn yank(&mut self) -> Option<&str> {
+        self.yank_index = 0;
+        self.top()
+    }
+
+    pub fn yank_pop(&mut self) -> Option<&str> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        self.yank_index = (self.yank_index + 1) % self.entries.len();
+        Some(&self.entries[self.entries.len() - 1 - self.yank_index])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kill_and_yank() {
+        let mut kr = KillRing::new();
+        assert_eq!(kr.yank(), None);
+
+        kr.kill("a", false);
+        kr.kill("b", true);
+        kr.kill("c", false);
+        assert_eq!(kr.yank(), Some("c"));
+        assert_eq!(kr.yank_pop(), Some("ab"));
+        assert_eq!(kr.yank_pop(), Some("c"));
+
+        kr.sync_with_clipboard(Some("c".to_owned()));
+        assert_eq!(kr.yank(), Some("c"));
+        assert_eq!(kr.yank_pop(), Some("ab"));
+        kr.sync_with_clipboard(Some("z".to_owned()));
+        assert_eq!(kr.yank(), Some("z"));
+        assert_eq!(kr.yank_pop(), Some("c"));
+    }
+}
@@ -0,0 +1,231 @@
+// Bookkeeping for the text layouts kept with the lines. Each line
+// remembers when its layout was last used, and when there are too many,
+// the least recently used ones are dropped, except the pinned ones.
+
+pub struct LayoutCache {
+    pub capacity: usize,
+    // of the layouts kept, and the sum of their heights
+    count: usize,
+    height: f64,
+    // The same for the lines before the mark line, kept for the scroll
+    // position, the mark follows the top line on screen.
+    mark: usize,
+    count_above: usize,
+    height_above: f64,
+    // When the pinned layouts alone don't fit, the next eviction waits
+    // until there are this many, rather than scanning the lines on every
+    // paint to find nothing to drop.
+    overflow: usize,
+    clock: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evicted: u64,
+}
+
+impl LayoutCache {
+    pub fn new(capacity: usize) -> LayoutCache {
+        LayoutCache {
+            capacity,
+            count: 0,
+            height: 0.0,
+            mark: 0,
+            count_above: 0,
+            height_above: 0.0,
+            overflow: 0,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            evicted: 0,
+        }
+    }
+
+    // Both return the time of use to store with the layout.
+    pub fn hit(&mut self) -> u64 {
+        self.hits += 1;
+        self.clock += 1;
+        self.clock
+    }
+
+    pub fn miss(&mut self, line_no: usize, height: f32) -> u64 {
+        self.misses += 1;
+        self.count += 1;
+        self.height += height as f64;
+        if line_no < self.mark {
+            self.count_above += 1;
+            self.height_above += height as f64;
+        }
+        self.clock += 1;
+        self.clock
+    }
+
+    // Evicted, or dropped because the line changed.
+    pub fn dropped(&mut self, line_no: usize, height: f32) {
+        self.count -= 1;
+        self.height -= height as f64;
+        if line_no < self.mark {
+            self.count_above -= 1;
+            self.height_above -= height as f64;
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn height(&self) -> f64 {
+        self.height
+    }
+
+    // Of the lines before the mark.
+    pub fn above(&self) -> (usize, f64) {
+        (self.count_above, self.height_above)
+    }
+
+    // layout(i) is the height of the layout of line i, if it has one.
+    // Only the lines between the old and the new mark are looked at,
+    // or those between the new mark and the nearer end if that's fewer.
+    pub fn move_mark(&mut self, mark: usize, num_lines: usize, layout: impl Fn(usize) -> Option<f32>) {
+        let sum = |lines: std::ops::Range<usize>| {
+            lines.filter_map(&layout).fold((0, 0.0), |(n, h), l| (n + 1, h + l as f64))
+        };
+        let distance = mark.abs_diff(self.mark);
+        if mark <= distance {
+            (self.count_above, self.height_above) = sum(0..mark);
+        } else if num_lines - mark <= distance {
+            let (n, h) = sum(mark..num_lines);
+            self.count_above = self.count - n;
+            self.height_above = self.height - h;
+        } else if mark > self.mark {
+            let (n, h) = sum(self.mark..mark);
+            self.count_above += n;
+            self.height_above += h;
+        } else {
+            let (n, h) = sum(mark..self.mark);
+            self.count_above -= n;
+            self.height_above -= h;
+        }
+        self.mark = mark;
+    }
+
+    // Lines first..=last were replaced with first..=new_last, after
+    // their layouts were dropped. The mark stays with its line.
+    pub fn lines_replaced(&mut self, first: usize, last: usize, new_last: usize) {
+        if self.mark > last {
+            self.mark = self.mark - last + new_last;
+        } else if self.mark > first {
+            self.mark = first;
+        }
+    }
+
+    // Of the laid out lines, None if there are none.
+    pub fn average_height(&self) -> Option<f32> {
+        (self.count > 0).then(|| (self.height / self.count as f64) as f32)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count > self.capacity.max(self.overflow)
+    }
+
+    pub fn clear(&mut self) {
+        self.count = 0;
+        self.height = 0.0;
+        self.mark = 0;
+        self.count_above = 0;
+        self.height_above = 0.0;
+        self.overflow = 0;
+    }
+
+    // Lines to drop the layouts of, given the lines that have them with
+    // the time of use. Goes a quarter below the capacity, so that it's
+    // not needed again right away. The caller reports the dropped layouts.
+    pub fn evict(&mut self, mut used: Vec<(usize, u64)>, pinned: impl Fn(usize) -> bool) -> Vec<usize> {
+        let target = self.capacity - self.capacity / 4;
+        let total = used.len();
+        used.retain(|&(line_no, _)| !pinned(line_no));
+        used.sort_by_key(|&(_, t)| t);
+        used.truncate(total.saturating_sub(target));
+        let kept = total - used.len();
+        self.overflow = if kept > target { kept + self.capacity / 4 } else { 0 };
+        self.evicted += used.len() as u64;
+        used.into_iter().map(|(line_no, _)| line_no).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn eviction() {
+        let mut cache = LayoutCache::new(4);
+        let mut used = Vec::new();
+        for line_no in 0..6 {
+            used.push((line_no, cache.miss(line_no, 10.0 + line_no as f32)));
+        }
+        // line 0 was used again since
+        used[0].1 = cache.hit();
+        assert!(cache.is_full());
+        assert_eq!(cache.average_height(), Some(12.5));
+        let mut evicted = cache.evict(used, |line_no| line_no == 1);
+        evicted.sort();
+        assert_eq!(evicted, vec![2, 3, 4]);
+        for &line_no in &evicted {
+            cache.dropped(line_no, 10.0 + line_no as f32);
+        }
+        assert!(!cache.is_full());
+        assert_eq!(cache.average_height(), Some(12.0));
+        cache.clear();
+        assert_eq!(cache.average_height(), None);
+        assert_eq!((cache.hits, cache.misses, cache.evicted), (1, 6, 3));
+    }
+
+    #[test]
+    fn pinned_overflow() {
+        let mut cache = LayoutCache::new(4);
+        let used: Vec<_> = (0..6).map(|line_no| (line_no, cache.miss(line_no, 10.0))).collect();
+        // all on screen, nothing can go
+        assert!(cache.evict(used, |_| true).is_empty());
+        assert!(!cache.is_full());
+        // it's tried again once there is a quarter more
+        cache.miss(6, 10.0);
+        assert!(!cache.is_full());
+        cache.miss(7, 10.0);
+        assert!(cache.is_full());
+    }
+
+    #[test]
+    fn above_mark() {
+        fn lay_out(cache: &mut LayoutCache, lines: &mut [Option<f32>], i: usize, h: f32) {
+            lines[i] = Some(h);
+            cache.miss(i, h);
+        }
+        fn check(cache: &mut LayoutCache, lines: &[Option<f32>], mark: usize) {
+            cache.move_mark(mark, lines.len(), |i| lines[i]);
+            let (n, h) = lines[..mark].iter().flatten().fold((0, 0.0), |(n, h), &l| (n + 1, h + l as f64));
+            assert_eq!(cache.above(), (n, h), "{}", mark);
+        }
+
+        let mut cache = LayoutCache::new(100);
+        // heights of the laid out lines
+        let mut lines: Vec<Option<f32>> = vec![None; 100];
+        lay_out(&mut cache, &mut lines, 10, 20.0);
+        lay_out(&mut cache, &mut lines, 50, 40.0);
+        lay_out(&mut cache, &mut lines, 90, 60.0);
+        // from either end or from the old mark
+        for mark in [60, 95, 55, 5, 51, 50, 100, 0] {
+            check(&mut cache, &lines, mark);
+        }
+
+        check(&mut cache, &lines, 60);
+        lay_out(&mut cache, &mut lines, 20, 5.0);
+        lay_out(&mut cache, &mut lines, 70, 5.0);
+        assert_eq!(cache.above(), (3, 65.0));
+        // lines 40..=50 become 40..=41
+        cache.dropped(50, 40.0);
+        lines.splice(40..=50, [None, None]);
+        cache.lines_replaced(40, 50, 41);
+        assert_eq!(cache.above(), (2, 25.0));
+        check(&mut cache, &lines, 51);
+        check(&mut cache, &lines, 0);
+    }
+}
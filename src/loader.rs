@@ -0,0 +1,179 @@
+// Reading large files in the background, decoding them chunk by chunk.
+// The UI thread polls for the text as it comes, so the beginning
+// of the file can be viewed while the rest is loading.
+
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver, TryRecvError};
+
+use super::editorconfig::{self, Charset};
+
+// Smaller files are read right away.
+pub const BACKGROUND_THRESHOLD: u64 = 4 << 20;
+// small, so that the first screen shows up quickly
+const FIRST_CHUNK_SIZE: usize = 64 << 10;
+const CHUNK_SIZE: usize = 4 << 20;
+
+pub enum Progress {
+    // decoded text with \n line breaks, and the number of bytes it took
+    Text(String, usize),
+    Done { utf8_loss: bool, crlf: bool },
+    Failed(String),
+}
+
+// How much of the data can be decoded without cutting a char or a CRLF
+// in half. The rest has to wait for more data.
+fn complete_prefix(data: &[u8], charset: Option<Charset>) -> usize {
+    let mut n = data.len();
+    match charset {
+        Some(Charset::Latin1) => {}
+        Some(Charset::Utf16Be) | Some(Charset::Utf16Le) => {
+            n &= !1;
+            let last = |n: usize| {
+                let bytes = [data[n - 2], data[n - 1]];
+                if charset == Some(Charset::Utf16Be) { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+            };
+            // a high surrogate or CR
+            if n >= 2 && ((0xD800..0xDC00).contains(&last(n)) || last(n) == 0x0D) {
+                n -= 2;
+            }
+            return n;
+        }
+        _ => {
+            // the last char, unless it's complete
+            let start = data.iter().rposition(|&b| b & 0xC0 != 0x80).unwrap_or(0);
+            let len = match data.get(start) {
+                Some(b) if b & 0xE0 == 0xC0 => 2,
+                Some(b) if b & 0xF0 == 0xE0 => 3,
+                Some(b) if b & 0xF8 == 0xF0 => 4,
+                _ => 1,
+            };
+            if start + len > n {
+                n = start;
+            }
+        }
+    }
+    if n > 0 && data[n - 1] == b'\r' {
+        n -= 1;
+    }
+    n
+}
+
+pub struct Decoder {
+    charset: Option<Charset>,
+    pending: Vec<u8>,
+    pub utf8_loss: bool,
+    // line breaks had CR in them
+    pub crlf: bool,
+}
+
+impl Decoder {
+    pub fn new(charset: Option<Charset>) -> Decoder {
+        Decoder {
+            charset,
+            pending: Vec::new(),
+            utf8_loss: false,
+            crlf: false,
+        }
+    }
+
+    // Returns the text and how many bytes of the data (and of the
+    // leftovers from the previous call) it took.
+    pub fn decode(&mut self, data: &[u8], at_end: bool) -> (String, usize) {
+        self.pending.extend_from_slice(data);
+        let n = if at_end { self.pending.len() } else { complete_prefix(&self.pending, self.charset) };
+        let (text, utf8_loss) = editorconfig::decode(&self.pending[..n], self.charset);
+        self.pending.drain(..n);
+        self.utf8_loss |= utf8_loss;
+        if text.contains('\r') {
+            self.crlf = true;
+            return (text.replace("\r\n", "\n").replace('\r', "\n"), n);
+        }
+        (text, n)
+    }
+}
+
+pub struct Loader {
+    // in bytes
+    pub total: u64,
+    pub loaded: u64,
+    rx: Receiver<Progress>,
+}
+
+impl Loader {
+    // Dropping the loader stops the reading.
+    pub fn start(mut source: impl Read + Send + 'static, total: u64, charset: Option<Charset>) -> Loader {
+        // a couple of chunks ahead at most
+        let (tx, rx) = sync_channel(2);
+        std::thread::spawn(move || {
+            let mut decoder = Decoder::new(charset);
+            let mut buf = vec![0; FIRST_CHUNK_SIZE];
+            loop {
+                let n = match source.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        let _ = tx.send(Progress::Failed(e.to_string()));
+                        return;
+                    }
+                };
+                let (text, bytes) = decoder.decode(&buf[..n], n == 0);
+                if tx.send(Progress::Text(text, bytes)).is_err() {
+                    return;
+                }
+                if n == 0 {
+                    let _ = tx.send(Progress::Done { utf8_loss: decoder.utf8_loss, crlf: decoder.crlf });
+                    return;
+                }
+                buf.resize(CHUNK_SIZE, 0);
+            }
+        });
+        Loader { total, loaded: 0, rx }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Progress> {
+        match self.rx.try_recv() {
+            Ok(p) => {
+                if let Progress::Text(_, bytes) = &p {
+                    self.loaded += *bytes as u64;
+                }
+                Some(p)
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Progress::Failed("loading thread died".to_owned())),
+        }
+    }
+
+    pub fn percent(&self) -> u64 {
+        (self.loaded * 100).checked_div(self.total).unwrap_or(100).min(100)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_in_pieces(data: &[u8], piece: usize, charset: Option<Charset>) -> (String, bool) {
+        let mut decoder = Decoder::new(charset);
+        let mut text = String::new();
+        for chunk in data.chunks(piece) {
+            text.push_str(&decoder.decode(chunk, false).0);
+        }
+        text.push_str(&decoder.decode(&[], true).0);
+        (text, decoder.crlf)
+    }
+
+    #[test]
+    fn chunks() {
+        let text = "a\r\nb€c\r\n😀\rd";
+        for piece in 1..5 {
+            assert_eq!(decode_in_pieces(text.as_bytes(), piece, None), ("a\nb€c\n😀\nd".to_owned(), true));
+            let utf16: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+            assert_eq!(decode_in_pieces(&utf16, piece, Some(Charset::Utf16Le)), ("a\nb€c\n😀\nd".to_owned(), true));
+        }
+        assert_eq!(decode_in_pieces(b"x\ny", 2, Some(Charset::Latin1)), ("x\ny".to_owned(), false));
+
+        let mut decoder = Decoder::new(None);
+        assert_eq!(decoder.decode(b"ok\xE2\x82", false), ("ok".to_owned(), 2));
+        assert_eq!(decoder.decode(b"\xAC\xFF", true), ("€\u{FFFD}".to_owned(), 4));
+        assert!(decoder.utf8_loss);
+    }
+}
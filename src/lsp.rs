@@ -0,0 +1,843 @@
+// Language Server Protocol client, JSON-RPC over the server's stdio.
+// https://microsoft.github.io/language-server-protocol/specification
+//
+// Only one document is open at a time, like in the editor itself.
+// Responses are read on a separate thread, which calls the notify
+// callback for every message; the UI thread then calls poll().
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+// Line and offset in UTF-16 code units, as LSP wants them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+// Replacement of start..end (positions before the change) with the text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextChange {
+    pub start: Position,
+    pub end: Position,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub start: Position,
+    pub end: Position,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompletionItem {
+    pub label: String,
+    pub insert_text: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Diagnostics { uri: String, diagnostics: Vec<Diagnostic> },
+    // empty if there is nothing to show
+    Hover(String),
+    Definition(Option<(String, Position)>),
+    Completion(Vec<CompletionItem>),
+    Exited,
+}
+
+enum Pending {
+    Initialize,
+    Hover,
+    Definition,
+    Completion,
+    Shutdown,
+}
+
+pub fn utf16_len(s: &str) -> usize {
+    s.chars().map(char::len_utf16).sum()
+}
+
+// Char offset in the line for UTF-16 offset,
+// clamped to the line length if it's out of range.
+pub fn char_offset(line: &str, character: usize) -> usize {
+    let mut u = 0;
+    for (i, c) in line.chars().enumerate() {
+        if u >= character {
+            return i;
+        }
+        u += c.len_utf16();
+    }
+    line.chars().count()
+}
+
+pub fn path_to_uri(path: &Path) -> String {
+    let mut s = path.to_string_lossy().replace('\\', "/");
+    if !s.starts_with('/') {
+        s.insert(0, '/');
+    }
+    let mut result = "file://".to_owned();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/:".contains(&b) {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{:02X}", b));
+        }
+    }
+    result
+}
+
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let s = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == b'%' {
+            let hex = std::str::from_utf8(s.get(i + 1..i + 3)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            bytes.push(s[i]);
+            i += 1;
+        }
+    }
+    let s = String::from_utf8(bytes).ok()?;
+    // "/C:/dir" on Windows
+    let b = s.as_bytes();
+    if b.len() >= 3 && b[0] == b'/' && b[1].is_ascii_alphabetic() && b[2] == b':' {
+        return Some(PathBuf::from(s[1..].replace('/', "\\")));
+    }
+    Some(PathBuf::from(s))
+}
+
+pub fn same_uri(a: &str, b: &str) -> bool {
+    // servers like to normalize drive letter case and escaping
+    match (uri_to_path(a), uri_to_path(b)) {
+        (Some(a), Some(b)) => a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase(),
+        _ => a == b,
+    }
+}
+
+pub fn language_id(path: &Path) -> String {
+    let ext = path.extension().map_or(String::new(), |e| e.to_string_lossy().to_lowercase());
+    match ext.as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "h" => "c",
+        "cc" | "cxx" | "hpp" => "cpp",
+        "md" => "markdown",
+        _ => return ext,
+    }.to_owned()
+}
+
+// Workspace root for the file: the closest directory with .git,
+// failing that the closest one with Cargo.toml, failing that the parent.
+pub fn find_root(path: &Path) -> PathBuf {
+    let parent = path.parent().unwrap_or(path);
+    for marker in &[".git", "Cargo.toml"] {
+        if let Some(d) = parent.ancestors().find(|d| d.join(marker).exists()) {
+            return d.to_owned();
+        }
+    }
+    parent.to_owned()
+}
+
+// Split on whitespace, except inside double quotes, for paths like
+// "C:\Program Files\...". Backslashes are just chars.
+fn split_command_line(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quoted = false;
+    for c in s.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                // "" is an empty argument
+                arg.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+pub fn parse_command_line(s: &str) -> Option<Command> {
+    let mut it = split_command_line(s).into_iter();
+    let mut command = Command::new(it.next()?);
+    command.args(it);
+    Some(command)
+}
+
+// Anything larger is not a real message, the stream is out of sync.
+const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+pub fn read_message(r: &mut impl BufRead) -> Option<Value> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if len.is_some() {
+                break;
+            }
+            continue;
+        }
+        // other headers (and any garbage) are ignored
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            len = n.trim().parse::<usize>().ok();
+        }
+    }
+    let len = len.unwrap();
+    if len > MAX_MESSAGE_LEN {
+        warn!("lsp message too large: {} bytes", len);
+        return None;
+    }
+    let mut body = vec![0; len];
+    r.read_exact(&mut body).ok()?;
+    match serde_json::from_slice(&body) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            warn!("bad lsp message: {}", e);
+            Some(Value::Null)
+        }
+    }
+}
+
+pub fn write_message(w: &mut impl Write, v: &Value) -> std::io::Result<()> {
+    let body = v.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    w.flush()
+}
+
+fn position_to_json(p: Position) -> Value {
+    json!({ "line": p.line, "character": p.character })
+}
+
+fn position_from_json(v: &Value) -> Position {
+    Position {
+        line: v["line"].as_u64().unwrap_or(0) as usize,
+        character: v["character"].as_u64().unwrap_or(0) as usize,
+    }
+}
+
+// MarkupContent, MarkedString, or an array of MarkedString
+fn hover_text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => {
+            items.iter().map(hover_text).filter(|s| !s.is_empty()).collect::<Vec<_>>().join("\n\n")
+        }
+        Value::Object(o) => o.get("value").and_then(Value::as_str).unwrap_or("").to_owned(),
+        _ => String::new(),
+    }
+}
+
+// "foo(${1:x}, $2)$0" -> "foo(x, )"
+fn strip_snippet(s: &str) -> String {
+    let mut result = String::new();
+    let mut it = s.chars().peekable();
+    while let Some(c) = it.next() {
+        match c {
+            '\\' => result.extend(it.next()),
+            '$' if it.peek() == Some(&'{') => {
+                it.next();
+                while matches!(it.peek(), Some(c) if c.is_ascii_digit()) {
+                    it.next();
+                }
+                if it.peek() == Some(&':') {
+                    it.next();
+                }
+            }
+            '$' => {
+                while matches!(it.peek(), Some(c) if c.is_ascii_digit()) {
+                    it.next();
+                }
+            }
+            '}' => {}
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn completion_items(v: &Value) -> Vec<CompletionItem> {
+    let items = match v {
+        Value::Array(items) => items,
+        Value::Object(o) => match o.get("items") {
+            Some(Value::Array(items)) => items,
+            _ => return Vec::new(),
+        },
+        _ => return Vec::new(),
+    };
+    let mut items: Vec<(String, CompletionItem)> = items.iter().map(|item| {
+        let label = item["label"].as_str().unwrap_or("").to_owned();
+        let text = item["textEdit"]["newText"].as_str()
+            .or_else(|| item["insertText"].as_str())
+            .unwrap_or(&label);
+        let insert_text = if item["insertTextFormat"] == 2 {
+            strip_snippet(text)
+        } else {
+            text.to_owned()
+        };
+        let sort_text = item["sortText"].as_str().unwrap_or(&label).to_owned();
+        (sort_text, CompletionItem { label, insert_text })
+    }).collect();
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+fn location(v: &Value) -> Option<(String, Position)> {
+    let v = match v {
+        Value::Array(items) => items.first()?,
+        v => v,
+    };
+    if let Some(uri) = v["uri"].as_str() {
+        return Some((uri.to_owned(), position_from_json(&v["range"]["start"])));
+    }
+    // LocationLink
+    let uri = v["targetUri"].as_str()?;
+    Some((uri.to_owned(), position_from_json(&v["targetSelectionRange"]["start"])))
+}
+
+fn diagnostics(params: &Value) -> Vec<Diagnostic> {
+    let items = match params["diagnostics"].as_array() {
+        Some(items) => items,
+        None => return Vec::new(),
+    };
+    items.iter().map(|d| Diagnostic {
+        start: position_from_json(&d["range"]["start"]),
+        end: position_from_json(&d["range"]["end"]),
+        severity: match d["severity"].as_u64() {
+            Some(1) | None => Severity::Error,
+            Some(2) => Severity::Warning,
+            _ => Severity::Info,
+        },
+        message: d["message"].as_str().unwrap_or("").to_owned(),
+    }).collect()
+}
+
+pub struct Client {
+    // only None while dropping
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    // None means the server closed its stdout
+    incoming: Receiver<Option<Value>>,
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+    initialized: bool,
+    // from the server capabilities, TextDocumentSyncKind.Incremental
+    incremental_sync: bool,
+
+    uri: Option<String>,
+    language_id: String,
+    // didOpen is sent by sync() once the server is initialized
+    opened: bool,
+    version: i64,
+}
+
+impl Client {
+    pub fn start(
+        mut command: Command,
+        root: &Path,
+        notify: impl Fn() + Send + 'static,
+    ) -> std::io::Result<Client> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, incoming) = channel();
+        std::thread::spawn(move || {
+            let mut r = BufReader::new(stdout);
+            loop {
+                let msg = read_message(&mut r);
+                let done = msg.is_none();
+                if tx.send(msg).is_err() {
+                    break;
+                }
+                notify();
+                if done {
+                    break;
+                }
+            }
+        });
+        let mut client = Client {
+            child: Some(child),
+            stdin: Some(stdin),
+            incoming,
+            next_id: 1,
+            pending: HashMap::new(),
+            initialized: false,
+            incremental_sync: false,
+            uri: None,
+            language_id: String::new(),
+            opened: false,
+            version: 0,
+        };
+        let params = json!({
+            "processId": std::process::id(),
+            "rootUri": path_to_uri(root),
+            "capabilities": {
+                "textDocument": {
+                    "synchronization": {},
+                    "publishDiagnostics": {},
+                    "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    "definition": { "linkSupport": true },
+                    "completion": { "completionItem": { "snippetSupport": true } },
+                },
+                "general": { "positionEncodings": ["utf-16"] },
+            },
+        });
+        client.request(Pending::Initialize, "initialize", params);
+        Ok(client)
+    }
+
+    fn send(&mut self, msg: Value) {
+        if let Err(e) = write_message(self.stdin.as_mut().unwrap(), &msg) {
+            warn!("can't write to language server: {}", e);
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn request(&mut self, kind: Pending, method: &str, params: Value) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, kind);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+    }
+
+    // Makes the document current, closing the previous one.
+    // The text is sent with the next sync().
+    pub fn open(&mut self, path: &Path) {
+        self.close();
+        self.uri = Some(path_to_uri(path));
+        self.language_id = language_id(path);
+        self.opened = false;
+    }
+
+    pub fn close(&mut self) {
+        if let Some(uri) = self.uri.take() {
+            if self.opened {
+                self.notification("textDocument/didClose", json!({ "textDocument": { "uri": uri } }));
+            }
+        }
+        self.opened = false;
+    }
+
+    pub fn is_current(&self, uri: &str) -> bool {
+        match &self.uri {
+            Some(u) => same_uri(u, uri),
+            None => false,
+        }
+    }
+
+    // Tells the server about the edits since the last call.
+    // The text is only requested if the whole document has to be sent.
+    pub fn sync(&mut self, changes: &[TextChange], text: impl FnOnce() -> String) {
+        if !self.initialized {
+            return;
+        }
+        let uri = match &self.uri {
+            Some(uri) => uri.clone(),
+            None => return,
+        };
+        if !self.opened {
+            self.opened = true;
+            self.version = 1;
+            let params = json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": self.language_id,
+                    "version": self.version,
+                    "text": text(),
+                },
+            });
+            self.notification("textDocument/didOpen", params);
+            return;
+        }
+        if changes.is_empty() {
+            return;
+        }
+        let content_changes: Vec<Value> = if self.incremental_sync {
+            changes.iter().map(|c| json!({
+                "range": { "start": position_to_json(c.start), "end": position_to_json(c.end) },
+                "text": c.text,
+            })).collect()
+        } else {
+            vec![json!({ "text": text() })]
+        };
+        self.version += 1;
+        let params = json!({
+            "textDocument": { "uri": uri, "version": self.version },
+            "contentChanges": content_changes,
+        });
+        self.notification("textDocument/didChange", params);
+    }
+
+    fn position_request(&mut self, kind: Pending, method: &str, pos: Position) -> bool {
+        let uri = match &self.uri {
+            Some(uri) if self.opened => uri.clone(),
+            _ => return false,
+        };
+        let params = json!({
+            "textDocument": { "uri": uri },
+            "position": position_to_json(pos),
+        });
+        self.request(kind, method, params);
+        true
+    }
+
+    // These return false if the server is not ready yet.
+    // The result comes later as an event.
+    pub fn hover(&mut self, pos: Position) -> bool {
+        self.position_request(Pending::Hover, "textDocument/hover", pos)
+    }
+
+    pub fn definition(&mut self, pos: Position) -> bool {
+        self.position_request(Pending::Definition, "textDocument/definition", pos)
+    }
+
+    pub fn completion(&mut self, pos: Position) -> bool {
+        self.position_request(Pending::Completion, "textDocument/completion", pos)
+    }
+
+    pub fn poll(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        while let Ok(msg) = self.incoming.try_recv() {
+            match msg {
+                Some(msg) => self.handle_message(msg, &mut events),
+                None => events.push(Event::Exited),
+            }
+        }
+        events
+    }
+
+    fn handle_message(&mut self, msg: Value, events: &mut Vec<Event>) {
+        let method = msg["method"].as_str().map(str::to_owned);
+        match (method, msg.get("id")) {
+            // request from the server, we don't support any
+            // but have to reply to something
+            (Some(method), Some(id)) => {
+                let result = if method == "workspace/configuration" {
+                    let n = msg["params"]["items"].as_array().map_or(0, Vec::len);
+                    Value::Array(vec![Value::Null; n])
+                } else {
+                    Value::Null
+                };
+                let id = id.clone();
+                self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+            }
+            (Some(method), None) => match method.as_str() {
+                "textDocument/publishDiagnostics" => {
+                    events.push(Event::Diagnostics {
+                        uri: msg["params"]["uri"].as_str().unwrap_or("").to_owned(),
+                        diagnostics: diagnostics(&msg["params"]),
+                    });
+                }
+                "window/showMessage" | "window/logMessage" => {
+                    info!("language server: {}", msg["params"]["message"].as_str().unwrap_or(""));
+                }
+                _ => {}
+            },
+            (None, Some(id)) => {
+                let kind = match id.as_u64().and_then(|id| self.pending.remove(&id)) {
+                    Some(kind) => kind,
+                    None => return,
+                };
+                if let Some(e) = msg.get("error") {
+                    warn!("language server error: {}", e);
+                }
+                let result = &msg["result"];
+                match kind {
+                    Pending::Initialize => {
+                        let sync = &result["capabilities"]["textDocumentSync"];
+                        let kind = sync.as_u64().or_else(|| sync["change"].as_u64());
+                        self.incremental_sync = kind == Some(2);
+                        self.initialized = true;
+                        self.notification("initialized", json!({}));
+                    }
+                    Pending::Hover => events.push(Event::Hover(hover_text(&result["contents"]))),
+                    Pending::Definition => events.push(Event::Definition(location(result))),
+                    Pending::Completion => events.push(Event::Completion(completion_items(result))),
+                    Pending::Shutdown => {}
+                }
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Exit is sent after the shutdown response, or after some time
+        // without it. Then the server gets some time to exit and is killed
+        // after that. It's done on a separate thread, so that the UI
+        // doesn't hang.
+        let id = self.next_id;
+        self.request(Pending::Shutdown, "shutdown", Value::Null);
+        let mut child = self.child.take().unwrap();
+        let mut stdin = self.stdin.take().unwrap();
+        let incoming = std::mem::replace(&mut self.incoming, channel().1);
+        std::thread::spawn(move || {
+            let start = Instant::now();
+            loop {
+                let timeout = Duration::from_secs(1).saturating_sub(start.elapsed());
+                match incoming.recv_timeout(timeout) {
+                    Ok(Some(msg)) if msg["id"] == id && msg.get("method").is_none() => break,
+                    // anything else is too late to handle
+                    Ok(Some(_)) => {}
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            let exit = json!({ "jsonrpc": "2.0", "method": "exit", "params": null });
+            let _ = write_message(&mut stdin, &exit);
+            drop(stdin);
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(2) {
+                if let Ok(Some(_)) = child.try_wait() {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            warn!("killing language server");
+            let _ = child.kill();
+            let _ = child.wait();
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::Sender;
+
+    // set for the child process
+    const FAKE_SERVER_VAR: &str = "AN_EDITOR_FAKE_LSP_SERVER";
+
+    // Scripted language server, fake_server_session runs it in a child
+    // process. It keeps a copy of the document and reports "bad" as errors.
+    fn fake_server() {
+        fn report(out: &mut impl Write, uri: &str, text: &str) {
+            let mut diagnostics = Vec::new();
+            for (line_no, line) in text.split('\n').enumerate() {
+                if let Some(p) = line.find("bad") {
+                    let character = utf16_len(&line[..p]);
+                    diagnostics.push(json!({
+                        "range": {
+                            "start": { "line": line_no, "character": character },
+                            "end": { "line": line_no, "character": character + 3 },
+                        },
+                        "severity": 1,
+                        "message": "bad",
+                    }));
+                }
+            }
+            let msg = json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/publishDiagnostics",
+                "params": { "uri": uri, "diagnostics": diagnostics },
+            });
+            write_message(out, &msg).unwrap();
+        }
+
+        let stdin = std::io::stdin();
+        let mut r = stdin.lock();
+        let mut out = std::io::stdout();
+        let mut text = String::new();
+        let mut shut_down = false;
+        while let Some(msg) = read_message(&mut r) {
+            let params = &msg["params"];
+            let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_owned();
+            let pos = position_from_json(&params["position"]);
+            let result = match msg["method"].as_str().unwrap_or("") {
+                "initialize" => {
+                    let request = json!({
+                        "jsonrpc": "2.0", "id": "p", "method": "window/workDoneProgress/create",
+                        "params": { "token": "t" },
+                    });
+                    write_message(&mut out, &request).unwrap();
+                    json!({ "capabilities": { "textDocumentSync": { "openClose": true, "change": 2 } } })
+                }
+                "textDocument/didOpen" => {
+                    text = params["textDocument"]["text"].as_str().unwrap().to_owned();
+                    report(&mut out, &uri, &text);
+                    continue;
+                }
+                "textDocument/didChange" => {
+                    for c in params["contentChanges"].as_array().unwrap() {
+                        let to_offset = |p: Position| {
+                            let line_start: usize = text.split('\n').take(p.line).map(|l| l.len() + 1).sum();
+                            let line = text[line_start..].split('\n').next().unwrap();
+                            let n = char_offset(line, p.character);
+                            line_start + line.chars().take(n).map(char::len_utf8).sum::<usize>()
+                        };
+                        let start = to_offset(position_from_json(&c["range"]["start"]));
+                        let end = to_offset(position_from_json(&c["range"]["end"]));
+                        text.replace_range(start..end, c["text"].as_str().unwrap());
+                    }
+                    report(&mut out, &uri, &text);
+                    continue;
+                }
+                "textDocument/hover" => {
+                    let value = format!("{}:{}\n{}", pos.line, pos.character, text);
+                    json!({ "contents": { "kind": "plaintext", "value": value } })
+                }
+                "textDocument/definition" => json!([{
+                    "targetUri": uri,
+                    "targetRange": { "start": { "line": 0, "character": 0 }, "end": { "line": 9, "character": 0 } },
+                    "targetSelectionRange": { "start": { "line": 1, "character": 2 }, "end": { "line": 1, "character": 4 } },
+                }]),
+                "textDocument/completion" => json!({ "isIncomplete": false, "items": [
+                    { "label": "foo", "sortText": "2" },
+                    { "label": "bar(…)", "insertText": "bar(${1:x})$0", "insertTextFormat": 2, "sortText": "1" },
+                ]}),
+                "shutdown" => {
+                    shut_down = true;
+                    Value::Null
+                }
+                // right away, the client may not be reading anymore
+                "exit" => std::process::exit(if shut_down { 0 } else { 1 }),
+                _ => continue,
+            };
+            if let Some(id) = msg.get("id") {
+                write_message(&mut out, &json!({ "jsonrpc": "2.0", "id": id, "result": result })).unwrap();
+            }
+        }
+    }
+
+    fn start_fake_server() -> (Client, Receiver<()>) {
+        let mut command = Command::new(std::env::current_exe().unwrap());
+        command.args(["--exact", "lsp::test::fake_server_session", "-q", "--test-threads=1"]);
+        command.env(FAKE_SERVER_VAR, "1");
+        let (tx, rx): (Sender<()>, _) = channel();
+        let client = Client::start(command, &std::env::temp_dir(), move || {
+            let _ = tx.send(());
+        }).unwrap();
+        (client, rx)
+    }
+
+    fn next_event(client: &mut Client, rx: &Receiver<()>, text: &str) -> Event {
+        loop {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            let mut events = client.poll();
+            client.sync(&[], || text.to_owned());
+            if !events.is_empty() {
+                assert_eq!(events.len(), 1, "{:?}", events);
+                return events.pop().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn fake_server_session() {
+        // this is the child process
+        if std::env::var_os(FAKE_SERVER_VAR).is_some() {
+            fake_server();
+            return;
+        }
+        let (mut client, rx) = start_fake_server();
+        let path = std::env::temp_dir().join("test.rs");
+        client.open(&path);
+        let mut text = "// 😀 bad\nok\n".to_owned();
+
+        let diagnostic = |line, character| Diagnostic {
+            start: Position { line, character },
+            end: Position { line, character: character + 3 },
+            severity: Severity::Error,
+            message: "bad".to_owned(),
+        };
+        let uri = path_to_uri(&path);
+        assert_eq!(next_event(&mut client, &rx, &text), Event::Diagnostics {
+            uri: uri.clone(),
+            diagnostics: vec![diagnostic(0, 6)],
+        });
+
+        // "// 😀 bad\nok\n" -> "// 😀😀 bad\nnot bad\n"
+        client.sync(&[
+            TextChange {
+                start: Position { line: 0, character: 3 },
+                end: Position { line: 0, character: 3 },
+                text: "😀".to_owned(),
+            },
+            TextChange {
+                start: Position { line: 1, character: 0 },
+                end: Position { line: 1, character: 2 },
+                text: "not bad".to_owned(),
+            },
+        ], || panic!());
+        text = "// 😀😀 bad\nnot bad\n".to_owned();
+        assert_eq!(next_event(&mut client, &rx, &text), Event::Diagnostics {
+            uri: uri.clone(),
+            diagnostics: vec![diagnostic(0, 8), diagnostic(1, 4)],
+        });
+
+        assert!(client.hover(Position { line: 1, character: 1 }));
+        assert_eq!(next_event(&mut client, &rx, &text), Event::Hover(format!("1:1\n{}", text)));
+
+        assert!(client.definition(Position::default()));
+        assert_eq!(
+            next_event(&mut client, &rx, &text),
+            Event::Definition(Some((uri, Position { line: 1, character: 2 }))));
+
+        assert!(client.completion(Position::default()));
+        assert_eq!(next_event(&mut client, &rx, &text), Event::Completion(vec![
+            CompletionItem { label: "bar(…)".to_owned(), insert_text: "bar(x)".to_owned() },
+            CompletionItem { label: "foo".to_owned(), insert_text: "foo".to_owned() },
+        ]));
+    }
+
+    #[test]
+    fn messages() {
+        let mut data = Vec::new();
+        write_message(&mut data, &json!({ "id": 1 })).unwrap();
+        assert_eq!(read_message(&mut &data[..]), Some(json!({ "id": 1 })));
+        let data = b"Content-Length: 99999999999999\r\n\r\n{}";
+        assert_eq!(read_message(&mut &data[..]), None);
+    }
+
+    #[test]
+    fn uris() {
+        let p = Path::new("/home/me/a b/ä.rs");
+        assert_eq!(path_to_uri(p), "file:///home/me/a%20b/%C3%A4.rs");
+        assert_eq!(uri_to_path(&path_to_uri(p)), Some(p.to_owned()));
+        assert_eq!(path_to_uri(Path::new("C:\\x\\y.rs")), "file:///C:/x/y.rs");
+        assert_eq!(uri_to_path("file:///c%3A/x/y.rs"), Some(PathBuf::from("c:\\x\\y.rs")));
+        assert!(same_uri("file:///C:/x/y.rs", "file:///c%3A/x/y.rs"));
+
+        assert_eq!(char_offset("a😀b", 3), 2);
+        assert_eq!(char_offset("a😀b", 10), 3);
+        assert_eq!(strip_snippet("f(${1:a}, $2)$0 \\$"), "f(a, ) $");
+
+        assert_eq!(split_command_line("  rust-analyzer "), vec!["rust-analyzer"]);
+        assert_eq!(
+            split_command_line("\"C:\\Program Files\\clangd.exe\" --log=error \"\""),
+            vec!["C:\\Program Files\\clangd.exe", "--log=error", ""]);
+        assert_eq!(split_command_line("pyls --x=\"a b\""), vec!["pyls", "--x=a b"]);
+    }
+}
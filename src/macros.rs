@@ -0,0 +1,237 @@
+use super::view_state::{Motion, ViewState};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MacroCmd {
+    Move(Motion),
+    // move extending the selection
+    Select(Motion),
+    InsertChar(char),
+    Backspace,
+    Del,
+    KillLine,
+    Paste(String),
+    Find(String),
+}
+
+const MOTIONS: [Motion; 12] = [
+    Motion::Left,
+    Motion::Right,
+    Motion::CtrlLeft,
+    Motion::CtrlRight,
+    Motion::Home,
+    Motion::End,
+    Motion::CtrlHome,
+    Motion::CtrlEnd,
+    Motion::Up,
+    Motion::Down,
+    Motion::PgUp,
+    Motion::PgDown,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    Times(usize),
+    UntilEnd,
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Macro {
+    pub commands: Vec<MacroCmd>,
+}
+
+impl Macro {
+    // Returns false if a search failed, the rest of the macro is skipped then.
+    // Doesn't make undo snapshots, it's up to the caller.
+    pub fn play(&self, view_state: &mut ViewState) -> bool {
+        for cmd in &self.commands {
+            match cmd {
+                MacroCmd::Move(m) => {
+                    view_state.move_cursor(*m);
+                    view_state.clear_selection();
+                }
+                MacroCmd::Select(m) => view_state.move_cursor(*m),
+                MacroCmd::InsertChar(c) => view_state.insert_char(*c),
+                MacroCmd::Backspace => view_state.backspace(),
+                MacroCmd::Del => view_state.del(),
+                MacroCmd::KillLine => {
+                    view_state.kill_line();
+                }
+                MacroCmd::Paste(s) => view_state.paste(s),
+                MacroCmd::Find(s) => {
+                    if !view_state.find_next(s) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // Each playback is a separate undo step.
+    // Stops early if a search fails, and in UntilEnd mode also when
+    // the playback no longer advances the cursor.
+    // Returns the number of completed playbacks.
+    pub fn play_repeatedly(&self, view_state: &mut ViewState, repeat: Repeat) -> usize {
+        let max_count = match repeat {
+            Repeat::Times(n) => n,
+            Repeat::UntilEnd => usize::MAX,
+        };
+        let mut count = 0;
+        while count < max_count {
+            let pos = view_state.cursor_pos();
+            if repeat == Repeat::UntilEnd && pos == view_state.document_len() {
+                break;
+            }
+            view_state.make_undo_snapshot();
+            if !self.play(view_state) {
+                break;
+            }
+            count += 1;
+            if repeat == Repeat::UntilEnd && view_state.cursor_pos() <= pos {
+                break;
+            }
+        }
+        count
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+        for cmd in &self.commands {
+            let line = match cmd {
+                MacroCmd::Move(m) => format!("move {:?}", m),
+                MacroCmd::Select(m) => format!("select {:?}", m),
+                MacroCmd::InsertChar(c) => format!("insert {:?}", c),
+                MacroCmd::Backspace => "backspace".to_owned(),
+                MacroCmd::Del => "del".to_owned(),
+                MacroCmd::KillLine => "kill_line".to_owned(),
+                MacroCmd::Paste(s) => format!("paste {:?}", s),
+                MacroCmd::Find(s) => format!("find {:?}", s),
+            };
+            result.push_str(&line);
+            result.push('\n');
+        }
+        result
+    }
+
+    pub fn from_text(s: &str) -> Result<Macro, String> {
+        let mut commands = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, arg) = match line.find(' ') {
+                Some(p) => (&line[..p], line[p + 1..].trim()),
+                None => (line, ""),
+            };
+            let cmd = match name {
+                "move" => parse_motion(arg).map(MacroCmd::Move),
+                "select" => parse_motion(arg).map(MacroCmd::Select),
+                "insert" => parse_quoted(arg, '\'').and_then(|s| {
+                    let mut it = s.chars();
+                    match (it.next(), it.next()) {
+                        (Some(c), None) => Ok(MacroCmd::InsertChar(c)),
+                        _ => Err(format!("expected single char, got {:?}", s)),
+                    }
+                }),
+                "backspace" => Ok(MacroCmd::Backspace),
+                "del" => Ok(MacroCmd::Del),
+                "kill_line" => Ok(MacroCmd::KillLine),
+                "paste" => parse_quoted(arg, '"').map(MacroCmd::Paste),
+                "find" => parse_quoted(arg, '"').map(MacroCmd::Find),
+                _ => Err(format!("unknown command {:?}", name)),
+            };
+            commands.push(cmd.map_err(|e| format!("line {}: {}", i + 1, e))?);
+        }
+        Ok(Macro { commands })
+    }
+}
+
+fn parse_motion(s: &str) -> Result<Motion, String> {
+    MOTIONS.iter()
+        .find(|m| format!("{:?}", m) == s)
+        .cloned()
+        .ok_or_else(|| format!("unknown motion {:?}", s))
+}
+
+// Inverse of the Debug formatting for str and char.
+fn parse_quoted(s: &str, quote: char) -> Result<String, String> {
+    let inner = if s.len() >= 2 && s.starts_with(quote) && s.ends_with(quote) {
+        &s[1..s.len() - 1]
+    } else {
+        return Err(format!("expected {}-quoted literal, got {:?}", quote, s));
+    };
+    let mut result = String::new();
+    let mut it = inner.chars();
+    while let Some(c) = it.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let c = match it.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('u') => {
+                let code: String = it.by_ref().take_while(|&c| c != '}').collect();
+                code.strip_prefix('{')
+                    .and_then(|code| u32::from_str_radix(code, 16).ok())
+                    .and_then(std::char::from_u32)
+                    .ok_or_else(|| format!("bad unicode escape in {:?}", s))?
+            }
+            _ => return Err(format!("bad escape in {:?}", s)),
+        };
+        result.push(c);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::view_state::create_headless_view_state;
+
+    #[test]
+    fn text_round_trip() {
+        let m = Macro {
+            commands: vec![
+                MacroCmd::Move(Motion::CtrlRight),
+                MacroCmd::Select(Motion::PgDown),
+                MacroCmd::InsertChar('\''),
+                MacroCmd::InsertChar('\t'),
+                MacroCmd::InsertChar('й'),
+                MacroCmd::Backspace,
+                MacroCmd::Del,
+                MacroCmd::KillLine,
+                MacroCmd::Paste("a \"b\"\n\\c\u{7f}".to_owned()),
+                MacroCmd::Find("".to_owned()),
+            ],
+        };
+        let text = m.to_text();
+        assert_eq!(Macro::from_text(&text), Ok(m));
+
+        assert!(Macro::from_text("move Sideways").is_err());
+        assert!(Macro::from_text("insert 'ab'").is_err());
+        assert!(Macro::from_text("paste \"unterminated").is_err());
+    }
+
+    #[test]
+    fn playback() {
+        let mut vs = create_headless_view_state(500.0, 300.0);
+        vs.load("one\ntwo\nthree", false);
+        let m = Macro::from_text("move Home\ninsert '-'\nmove End\nmove Right").unwrap();
+        assert_eq!(m.play_repeatedly(&mut vs, Repeat::UntilEnd), 3);
+        assert_eq!(vs.content(), "-one\n-two\n-three");
+        vs.undo();
+        assert_eq!(vs.content(), "-one\n-two\nthree");
+
+        vs.load("foo bar foo", false);
+        let m = Macro::from_text("find \"foo\"\npaste \"baz\"").unwrap();
+        assert_eq!(m.play_repeatedly(&mut vs, Repeat::Times(5)), 2);
+        assert_eq!(vs.content(), "baz bar baz");
+    }
+}
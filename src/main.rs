@@ -3,8 +3,11 @@
 
 use std::mem;
 use std::ptr::{null, null_mut};
-use std::io::Error;
-use std::path::PathBuf;
+use std::io::{Error, Read};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
 
 use winapi::Interface;
 use winapi::shared::minwindef::*;
@@ -19,6 +22,7 @@ use winapi::um::dwrite::*;
 use winapi::um::d2d1::{
     D2D1_SIZE_U,
     D2D1_POINT_2F,
+    D2D1_RECT_F,
 };
 
 use log::info;
@@ -29,26 +33,82 @@ mod line_gap_buffer;
 mod view_state;
 mod win_util;
 mod key_util;
+mod config;
+mod kill_ring;
+mod macros;
+mod scripting;
+mod filter;
+mod diff;
+mod lsp;
+mod compiler_output;
+mod completion;
+mod snippet;
+mod indent;
+mod editorconfig;
+mod brackets;
+mod rows;
+mod layout_cache;
+mod loader;
+mod follow;
+mod hex;
+mod mapped;
 
 use com_ptr::ComPtr;
-use view_state::ViewState;
+use indent::IndentStyle;
+use view_state::{Brushes, Diagnostic, DiagnosticSource, Motion, Typed, ViewState, Wrap};
+use config::{Config, Keymap};
+use kill_ring::KillRing;
+use follow::{Change, Follower};
+use hex::HexDocument;
+use mapped::MappedDocument;
+use loader::{Loader, Progress};
+use macros::{Macro, MacroCmd, Repeat};
+use scripting::ScriptHost;
 
 use win_util::*;
-use key_util::{KeyEvent, KeyMatcher};
+use key_util::{KeyEvent, KeyMatcher, SequenceMatch};
 
 #[derive(PartialEq, Eq)]
 enum ActionType {
     InsertChar,
     Backspace,
     Del,
+    Kill,
+    Yank,
     Other,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Action {
+    Command(Idm),
+    Motion(Motion),
+    KillLine,
+    KillRegion,
+    CopyRegion,
+    Yank,
+    YankPop,
+    SetMark,
+    Cancel,
+    Script(usize),
+}
+
+enum KeyMatch {
+    Action(Action),
+    // part of a longer key sequence, or an undefined key sequence
+    Consumed,
+    NotBound,
+}
+
 struct AppState {
     hwnd: HWND,
 
+    d2d_factory: ComPtr<ID2D1Factory>,
     dwrite_factory: ComPtr<IDWriteFactory>,
     resources: Resources,
+    // AN_EDITOR_DEVICE_LOSS=n makes every n-th frame report the device
+    // as lost, to test the recovery
+    simulated_device_loss: Option<u32>,
+    frame_count: u32,
     view_state: ViewState,
     font_size: f32,
 
@@ -57,10 +117,66 @@ struct AppState {
     flash: Option<String>,
 
     left_button_pressed: bool,
+    // where the line selection started
+    gutter_drag_line: Option<usize>,
     last_action: ActionType,
 
     menu: HMENU,
-    key_bindings: Vec<(KeyMatcher, Idm)>,
+    key_bindings: Vec<(Vec<KeyMatcher>, Action)>,
+    pending_keys: Vec<KeyEvent>,
+    // WM_CHAR that follows a keydown consumed by a key binding
+    // should not insert anything
+    swallow_char: bool,
+
+    // emacs-style region, it's the selection that survives cursor movement
+    mark_active: bool,
+    kill_ring: KillRing,
+    last_yank: Option<(usize, usize)>,
+
+    macro_recording: Option<Vec<MacroCmd>>,
+    last_macro: Option<Macro>,
+    last_search: Option<String>,
+    last_filter_command: Option<String>,
+
+    scripts: ScriptHost,
+    // of the last script command, shown in the status bar
+    script_error: Option<String>,
+    config: Config,
+
+    lsp: Option<lsp::Client>,
+    // the command lsp was started with
+    lsp_command: String,
+
+    // while the build is running
+    build: Option<Receiver<Result<compiler_output::BuildResult, String>>>,
+    build_messages: Vec<compiler_output::Message>,
+    current_build_message: Option<usize>,
+    build_status: Option<String>,
+
+    completion: Option<completion::Popup>,
+    // info about the code at the position, shown under the cursor
+    // until a key is pressed or the cursor moves away
+    hover: Option<(usize, String)>,
+    // for the current file
+    editorconfig: editorconfig::Settings,
+    // by file extension
+    snippets: HashMap<String, Vec<snippet::Snippet>>,
+    // start of the word completion was requested for,
+    // the reply is dropped if the cursor has left it by then
+    pending_completion: Option<usize>,
+
+    // while a large file is coming in
+    loading: Option<Loader>,
+    // too large to edit, or only partly loaded
+    read_only: bool,
+    // bytes of the file that are in the document
+    file_len: u64,
+    // the file is watched for appended text
+    follow: Option<Follower>,
+    // binary files are edited as bytes, the text view is empty then
+    hex: Option<HexDocument>,
+    // files past large_file_mb are only viewed, the text view is empty then
+    mapped: Option<MappedDocument<FileMapping>>,
 }
 
 impl HasHwnd for AppState {
@@ -102,56 +218,195 @@ impl AppState {
         // It will be changed right away on WM_SIZE.
         let width = 50.0;
         let height = 50.0;
-        let view_state = ViewState::new(
+        let mut view_state = ViewState::new(
             width, height,
             resources.text_format.clone(),
             dwrite_factory.clone(),
         );
+        let config = Config::load();
+        view_state.set_line_numbers(config.line_numbers);
+        view_state.set_wrap(config.wrap, config.wrap_indent);
+        view_state.set_layout_cache_capacity(config.layout_cache);
+        let scripts_dir = config::config_dir().map(|d| d.join("scripts"));
+        let (scripts, mut errors) = ScriptHost::load(scripts_dir.as_deref());
+        let (snippets, snippet_errors) = match config::config_dir() {
+            Some(d) => snippet::load_snippets(&d.join("snippets")),
+            None => (HashMap::new(), Vec::new()),
+        };
+        errors.extend(snippet_errors);
+        let mut key_bindings = init_key_bindings(config.keymap);
+        for (i, cmd) in scripts.commands.iter().enumerate() {
+            if let Some(key) = &cmd.key {
+                match key_util::parse_key_spec(key) {
+                    Ok(km) => add_key_binding(&mut key_bindings, vec![km], Action::Script(i)),
+                    Err(e) => errors.push(format!("{}: {}", cmd.title, e)),
+                }
+            }
+        }
+
+        let simulated_device_loss = std::env::var("AN_EDITOR_DEVICE_LOSS").ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&n| n > 0);
 
         AppState {
             hwnd,
+            d2d_factory,
             dwrite_factory,
             resources,
+            simulated_device_loss,
+            frame_count: 0,
             view_state,
             font_size: DEFAULT_FONT_SIZE,
 
             filename: None,
 
-            flash: None,
+            flash: if errors.is_empty() { None } else { Some(errors.join("\n")) },
 
             left_button_pressed: false,
+            gutter_drag_line: None,
             last_action: ActionType::Other,
 
-            menu: create_app_menu(),
-            key_bindings: init_key_bindings(),
+            menu: create_app_menu(&scripts),
+            key_bindings,
+            pending_keys: Vec::new(),
+            swallow_char: false,
+
+            mark_active: false,
+            kill_ring: KillRing::new(),
+            last_yank: None,
+
+            macro_recording: None,
+            last_macro: None,
+            last_search: None,
+            last_filter_command: None,
+
+            scripts,
+            script_error: None,
+            config,
+
+            lsp: None,
+            lsp_command: String::new(),
+
+            build: None,
+            build_messages: Vec::new(),
+            current_build_message: None,
+            build_status: None,
+
+            completion: None,
+            hover: None,
+            editorconfig: editorconfig::Settings::default(),
+            snippets,
+            pending_completion: None,
+            loading: None,
+            read_only: false,
+            file_len: 0,
+            follow: None,
+            hex: None,
+            mapped: None,
         }
     }
 
     fn get_title(&self) -> String {
         let mut s = String::new();
-        if self.view_state.modified() {
+        if self.modified() {
             s.push_str("* ");
         }
         match &self.filename {
             Some(p) => s.push_str(&p.file_name().unwrap().to_string_lossy()),
             None => s.push_str("untitled"),
         };
+        if self.macro_recording.is_some() {
+            s.push_str(" (recording macro)");
+        }
+        if let Some(status) = &self.build_status {
+            s.push_str(&format!(" [{}]", status));
+        }
+        if let Some(loader) = &self.loading {
+            s.push_str(&format!(" (loading {}%)", loader.percent()));
+        } else if self.read_only {
+            s.push_str(" (read only)");
+        }
+        if self.follow.is_some() {
+            s.push_str(" (following)");
+        }
         s
     }
 
+    fn modified(&self) -> bool {
+        match &self.hex {
+            Some(hex) => hex.modified(),
+            None => self.view_state.modified(),
+        }
+    }
+
+    // Beeps if the text can't be edited.
+    fn check_writable(&self) -> bool {
+        let writable = self.loading.is_none() && !self.read_only && self.hex.is_none();
+        if !writable {
+            unsafe {
+                MessageBeep(MB_OK);
+            }
+        }
+        writable
+    }
+
+    fn add_flash(&mut self, message: &str) {
+        match &mut self.flash {
+            Some(s) => {
+                s.push('\n');
+                s.push_str(message);
+            }
+            None => self.flash = Some(message.to_owned()),
+        }
+    }
+
     fn update_title(&self) {
         set_window_title(self.hwnd, &self.get_title());
+        // the status bar shows the modified state too
+        invalidate_rect(self.hwnd);
     }
 
-    fn match_key_event(&self, k: &KeyEvent) -> Option<Idm> {
+    fn record(&mut self, cmd: MacroCmd) {
+        if let Some(commands) = &mut self.macro_recording {
+            commands.push(cmd);
+        }
+    }
+
+    fn match_key_event(&mut self, k: &KeyEvent) -> KeyMatch {
+        if k.is_modifier() {
+            return if self.pending_keys.is_empty() {
+                KeyMatch::NotBound
+            } else {
+                KeyMatch::Consumed
+            };
+        }
+        self.pending_keys.push(k.clone());
         let mut matches = Vec::new();
-        for (km, cmd) in &self.key_bindings {
-            if km.matches(k) {
-                matches.push(*cmd);
+        let mut is_prefix = false;
+        for (seq, action) in &self.key_bindings {
+            match key_util::match_sequence(seq, &self.pending_keys) {
+                SequenceMatch::Full => matches.push(*action),
+                SequenceMatch::Prefix => is_prefix = true,
+                SequenceMatch::No => {}
             }
         }
         assert!(matches.len() < 2);
-        matches.first().cloned()
+        assert!(matches.is_empty() || !is_prefix);
+        if let Some(&action) = matches.first() {
+            self.pending_keys.clear();
+            return KeyMatch::Action(action);
+        }
+        if is_prefix {
+            return KeyMatch::Consumed;
+        }
+        let was_sequence = self.pending_keys.len() > 1;
+        self.pending_keys.clear();
+        if was_sequence {
+            info!("undefined key sequence");
+            KeyMatch::Consumed
+        } else {
+            KeyMatch::NotBound
+        }
     }
 }
 
@@ -159,7 +414,17 @@ struct Resources {
     render_target: ComPtr<ID2D1HwndRenderTarget>,
     brush: ComPtr<ID2D1Brush>,
     sel_brush: ComPtr<ID2D1Brush>,
+    popup_brush: ComPtr<ID2D1Brush>,
+    current_line_brush: ComPtr<ID2D1Brush>,
+    gutter_brush: ComPtr<ID2D1Brush>,
+    // indexed by lsp::Severity
+    diagnostic_brushes: Vec<ComPtr<ID2D1Brush>>,
     text_format: ComPtr<IDWriteTextFormat>,
+    // doesn't change with the font size
+    status_text_format: ComPtr<IDWriteTextFormat>,
+    status_bar_height: f32,
+    // of the monitor the window is on, the render target works in DIPs
+    dpi: f32,
 }
 
 impl Resources {
@@ -168,6 +433,7 @@ impl Resources {
         d2d_factory: &ComPtr<ID2D1Factory>,
         dwrite_factory: &ComPtr<IDWriteFactory>,
     ) -> Self {
+        let dpi = get_dpi(hwnd);
         let render_target = unsafe {
             let render_properties = D2D1_RENDER_TARGET_PROPERTIES {
                 _type: D2D1_RENDER_TARGET_TYPE_DEFAULT,
@@ -175,8 +441,8 @@ impl Resources {
                     format: DXGI_FORMAT_B8G8R8A8_UNORM,
                     alphaMode: D2D1_ALPHA_MODE_IGNORE,
                 },
-                dpiX: 0.0,
-                dpiY: 0.0,
+                dpiX: dpi,
+                dpiY: dpi,
                 usage: D2D1_RENDER_TARGET_USAGE_NONE,
                 minLevel: D2D1_FEATURE_LEVEL_DEFAULT,
             };
@@ -214,11 +480,53 @@ impl Resources {
             assert!(hr == S_OK, "0x{:x}", hr);
             ComPtr::from_raw(brush)
         };
+        let popup_brush = unsafe {
+            let c = D2D1_COLOR_F { r: 0.15, g: 0.15, b: 0.25, a: 1.0 };
+            let mut brush = null_mut();
+            let hr = render_target.CreateSolidColorBrush(&c, null(), &mut brush);
+            assert!(hr == S_OK, "0x{:x}", hr);
+            ComPtr::from_raw(brush)
+        };
+        let current_line_brush = unsafe {
+            let c = D2D1_COLOR_F { r: 0.07, g: 0.07, b: 0.28, a: 1.0 };
+            let mut brush = null_mut();
+            let hr = render_target.CreateSolidColorBrush(&c, null(), &mut brush);
+            assert!(hr == S_OK, "0x{:x}", hr);
+            ComPtr::from_raw(brush)
+        };
+        let gutter_brush = unsafe {
+            let c = D2D1_COLOR_F { r: 0.5, g: 0.5, b: 0.6, a: 1.0 };
+            let mut brush = null_mut();
+            let hr = render_target.CreateSolidColorBrush(&c, null(), &mut brush);
+            assert!(hr == S_OK, "0x{:x}", hr);
+            ComPtr::from_raw(brush)
+        };
+        let status_text_format = create_text_format(dwrite_factory, STATUS_FONT_SIZE);
+        let status_bar_height = text_layout::TextLayout::new(
+            "0", dwrite_factory, &status_text_format, 1e6).height + 2.0 * STATUS_MARGIN;
+        let diagnostic_brushes = [
+            (1.0, 0.3, 0.3),  // error
+            (0.9, 0.8, 0.2),  // warning
+            (0.4, 0.6, 1.0),  // info
+        ].iter().map(|&(r, g, b)| unsafe {
+            let c = D2D1_COLOR_F { r, g, b, a: 1.0 };
+            let mut brush = null_mut();
+            let hr = render_target.CreateSolidColorBrush(&c, null(), &mut brush);
+            assert!(hr == S_OK, "0x{:x}", hr);
+            ComPtr::from_raw(brush).up()
+        }).collect();
         Resources {
             render_target,
             brush: brush.up(),
             sel_brush: sel_brush.up(),
+            popup_brush: popup_brush.up(),
+            current_line_brush: current_line_brush.up(),
+            gutter_brush: gutter_brush.up(),
+            diagnostic_brushes,
             text_format: create_text_format(dwrite_factory, DEFAULT_FONT_SIZE),
+            status_text_format,
+            status_bar_height,
+            dpi,
         }
     }
 }
@@ -241,13 +549,29 @@ fn create_text_format(dwrite_factory: &ComPtr<IDWriteFactory>, size: f32) -> Com
     }
 }
 
+// Sizes are in DIPs (1/96 inch), scaled to the monitor DPI.
 const DEFAULT_FONT_SIZE: f32 = 14.0;
+const STATUS_FONT_SIZE: f32 = 12.0;
+const STATUS_MARGIN: f32 = 3.0;
 const MIN_FONT_SIZE: f32 = 4.0;
 const MAX_FONT_SIZE: f32 = 32.0;
 
-const PADDING_LEFT: f32 = 5.0;
+const PADDING_LEFT: f32 = view_state::MARKERS_WIDTH;
+
+// Mouse positions come in pixels.
+fn mouse_pos(a: &AppState, lParam: LPARAM) -> (f32, f32) {
+    let scale = 96.0 / a.resources.dpi;
+    (GET_X_LPARAM(lParam) as f32 * scale, GET_Y_LPARAM(lParam) as f32 * scale)
+}
+
+// Where the text starts, right of the gutter.
+fn text_left(a: &AppState) -> f32 {
+    a.view_state.gutter_width() + PADDING_LEFT
+}
 
-fn paint(app_state: &mut AppState) {
+// Returns the EndDraw result, D2DERR_RECREATE_TARGET when the device is lost.
+fn paint(app_state: &mut AppState) -> HRESULT {
+    let left = text_left(app_state);
     let resources = &app_state.resources;
     let view_state = &mut app_state.view_state;
     let rt = &resources.render_target;
@@ -257,67 +581,1175 @@ fn paint(app_state: &mut AppState) {
         rt.Clear(&c);
 
         let origin = D2D1_POINT_2F {
-            x: PADDING_LEFT,
+            x: left,
             y: 0.0,
         };
-        view_state.render(origin, rt, &resources.brush, &resources.sel_brush);
+        let brushes = Brushes {
+            text: &resources.brush,
+            selection: &resources.sel_brush,
+            current_line: &resources.current_line_brush,
+            gutter: &resources.gutter_brush,
+            diagnostics: &resources.diagnostic_brushes,
+        };
+        match (&mut app_state.hex, &mut app_state.mapped) {
+            (Some(hex), _) => paint_hex(hex, resources, &app_state.dwrite_factory),
+            (_, Some(doc)) => paint_mapped(doc, resources, &app_state.dwrite_factory),
+            _ => view_state.render(origin, rt, brushes),
+        }
+        if let Some(popup) = &app_state.completion {
+            let (x, y) = view_state.cursor_bottom_coords();
+            paint_completion_popup(popup, x + left, y, resources, &app_state.dwrite_factory);
+        }
+        if let Some((pos, text)) = &app_state.hover {
+            if *pos == view_state.cursor_pos() {
+                let (x, y) = view_state.cursor_bottom_coords();
+                paint_hover_popup(text, x + left, y, resources, &app_state.dwrite_factory);
+            }
+        }
+        paint_status_bar(app_state);
 
         let hr = rt.EndDraw(null_mut(), null_mut());
-        assert!(hr == S_OK, "0x{:x}", hr);
-        // TODO: if hr == D2DERR_RECREATE_TARGET, recreate resources
+        app_state.frame_count += 1;
+        if let Some(n) = app_state.simulated_device_loss {
+            if app_state.frame_count.is_multiple_of(n) {
+                info!("simulating device loss");
+                return D2DERR_RECREATE_TARGET;
+            }
+        }
+        hr
+    }
+}
+
+// The render target and the brushes go with the device. The text formats
+// don't depend on it and are kept, with the current font size.
+fn recreate_resources(a: &mut AppState) {
+    let text_format = a.resources.text_format.clone();
+    a.resources = Resources::new(a.hwnd, &a.d2d_factory, &a.dwrite_factory);
+    a.resources.text_format = text_format;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum StatusField {
+    Position,
+    Selection,
+    Length,
+    Encoding,
+    LineEnding,
+    Indent,
+    FontSize,
+    Modified,
+    Loading,
+    ScriptError,
+}
+
+impl StatusField {
+    // What a click on the field does.
+    fn command(self) -> Option<Idm> {
+        match self {
+            StatusField::Position => Some(Idm::GoToLine),
+            StatusField::Encoding => Some(Idm::ChangeEncoding),
+            StatusField::LineEnding => Some(Idm::ChangeLineEnding),
+            StatusField::Indent => Some(Idm::ChangeIndent),
+            StatusField::Modified => Some(Idm::Save),
+            StatusField::Loading => Some(Idm::StopLoading),
+            StatusField::Selection | StatusField::Length | StatusField::FontSize |
+            StatusField::ScriptError => None,
+        }
+    }
+}
+
+// Fields with their layouts and left edges, left to right.
+fn status_bar_fields(a: &AppState) -> Vec<(StatusField, text_layout::TextLayout, f32)> {
+    const GAP: f32 = 20.0;
+    let (line, col, visual_col) = a.view_state.cursor_line_col();
+    let (sel_start, sel_end) = a.view_state.selection_range();
+    let charset = a.editorconfig.charset.unwrap_or(editorconfig::Charset::Utf8);
+    let end_of_line = a.editorconfig.end_of_line.unwrap_or(editorconfig::EndOfLine::Lf);
+    let (position, length) = match (&a.hex, &a.mapped) {
+        (Some(hex), _) => (format!("Offset {:X}", hex.cursor()), format!("{} bytes", hex.data().len())),
+        (_, Some(doc)) => (format!("Ln {}", doc.top_line() + 1), format!("{} bytes", doc.size())),
+        _ => (format!("Ln {}, Col {}, Vis {}", line, col, visual_col),
+              format!("{} chars", a.view_state.document_len())),
+    };
+    let mut fields = vec![(StatusField::Position, position)];
+    if sel_end > sel_start {
+        fields.push((StatusField::Selection, format!("{} selected", sel_end - sel_start)));
+    }
+    fields.extend(vec![
+        (StatusField::Length, length),
+        (StatusField::Encoding, charset.name().to_uppercase()),
+        (StatusField::LineEnding, end_of_line.name().to_uppercase()),
+        (StatusField::Indent, match a.view_state.indent_style {
+            IndentStyle::Tabs => "Tabs".to_owned(),
+            IndentStyle::Spaces(n) => format!("Spaces: {}", n),
+        }),
+        (StatusField::FontSize, format!("Font: {}", a.font_size)),
+        (StatusField::Modified, if a.modified() { "Modified" } else { "Saved" }.to_owned()),
+    ]);
+    if let Some(loader) = &a.loading {
+        fields.push((StatusField::Loading, format!("Loading {}%", loader.percent())));
+    } else if let Some(doc) = a.mapped.as_ref().filter(|doc| !doc.indexed()) {
+        fields.push((StatusField::Loading, format!("Indexing {}%", doc.percent())));
+    } else if a.read_only {
+        fields.push((StatusField::Loading, "Read only".to_owned()));
+    }
+    if let Some(e) = &a.script_error {
+        fields.push((StatusField::ScriptError, e.lines().next().unwrap_or_default().to_owned()));
+    }
+    let mut x = STATUS_MARGIN;
+    fields.into_iter().map(|(field, text)| {
+        let layout = text_layout::TextLayout::new(
+            &text, &a.dwrite_factory, &a.resources.status_text_format, 1e6);
+        let left = x;
+        x += layout.width + GAP;
+        (field, layout, left)
+    }).collect()
+}
+
+fn paint_status_bar(a: &AppState) {
+    let resources = &a.resources;
+    let rt = &resources.render_target;
+    let size = unsafe { rt.GetSize() };
+    let top = size.height - resources.status_bar_height;
+    unsafe {
+        let rect = D2D1_RECT_F { left: 0.0, top, right: size.width, bottom: size.height };
+        rt.FillRectangle(&rect, resources.popup_brush.as_raw());
+        for (_, layout, left) in status_bar_fields(a) {
+            rt.DrawTextLayout(
+                D2D1_POINT_2F { x: left, y: top + STATUS_MARGIN },
+                layout.raw.as_raw(),
+                resources.brush.as_raw(),
+                D2D1_DRAW_TEXT_OPTIONS_NONE,
+            );
+        }
+    }
+}
+
+// None if the point is not on the status bar.
+fn status_field_at(a: &AppState, x: f32, y: f32) -> Option<Option<StatusField>> {
+    let size = unsafe { a.resources.render_target.GetSize() };
+    if y < size.height - a.resources.status_bar_height {
+        return None;
+    }
+    Some(status_bar_fields(a).into_iter()
+        .find(|(_, layout, left)| *left <= x && x < left + layout.width)
+        .map(|(field, _, _)| field))
+}
+
+// Char width and row height.
+fn hex_metrics(resources: &Resources, dwrite_factory: &ComPtr<IDWriteFactory>) -> (f32, f32) {
+    let layout = text_layout::TextLayout::new("0", dwrite_factory, &resources.text_format, 1e6);
+    (layout.width, layout.height)
+}
+
+// The cursor byte is highlighted in both columns, in the one typed in
+// just the digit that is typed over.
+fn paint_hex(hex: &mut HexDocument, resources: &Resources, dwrite_factory: &ComPtr<IDWriteFactory>) {
+    let rt = &resources.render_target;
+    let size = unsafe { rt.GetSize() };
+    let (char_width, row_height) = hex_metrics(resources, dwrite_factory);
+    hex.set_page_rows(((size.height - resources.status_bar_height) / row_height) as usize);
+    let cursor_row = hex.cursor() / hex::BYTES_PER_ROW;
+    let i = hex.cursor() % hex::BYTES_PER_ROW;
+    let top = hex.top_row();
+    let digits = hex.offset_digits();
+    for row in top..(top + hex.page_rows() + 1).min(hex.num_rows()) {
+        let y = (row - top) as f32 * row_height;
+        if row == cursor_row && !hex.data().is_empty() {
+            let (hex_left, hex_width) = match (hex.ascii, hex.low_nibble()) {
+                (true, _) => (hex::hex_col(i, digits), 2),
+                (false, low) => (hex::hex_col(i, digits) + low as usize, 1),
+            };
+            let cells = [
+                (hex_left, hex_width, !hex.ascii),
+                (hex::ascii_col(i, digits), 1, hex.ascii),
+            ];
+            for (col, width, active) in cells {
+                let left = PADDING_LEFT + col as f32 * char_width;
+                let rect = D2D1_RECT_F { left, top: y, right: left + width as f32 * char_width, bottom: y + row_height };
+                let brush = if active { &resources.sel_brush } else { &resources.current_line_brush };
+                unsafe {
+                    rt.FillRectangle(&rect, brush.as_raw());
+                }
+            }
+        }
+        let layout = text_layout::TextLayout::new(
+            &hex.format_row(row), dwrite_factory, &resources.text_format, 1e6);
+        layout.draw(rt, PADDING_LEFT, y, &resources.brush);
+    }
+}
+
+// Just the lines on screen, there is no cursor.
+fn paint_mapped(
+    doc: &mut MappedDocument<FileMapping>,
+    resources: &Resources,
+    dwrite_factory: &ComPtr<IDWriteFactory>,
+) {
+    let rt = &resources.render_target;
+    let size = unsafe { rt.GetSize() };
+    let (_, row_height) = hex_metrics(resources, dwrite_factory);
+    doc.set_page_lines(((size.height - resources.status_bar_height) / row_height) as usize);
+    for (i, line) in doc.visible_lines().iter().enumerate() {
+        let layout = text_layout::TextLayout::new(line, dwrite_factory, &resources.text_format, 1e6);
+        layout.draw(rt, PADDING_LEFT, i as f32 * row_height, &resources.brush);
+    }
+}
+
+// Below the cursor, or above it if there is no room.
+fn paint_completion_popup(
+    popup: &completion::Popup,
+    x: f32, y: f32,
+    resources: &Resources,
+    dwrite_factory: &ComPtr<IDWriteFactory>,
+) {
+    const MARGIN: f32 = 3.0;
+    let rt = &resources.render_target;
+    let (labels, selected) = popup.visible_labels();
+    let layout = text_layout::TextLayout::new(
+        &labels.join("\n"), dwrite_factory, &resources.text_format, 10000.0);
+    let size = unsafe { rt.GetSize() };
+    let width = layout.width + 2.0 * MARGIN;
+    let height = layout.height + 2.0 * MARGIN;
+    let left = x.min(size.width - width).max(0.0);
+    let top = if y + height > size.height && y - layout.line_height - height >= 0.0 {
+        y - layout.line_height - height
+    } else {
+        y
+    };
+    let row_height = layout.height / labels.len() as f32;
+    unsafe {
+        let rect = D2D1_RECT_F { left, top, right: left + width, bottom: top + height };
+        rt.FillRectangle(&rect, resources.popup_brush.as_raw());
+        let row_top = top + MARGIN + row_height * selected as f32;
+        let rect = D2D1_RECT_F { left, top: row_top, right: left + width, bottom: row_top + row_height };
+        rt.FillRectangle(&rect, resources.sel_brush.as_raw());
+        rt.DrawTextLayout(
+            D2D1_POINT_2F { x: left + MARGIN, y: top + MARGIN },
+            layout.raw.as_raw(),
+            resources.brush.as_raw(),
+            D2D1_DRAW_TEXT_OPTIONS_NONE,
+        );
+    }
+}
+
+// Below the cursor like the completion popup, long text is cut.
+fn paint_hover_popup(
+    text: &str,
+    x: f32, y: f32,
+    resources: &Resources,
+    dwrite_factory: &ComPtr<IDWriteFactory>,
+) {
+    const MARGIN: f32 = 3.0;
+    const MAX_WIDTH: f32 = 600.0;
+    const MAX_LINES: usize = 30;
+    let rt = &resources.render_target;
+    let mut lines: Vec<&str> = text.lines().take(MAX_LINES + 1).collect();
+    if lines.len() > MAX_LINES {
+        lines[MAX_LINES] = "...";
+    }
+    let layout = text_layout::TextLayout::new(
+        &lines.join("\n"), dwrite_factory, &resources.text_format, MAX_WIDTH);
+    let size = unsafe { rt.GetSize() };
+    let width = layout.width + 2.0 * MARGIN;
+    let height = layout.height + 2.0 * MARGIN;
+    let left = x.min(size.width - width).max(0.0);
+    let top = if y + height > size.height && y - layout.line_height - height >= 0.0 {
+        y - layout.line_height - height
+    } else {
+        y
+    };
+    unsafe {
+        let rect = D2D1_RECT_F { left, top, right: left + width, bottom: top + height };
+        rt.FillRectangle(&rect, resources.popup_brush.as_raw());
+        rt.DrawTextLayout(
+            D2D1_POINT_2F { x: left + MARGIN, y: top + MARGIN },
+            layout.raw.as_raw(),
+            resources.brush.as_raw(),
+            D2D1_DRAW_TEXT_OPTIONS_NONE,
+        );
     }
 }
 
 fn load_document(app_state: &mut Token<AppState>, path: PathBuf) {
+    let size = std::fs::metadata(&path).map_or(0, |m| m.len());
+    // binary files are read at once, unless they are too large for that
+    let large = size >= app_state.borrow_mut().config.large_file_mb << 20;
+    if size >= loader::BACKGROUND_THRESHOLD && (large || !looks_binary(&path)) {
+        start_loading(app_state, path, size);
+        return;
+    }
     match std::fs::read(&path) {
         Ok(data) => {
-            let mut content = String::from_utf8_lossy(&data);
-            let utf8_loss = match content {
-                std::borrow::Cow::Borrowed(_) => false,
-                std::borrow::Cow::Owned(_) => true,
+            let settings = editorconfig::settings_for(&path);
+            if hex::is_binary(&data[..data.len().min(hex::SAMPLE_SIZE)], settings.charset) {
+                start_hex(&mut app_state.borrow_mut(), path, settings, data);
+                return;
+            }
+            let (mut content, utf8_loss) = editorconfig::decode(&data, settings.charset);
+            let had_cr = content.contains('\r');
+            if had_cr {
+                content = content.replace("\r\n", "\n").replace('\r', "\n");
+            }
+            let mut app_state = app_state.borrow_mut();
+            app_state.view_state.load(&content, false);
+            app_state.file_len = data.len() as u64;
+            start_document(&mut app_state, path, settings, &content);
+            document_loaded(&mut app_state, utf8_loss, had_cr);
+        }
+        Err(e) => open_failed(app_state, &path, e),
+    }
+}
+
+fn open_failed(app_state: &mut Token<AppState>, path: &Path, e: Error) {
+    let msg = format!("Can't open {}.\n{}", path.to_string_lossy(), e);
+    message_box(
+        app_state,
+        "an editor - error",
+        &msg,
+        MB_OK | MB_ICONERROR);
+}
+
+fn looks_binary(path: &Path) -> bool {
+    let mut sample = Vec::new();
+    let res = std::fs::File::open(path)
+        .and_then(|f| f.take(hex::SAMPLE_SIZE as u64).read_to_end(&mut sample));
+    res.is_ok() && hex::is_binary(&sample, editorconfig::settings_for(path).charset)
+}
+
+// Instead of the text mangled by the conversion.
+fn start_hex(a: &mut AppState, path: PathBuf, settings: editorconfig::Settings, data: Vec<u8>) {
+    a.view_state.load("", false);
+    a.file_len = data.len() as u64;
+    start_document(a, path, settings, "");
+    stop_following(a);
+    a.hex = Some(HexDocument::new(data));
+    a.lsp = None;
+    a.view_state.track_changes(false);
+    show_build_messages(a);
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+// Between hex and text. The text is converted with the file's encoding.
+fn toggle_hex(a: &mut AppState) {
+    match a.hex.take() {
+        Some(hex) => {
+            let (mut content, utf8_loss) = editorconfig::decode(hex.data(), a.editorconfig.charset);
+            let had_cr = content.contains('\r');
+            if had_cr {
+                content = content.replace("\r\n", "\n").replace('\r', "\n");
+            }
+            a.view_state.load(&content, hex.modified());
+            document_loaded(a, utf8_loss, had_cr);
+        }
+        None => {
+            let unmodified_file = a.filename.as_ref()
+                .filter(|_| !a.view_state.modified())
+                .and_then(|path| std::fs::read(path).ok());
+            let mut hex = match unmodified_file {
+                Some(data) => HexDocument::new(data),
+                None => {
+                    let content = a.view_state.content();
+                    if let Some(c) = editorconfig::unencodable_char(&content, a.editorconfig.charset) {
+                        let msg = format!(
+                            "The document has characters that {} can't encode, like {:?} (U+{:04X}).",
+                            a.editorconfig.charset.unwrap().name(), c, c as u32);
+                        a.add_flash(&msg);
+                        return;
+                    }
+                    let mut hex = HexDocument::new(editorconfig::encode(&content, &a.editorconfig));
+                    hex.mark_modified();
+                    hex
+                }
             };
-            let crlf_fix = if content.contains('\r') {
-                content = content.replace('\r', "").into();
-                true
-            } else {
-                false
+            hex.move_to(0);
+            stop_following(a);
+            a.view_state.load("", false);
+            a.completion = None;
+            a.hex = Some(hex);
+            a.lsp = None;
+            a.view_state.track_changes(false);
+        }
+    }
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+// Settings for the newly loaded document, text is what is loaded so far.
+fn start_document(a: &mut AppState, path: PathBuf, settings: editorconfig::Settings, text: &str) {
+    stop_loading(a);
+    close_mapped(a);
+    a.hex = None;
+    // reloading a followed file keeps following it
+    if a.filename.as_ref() != Some(&path) {
+        stop_following(a);
+    }
+    a.read_only = false;
+    a.filename = Some(path);
+    let detected = indent::detect(text).unwrap_or(a.config.indent);
+    a.view_state.indent_style = settings.indent(detected);
+    a.view_state.set_tab_width(settings.tab_width);
+    a.editorconfig = settings;
+    update_syntax(a);
+    a.completion = None;
+    a.hover = None;
+    a.update_title();
+}
+
+// When the whole file is in, or as much of it as there is going to be.
+fn document_loaded(a: &mut AppState, utf8_loss: bool, had_cr: bool) {
+    // line breaks are converted back on save if they are expected
+    let crlf_expected = matches!(
+        a.editorconfig.end_of_line, Some(editorconfig::EndOfLine::CrLf) | Some(editorconfig::EndOfLine::Cr));
+    let crlf_fix = had_cr && !crlf_expected;
+    if a.read_only {
+        // not worth sending to the language server
+        a.lsp = None;
+        a.view_state.track_changes(false);
+    } else {
+        if utf8_loss || crlf_fix {
+            a.view_state.mark_modified();
+        }
+        open_in_language_server(a);
+    }
+    show_build_messages(a);
+    if a.follow.is_some() {
+        start_following(a);
+    }
+    a.update_title();
+
+    if utf8_loss || crlf_fix {
+        let mut messages = Vec::new();
+        if utf8_loss {
+            messages.push("File is not valid in its encoding, problematic parts were replaced with '�'.");
+        }
+        if crlf_fix {
+            messages.push("CRLF line breaks were converted to LF.");
+        }
+        a.add_flash(&messages.join("\n"));
+    }
+}
+
+const LOADING_TIMER: usize = 1;
+const LOADING_POLL_MS: u32 = 10;
+// per timer tick, so that the window stays responsive
+const LOADING_SLICE: Duration = Duration::from_millis(30);
+
+// Large files are read in the background and viewable as they come.
+// Past the configured size they are viewed read only from a memory mapping.
+fn start_loading(app_state: &mut Token<AppState>, path: PathBuf, size: u64) {
+    let large = size >= app_state.borrow_mut().config.large_file_mb << 20;
+    let settings = editorconfig::settings_for(&path);
+    let charset = settings.charset;
+    let file = std::fs::File::open(&path);
+    if large {
+        match file.and_then(|f| FileMapping::new(&f, size as usize)) {
+            Ok(mapping) => start_mapped(&mut app_state.borrow_mut(), path, settings, mapping),
+            Err(e) => open_failed(app_state, &path, e),
+        }
+        return;
+    }
+    match file.map(|f| Loader::start(f, size, charset)) {
+        Ok(loader) => {
+            let mut g = app_state.borrow_mut();
+            let a = &mut *g;
+            a.view_state.load("", false);
+            // not to be sent to the language server before it's all in
+            a.view_state.track_changes(false);
+            start_document(a, path, settings, "");
+            a.loading = Some(loader);
+            set_timer(a.hwnd, LOADING_TIMER, LOADING_POLL_MS);
+            a.update_title();
+        }
+        Err(e) => open_failed(app_state, &path, e),
+    }
+}
+
+fn continue_loading(a: &mut AppState) {
+    let start = Instant::now();
+    while start.elapsed() < LOADING_SLICE {
+        let loader = match &mut a.loading {
+            Some(loader) => loader,
+            None => return,
+        };
+        let first = loader.loaded == 0;
+        let progress = loader.try_recv();
+        a.file_len = loader.loaded;
+        match progress {
+            None => break,
+            Some(Progress::Text(text, _)) => {
+                a.view_state.append_loaded(&text);
+                if first {
+                    let detected = indent::detect(&text).unwrap_or(a.config.indent);
+                    a.view_state.indent_style = a.editorconfig.indent(detected);
+                }
+            }
+            Some(Progress::Done { utf8_loss, crlf }) => {
+                stop_loading(a);
+                document_loaded(a, utf8_loss, crlf);
+                break;
+            }
+            Some(Progress::Failed(e)) => {
+                let path = a.filename.as_ref().unwrap().to_string_lossy().into_owned();
+                a.add_flash(&format!("Can't read all of {}.\n{}", path, e));
+                cancel_loading(a);
+                break;
+            }
+        }
+    }
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+fn stop_loading(a: &mut AppState) {
+    if a.loading.take().is_some() {
+        kill_timer(a.hwnd, LOADING_TIMER);
+    }
+}
+
+const INDEX_TIMER: usize = 3;
+// per timer tick, kept even for UTF-16
+const INDEX_CHUNK: usize = 1 << 20;
+
+// The file isn't decoded, the lines are found where they are needed.
+fn start_mapped(a: &mut AppState, path: PathBuf, settings: editorconfig::Settings, mapping: FileMapping) {
+    a.view_state.load("", false);
+    a.file_len = mapping.as_ref().len() as u64;
+    let charset = settings.charset;
+    start_document(a, path, settings, "");
+    stop_following(a);
+    let mut doc = MappedDocument::new(mapping, charset);
+    // enough for the first screen
+    doc.index_more(INDEX_CHUNK);
+    a.mapped = Some(doc);
+    a.read_only = true;
+    a.lsp = None;
+    a.view_state.track_changes(false);
+    set_timer(a.hwnd, INDEX_TIMER, LOADING_POLL_MS);
+    show_build_messages(a);
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+// More of the file becomes reachable as it's indexed.
+fn continue_indexing(a: &mut AppState) {
+    let start = Instant::now();
+    let doc = match &mut a.mapped {
+        Some(doc) => doc,
+        None => return,
+    };
+    while start.elapsed() < LOADING_SLICE {
+        if doc.index_more(INDEX_CHUNK) {
+            kill_timer(a.hwnd, INDEX_TIMER);
+            break;
+        }
+    }
+    invalidate_rect(a.hwnd);
+}
+
+fn close_mapped(a: &mut AppState) {
+    if a.mapped.take().is_some() {
+        kill_timer(a.hwnd, INDEX_TIMER);
+    }
+}
+
+const FOLLOW_TIMER: usize = 2;
+const FOLLOW_POLL_MS: u32 = 500;
+
+// From the end of the document, which is scrolled into view.
+fn start_following(a: &mut AppState) {
+    let path = a.filename.clone().unwrap();
+    match Follower::new(path, a.file_len, a.editorconfig.charset) {
+        Ok(follower) => {
+            a.follow = Some(follower);
+            set_timer(a.hwnd, FOLLOW_TIMER, FOLLOW_POLL_MS);
+            a.view_state.move_cursor(Motion::CtrlEnd);
+            a.view_state.clear_selection();
+            a.view_state.scroll_to_end();
+        }
+        Err(e) => {
+            stop_following(a);
+            a.add_flash(&format!("Can't follow the file.\n{}", e));
+        }
+    }
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+fn stop_following(a: &mut AppState) {
+    if a.follow.take().is_some() {
+        kill_timer(a.hwnd, FOLLOW_TIMER);
+        a.update_title();
+    }
+}
+
+// Appends what was added to the file, reloads it when it was truncated
+// or replaced.
+fn poll_followed_file(app_state: &mut Token<AppState>) {
+    let path = {
+        let mut g = app_state.borrow_mut();
+        let a = &mut *g;
+        if a.loading.is_some() {
+            return;
+        }
+        let follower = match &mut a.follow {
+            Some(follower) => follower,
+            None => return,
+        };
+        match follower.poll() {
+            Ok(Change::None) => return,
+            Ok(Change::Appended(text)) => {
+                a.file_len = follower.offset();
+                a.view_state.append_followed(&text);
+                invalidate_rect(a.hwnd);
+                return;
+            }
+            Ok(Change::Replaced) if !a.modified() => a.filename.clone().unwrap(),
+            Ok(Change::Replaced) => {
+                stop_following(a);
+                a.add_flash("The file was truncated or replaced, and there are unsaved changes.\nNot following it anymore.");
+                invalidate_rect(a.hwnd);
+                return;
+            }
+            Err(e) => {
+                stop_following(a);
+                a.add_flash(&format!("Can't follow the file.\n{}", e));
+                invalidate_rect(a.hwnd);
+                return;
+            }
+        }
+    };
+    info!("reloading followed file {:?}", path);
+    load_document(app_state, path);
+}
+
+// What's loaded stays, but saving it would cut the file.
+fn cancel_loading(a: &mut AppState) {
+    if a.loading.is_some() {
+        stop_loading(a);
+        a.read_only = true;
+        document_loaded(a, false, false);
+    }
+}
+
+// For bracket matching, by the file extension.
+fn update_syntax(a: &mut AppState) {
+    let ext = a.filename.as_ref()
+        .and_then(|p| p.extension())
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    a.view_state.set_syntax(brackets::syntax_for(&ext));
+}
+
+// Posted by the lsp reader thread.
+const WM_LSP: UINT = WM_APP + 1;
+
+// Starts, restarts or stops the language server according to the current
+// file name, and tells it about the document.
+fn open_in_language_server(a: &mut AppState) {
+    let command = a.filename.as_ref().and_then(|p| a.config.language_server_for(p));
+    if a.lsp.is_some() && command.as_ref() != Some(&a.lsp_command) {
+        info!("stopping language server {:?}", a.lsp_command);
+        a.lsp = None;
+    }
+    a.view_state.set_diagnostics(DiagnosticSource::Lsp, Vec::new());
+    if let (Some(path), Some(command)) = (&a.filename, command) {
+        if a.lsp.is_none() {
+            info!("starting language server {:?}", command);
+            let hwnd = a.hwnd as usize;
+            let notify = move || unsafe {
+                PostMessageW(hwnd as HWND, WM_LSP, 0, 0);
             };
-            let mut app_state = app_state.borrow_mut();
-            app_state.filename = Some(path);
-            app_state.view_state.load(&content, utf8_loss || crlf_fix);
-            app_state.update_title();
-
-            if utf8_loss || crlf_fix {
-                let mut messages = Vec::new();
-                if utf8_loss {
-                    messages.push("File is not valid UTF-8, problematic parts were replaced with '�'.");
+            let res = lsp::parse_command_line(&command)
+                .ok_or_else(|| "empty command".to_owned())
+                .and_then(|c| lsp::Client::start(c, &lsp::find_root(path), notify).map_err(|e| e.to_string()));
+            match res {
+                Ok(client) => a.lsp = Some(client),
+                Err(e) => a.add_flash(&format!("Can't start language server {:?}.\n{}", command, e)),
+            }
+            a.lsp_command = command;
+        }
+    }
+    match (&mut a.lsp, &a.filename) {
+        (Some(client), Some(path)) => client.open(path),
+        (Some(client), None) => client.close(),
+        _ => {}
+    }
+    a.view_state.track_changes(a.lsp.is_some());
+}
+
+// Sends pending edits to the language server.
+fn sync_language_server(a: &mut AppState) {
+    if let Some(client) = &mut a.lsp {
+        let changes = a.view_state.take_changes();
+        let view_state = &a.view_state;
+        client.sync(&changes, || view_state.content());
+    }
+}
+
+fn handle_lsp_events(app_state: &mut Token<AppState>) {
+    let events = {
+        let mut g = app_state.borrow_mut();
+        let a = &mut *g;
+        let events = match &mut a.lsp {
+            Some(client) => client.poll(),
+            None => return,
+        };
+        sync_language_server(a);
+        events
+    };
+    for event in events {
+        match event {
+            lsp::Event::Diagnostics { uri, diagnostics } => {
+                let mut g = app_state.borrow_mut();
+                let a = &mut *g;
+                if a.lsp.as_ref().map(|c| c.is_current(&uri)) == Some(true) {
+                    let diagnostics = diagnostics.into_iter().enumerate().map(|(id, d)| Diagnostic {
+                        start: a.view_state.pos_from_lsp(d.start),
+                        end: a.view_state.pos_from_lsp(d.end),
+                        severity: d.severity,
+                        message: d.message,
+                        source: DiagnosticSource::Lsp,
+                        id,
+                    }).collect();
+                    a.view_state.set_diagnostics(DiagnosticSource::Lsp, diagnostics);
+                    invalidate_rect(a.hwnd);
                 }
-                if crlf_fix {
-                    messages.push("CRLF line breaks were converted to LF.");
+            }
+            lsp::Event::Hover(text) => {
+                let mut a = app_state.borrow_mut();
+                let mut text = text;
+                for d in a.view_state.diagnostics_at(a.view_state.cursor_pos()) {
+                    text = format!("{:?}: {}\n\n{}", d.severity, d.message, text);
                 }
-                assert!(app_state.flash.is_none());
-                app_state.flash = Some(messages.join("\n"));
+                show_hover(&mut a, text.trim());
+            }
+            lsp::Event::Definition(Some((uri, position))) => go_to_location(app_state, &uri, position),
+            lsp::Event::Definition(None) => {
+                message_box(app_state, "an editor", "Definition not found.", MB_OK | MB_ICONINFORMATION);
+            }
+            lsp::Event::Completion(items) => show_completions(&mut app_state.borrow_mut(), items),
+            lsp::Event::Exited => {
+                let mut a = app_state.borrow_mut();
+                a.lsp = None;
+                a.view_state.track_changes(false);
+                a.add_flash("Language server exited.");
+                invalidate_rect(a.hwnd);
+            }
+        }
+    }
+}
+
+fn show_hover(a: &mut AppState, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    a.hover = Some((a.view_state.cursor_pos(), text.to_owned()));
+    invalidate_rect(a.hwnd);
+}
+
+fn go_to_location(app_state: &mut Token<AppState>, uri: &str, position: lsp::Position) {
+    let current = app_state.borrow_mut().lsp.as_ref().map(|c| c.is_current(uri)) == Some(true);
+    if !current {
+        let path = match lsp::uri_to_path(uri) {
+            Some(path) => path,
+            None => return,
+        };
+        let modified = app_state.borrow_mut().modified();
+        if modified && !prompt_about_unsaved_changes(app_state) {
+            return;
+        }
+        load_document(app_state, path);
+    }
+    let mut g = app_state.borrow_mut();
+    let a = &mut *g;
+    a.last_action = ActionType::Other;
+    let pos = a.view_state.pos_from_lsp(position);
+    a.view_state.set_selection(pos, pos);
+    invalidate_rect(a.hwnd);
+}
+
+fn show_completions(a: &mut AppState, items: Vec<lsp::CompletionItem>) {
+    let cursor = a.view_state.cursor_pos();
+    let start = a.view_state.word_start(cursor);
+    if a.pending_completion.take() != Some(start) {
+        return;
+    }
+    let prefix = a.view_state.slice_string(start, cursor);
+    a.completion = completion::Popup::new(start, items, &prefix);
+    invalidate_rect(a.hwnd);
+}
+
+fn show_word_completions(a: &mut AppState) {
+    let cursor = a.view_state.cursor_pos();
+    let start = a.view_state.word_start(cursor);
+    let prefix = a.view_state.slice_string(start, cursor);
+    let words = a.view_state.word_completions();
+    a.completion = completion::Popup::from_words(start, words, &prefix);
+    invalidate_rect(a.hwnd);
+}
+
+// From the language server if there is one, the reply comes later.
+fn request_completion(a: &mut AppState) {
+    sync_language_server(a);
+    let pos = a.view_state.lsp_position(a.view_state.cursor_pos());
+    let sent = match &mut a.lsp {
+        Some(client) => client.completion(pos),
+        None => false,
+    };
+    if sent {
+        a.pending_completion = Some(a.view_state.word_start(a.view_state.cursor_pos()));
+    } else {
+        show_word_completions(a);
+    }
+}
+
+// Narrows down the popup to what's typed so far,
+// or closes it when the cursor leaves the word.
+fn update_completion(a: &mut AppState) {
+    let popup = match &mut a.completion {
+        Some(popup) => popup,
+        None => return,
+    };
+    let cursor = a.view_state.cursor_pos();
+    let keep = popup.start <= cursor
+        && a.view_state.word_start(cursor) == popup.start
+        && !a.view_state.has_selection()
+        && popup.filter(&a.view_state.slice_string(popup.start, cursor));
+    if !keep {
+        a.completion = None;
+    }
+    invalidate_rect(a.hwnd);
+}
+
+fn accept_completion(a: &mut AppState) {
+    let popup = a.completion.take().unwrap();
+    let text = popup.selected_item().insert_text.clone();
+    let cursor = a.view_state.cursor_pos();
+    a.last_action = ActionType::Other;
+    a.mark_active = false;
+    for _ in popup.start..cursor {
+        a.record(MacroCmd::Backspace);
+    }
+    a.record(MacroCmd::Paste(text.clone()));
+    a.view_state.make_undo_snapshot();
+    a.view_state.replace_text(popup.start, cursor, &text);
+    let end = popup.start + text.chars().count();
+    a.view_state.set_selection(end, end);
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+// Popups and snippets follow what's typed.
+fn after_key(a: &mut AppState) {
+    a.view_state.after_input();
+    update_completion(a);
+}
+
+// Tab moves between snippet tab stops, expands the snippet whose trigger
+// is before the cursor, indents the selected lines or inserts indentation.
+// Shift-Tab goes back or outdents.
+fn handle_tab_key(a: &mut AppState, k: &KeyEvent) -> bool {
+    if k.key_code != VK_TAB || k.ctrl_pressed || k.alt_pressed {
+        return false;
+    }
+    a.swallow_char = true;
+    if !a.check_writable() {
+        return true;
+    }
+    invalidate_rect(a.hwnd);
+    if a.view_state.next_tab_stop(!k.shift_pressed) {
+        a.last_action = ActionType::Other;
+        return true;
+    }
+    let selection = a.view_state.has_selection();
+    if k.shift_pressed || selection || !expand_snippet(a) {
+        a.last_action = ActionType::Other;
+        a.mark_active = false;
+        a.view_state.make_undo_snapshot();
+        if k.shift_pressed || selection {
+            a.view_state.indent_lines(!k.shift_pressed);
+        } else {
+            let text = a.view_state.insert_indent();
+            a.record(MacroCmd::Paste(text));
+        }
+        a.update_title();
+    }
+    true
+}
+
+// Returns false if there is no snippet for the word before the cursor.
+fn expand_snippet(a: &mut AppState) -> bool {
+    let ext = match a.filename.as_ref().and_then(|p| p.extension()) {
+        Some(ext) => ext.to_string_lossy().to_lowercase(),
+        None => return false,
+    };
+    let cursor = a.view_state.cursor_pos();
+    let start = a.view_state.word_start(cursor);
+    let trigger = a.view_state.slice_string(start, cursor);
+    let body = match a.snippets.get(&ext).and_then(|s| s.iter().find(|s| s.trigger == trigger)) {
+        Some(s) => &s.body,
+        None => return false,
+    };
+    match snippet::expand(body, &a.view_state.line_indent(cursor)) {
+        Ok(e) => {
+            a.last_action = ActionType::Other;
+            a.mark_active = false;
+            a.view_state.make_undo_snapshot();
+            a.view_state.insert_snippet(start, cursor, e);
+            a.update_title();
+        }
+        Err(e) => a.add_flash(&format!("Snippet {:?}: {}", trigger, e)),
+    }
+    true
+}
+
+// Keys that go to the popup while it's open.
+fn handle_completion_key(a: &mut AppState, k: &KeyEvent) -> bool {
+    let popup = match &mut a.completion {
+        Some(popup) => popup,
+        None => return false,
+    };
+    if k.ctrl_pressed || k.alt_pressed {
+        return false;
+    }
+    let page = completion::MAX_VISIBLE_ITEMS as isize;
+    match k.key_code {
+        VK_UP => popup.move_selection(-1),
+        VK_DOWN => popup.move_selection(1),
+        VK_PRIOR => popup.move_selection(-page),
+        VK_NEXT => popup.move_selection(page),
+        VK_ESCAPE => a.completion = None,
+        VK_RETURN | VK_TAB => {
+            accept_completion(a);
+            a.swallow_char = true;
+        }
+        _ => return false,
+    }
+    invalidate_rect(a.hwnd);
+    true
+}
+
+const WM_BUILD_DONE: UINT = WM_APP + 2;
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Turns build messages for the current file into diagnostics,
+// so that they follow the edits from now on.
+fn show_build_messages(a: &mut AppState) {
+    let mut diagnostics = Vec::new();
+    if let Some(path) = &a.filename {
+        for (id, m) in a.build_messages.iter().enumerate() {
+            if same_file(&m.path, path) {
+                diagnostics.push(Diagnostic {
+                    start: a.view_state.line_col_to_pos(m.line, m.column),
+                    end: a.view_state.line_col_to_pos(m.end_line, m.end_column),
+                    severity: m.severity,
+                    message: m.message.clone(),
+                    source: DiagnosticSource::Build,
+                    id,
+                });
             }
         }
+    }
+    a.view_state.set_diagnostics(DiagnosticSource::Build, diagnostics);
+    invalidate_rect(a.hwnd);
+}
+
+fn start_build(app_state: &mut Token<AppState>) {
+    if app_state.borrow_mut().build.is_some() {
+        return;
+    }
+    // build what's on the screen
+    let (path, modified) = {
+        let a = app_state.borrow_mut();
+        (a.filename.clone(), a.modified())
+    };
+    if let (Some(path), true) = (&path, modified) {
+        if !save_document(app_state, path.clone()) {
+            return;
+        }
+    }
+    let mut g = app_state.borrow_mut();
+    let a = &mut *g;
+    let root = match &path {
+        Some(path) => lsp::find_root(path),
+        None => std::env::current_dir().unwrap_or_default(),
+    };
+    let command = a.config.build_command.clone();
+    info!("building {:?} in {}", command, root.to_string_lossy());
+    let (tx, rx) = channel();
+    let hwnd = a.hwnd as usize;
+    std::thread::spawn(move || {
+        let _ = tx.send(compiler_output::run(&command, &root));
+        unsafe {
+            PostMessageW(hwnd as HWND, WM_BUILD_DONE, 0, 0);
+        }
+    });
+    a.build = Some(rx);
+    a.build_status = Some("building...".to_owned());
+    a.update_title();
+}
+
+fn finish_build(a: &mut AppState) {
+    let res = match a.build.take() {
+        Some(rx) => rx.recv().unwrap(),
+        None => return,
+    };
+    match res {
+        Ok(res) => {
+            let count = |severity| res.messages.iter().filter(|m| m.severity == severity).count();
+            let errors = count(lsp::Severity::Error);
+            let warnings = count(lsp::Severity::Warning);
+            a.build_status = Some(if errors + warnings > 0 {
+                format!("{} errors, {} warnings", errors, warnings)
+            } else if res.success {
+                "build ok".to_owned()
+            } else {
+                "build failed".to_owned()
+            });
+            a.build_messages = res.messages;
+        }
         Err(e) => {
-            let msg = format!("Can't open {}.\n{}", path.to_string_lossy(), e);
-            message_box(
-                app_state,
-                "an editor - error",
-                &msg,
-                MB_OK | MB_ICONERROR);
+            a.build_status = None;
+            a.build_messages.clear();
+            a.add_flash(&e);
+        }
+    }
+    a.current_build_message = None;
+    show_build_messages(a);
+    a.update_title();
+}
+
+// The position comes from the marker if the file is open,
+// so it's still right after the edits.
+fn go_to_build_message(app_state: &mut Token<AppState>, idx: usize) {
+    let (path, current) = {
+        let a = app_state.borrow_mut();
+        let path = a.build_messages[idx].path.clone();
+        let current = a.filename.as_ref().map(|f| same_file(f, &path)) == Some(true);
+        (path, current)
+    };
+    if !current {
+        let modified = app_state.borrow_mut().modified();
+        if modified && !prompt_about_unsaved_changes(app_state) {
+            return;
         }
+        load_document(app_state, path);
+    }
+    let mut g = app_state.borrow_mut();
+    let a = &mut *g;
+    a.last_action = ActionType::Other;
+    a.current_build_message = Some(idx);
+    if let Some(d) = a.view_state.find_diagnostic(DiagnosticSource::Build, idx) {
+        let (start, end) = (d.start, d.end);
+        a.view_state.set_selection(end, start);
     }
+    invalidate_rect(a.hwnd);
+}
+
+// Runs the formatter configured for the path and applies its output
+// as a diff, so that the cursor and the scroll position stay in place.
+// It's one undo step. Errors go to flash.
+fn format_document(a: &mut AppState, path: &Path) {
+    let command = match a.config.formatter_for(path) {
+        Some(command) => command,
+        None => return,
+    };
+    a.last_action = ActionType::Other;
+    let content = a.view_state.content();
+    match filter::run_filter(&command, &content, a.config.filter_timeout) {
+        Ok(output) => {
+            if !output.stderr.is_empty() {
+                a.add_flash(output.stderr.trim_end());
+            }
+            // probably a formatter that works in place, don't wipe the document
+            if output.stdout.is_empty() && !content.is_empty() {
+                a.add_flash(&format!("{:?} produced no output.", command));
+                return;
+            }
+            let edits = diff::diff(&content, &output.stdout);
+            info!("formatter made {} edits", edits.len());
+            if !edits.is_empty() {
+                a.view_state.make_undo_snapshot();
+                a.view_state.apply_edits(&edits);
+            }
+        }
+        Err(e) => a.add_flash(&e),
+    }
+    invalidate_rect(a.hwnd);
 }
 
 fn save_document(app_state: &mut Token<AppState>, path: PathBuf) -> bool {
     let mut g = app_state.borrow_mut();
-    let content: String = g.view_state.content();
-    match std::fs::write(&path, content) {
+    let renamed = g.filename.as_ref() != Some(&path);
+    if renamed {
+        g.editorconfig = editorconfig::settings_for(&path);
+    }
+    // Whitespace fixes are made to the document only once it's saved.
+    let mut whitespace_edits = Vec::new();
+    let data = match g.hex.as_ref().map(|hex| hex.data().to_vec()) {
+        // exactly the bytes
+        Some(data) => data,
+        None => {
+            if g.config.format_on_save {
+                format_document(&mut g, &path);
+            }
+            let content = g.view_state.content();
+            whitespace_edits = editorconfig::whitespace_edits(&content, &g.editorconfig);
+            let content = diff::apply(&content, &whitespace_edits);
+            if let Some(c) = editorconfig::unencodable_char(&content, g.editorconfig.charset) {
+                let msg = format!(
+                    "The document has characters that {} can't encode, like {:?} (U+{:04X}).\n\
+                    They will be saved as \"?\". Save anyway?",
+                    g.editorconfig.charset.unwrap().name(), c, c as u32);
+                drop(g);
+                if message_box(app_state, "an editor", &msg, MB_OKCANCEL | MB_ICONWARNING) != IDOK {
+                    return false;
+                }
+                g = app_state.borrow_mut();
+            }
+            editorconfig::encode(&content, &g.editorconfig)
+        }
+    };
+    let len = data.len() as u64;
+    match std::fs::write(&path, data) {
         Ok(()) => {
+            if !whitespace_edits.is_empty() {
+                g.last_action = ActionType::Other;
+                g.view_state.make_undo_snapshot();
+                g.view_state.apply_edits(&whitespace_edits);
+                invalidate_rect(g.hwnd);
+            }
+            if renamed {
+                stop_following(&mut g);
+            }
+            g.file_len = len;
+            // the file has the document now, it's followed from its end
+            if g.follow.is_some() {
+                match Follower::new(path.clone(), len, g.editorconfig.charset) {
+                    Ok(follower) => g.follow = Some(follower),
+                    Err(e) => {
+                        stop_following(&mut g);
+                        g.add_flash(&format!("Can't follow the file.\n{}", e));
+                    }
+                }
+            }
             g.filename = Some(path);
             g.view_state.set_unmodified_snapshot();
+            if let Some(hex) = &mut g.hex {
+                hex.set_unmodified();
+            }
             g.update_title();
+            if renamed && g.hex.is_none() {
+                update_syntax(&mut g);
+                open_in_language_server(&mut g);
+            }
             true
         },
         Err(e) => {
@@ -365,139 +1797,454 @@ fn prompt_about_unsaved_changes(app_state: &mut Token<AppState>) -> bool {
     false
 }
 
-fn init_key_bindings() -> Vec<(KeyMatcher, Idm)> {
+fn init_key_bindings(keymap: Keymap) -> Vec<(Vec<KeyMatcher>, Action)> {
     use key_util::{CTRL, SHIFT, ALT};
     let vk = |key_code| KeyMatcher::from_key_code(key_code);
     let ch_scan = |c| KeyMatcher::from_char_to_scan_code(c);
-    vec![
-        (SHIFT + vk(VK_DELETE), Idm::Cut),
-        (CTRL + vk(VK_INSERT), Idm::Copy),
-        (SHIFT + vk(VK_INSERT), Idm::Paste),
-        (CTRL + ch_scan('X'), Idm::Cut),
-        (CTRL + ch_scan('C'), Idm::Copy),
-        (CTRL + ch_scan('V'), Idm::Paste),
-
-        (CTRL + vk(VK_OEM_MINUS), Idm::SmallerFont),
-        (CTRL + vk(VK_OEM_PLUS), Idm::LargerFont),
-        (CTRL + vk(VK_SUBTRACT), Idm::SmallerFont),
-        (CTRL + vk(VK_ADD), Idm::LargerFont),
-
-        (CTRL + ch_scan('Z'), Idm::Undo),
-        (CTRL + ch_scan('Y'), Idm::Redo),
-
-        (CTRL + ch_scan('A'), Idm::SelectAll),
-        (CTRL + ch_scan('N'), Idm::New),
-        (CTRL + ch_scan('O'), Idm::Open),
-        (CTRL + ch_scan('S'), Idm::Save),
-        (CTRL + (SHIFT + ch_scan('S')), Idm::SaveAs),
+    let cmd = Action::Command;
+    let font_bindings = vec![
+        (vec![CTRL + vk(VK_OEM_MINUS)], cmd(Idm::SmallerFont)),
+        (vec![CTRL + vk(VK_OEM_PLUS)], cmd(Idm::LargerFont)),
+        (vec![CTRL + vk(VK_SUBTRACT)], cmd(Idm::SmallerFont)),
+        (vec![CTRL + vk(VK_ADD)], cmd(Idm::LargerFont)),
+    ];
+    let mut bindings = match keymap {
+        Keymap::Default => vec![
+            (vec![SHIFT + vk(VK_DELETE)], cmd(Idm::Cut)),
+            (vec![CTRL + vk(VK_INSERT)], cmd(Idm::Copy)),
+            (vec![SHIFT + vk(VK_INSERT)], cmd(Idm::Paste)),
+            (vec![CTRL + ch_scan('X')], cmd(Idm::Cut)),
+            (vec![CTRL + ch_scan('C')], cmd(Idm::Copy)),
+            (vec![CTRL + ch_scan('V')], cmd(Idm::Paste)),
+
+            (vec![CTRL + ch_scan('Z')], cmd(Idm::Undo)),
+            (vec![CTRL + ch_scan('Y')], cmd(Idm::Redo)),
+
+            (vec![CTRL + ch_scan('A')], cmd(Idm::SelectAll)),
+            (vec![CTRL + ch_scan('F')], cmd(Idm::Find)),
+            (vec![vk(VK_F3)], cmd(Idm::FindNext)),
+            (vec![CTRL + (SHIFT + vk(VK_OEM_5))], cmd(Idm::FilterSelection)),
+            (vec![CTRL + (SHIFT + ch_scan('I'))], cmd(Idm::FormatDocument)),
+            (vec![CTRL + ch_scan('G')], cmd(Idm::GoToLine)),
+            (vec![CTRL + vk(VK_OEM_6)], cmd(Idm::GoToMatchingBracket)),
+            (vec![CTRL + (SHIFT + vk(VK_OEM_MINUS))], cmd(Idm::Fold)),
+            (vec![CTRL + (SHIFT + vk(VK_OEM_PLUS))], cmd(Idm::Unfold)),
+            (vec![CTRL + (SHIFT + vk(VK_OEM_6))], cmd(Idm::SelectToMatchingBracket)),
+            (vec![ALT + ch_scan('Z')], cmd(Idm::ToggleWrap)),
+            (vec![CTRL + (SHIFT + ch_scan('F'))], cmd(Idm::ToggleFollow)),
+            (vec![CTRL + (SHIFT + ch_scan('H'))], cmd(Idm::ToggleHex)),
+            (vec![CTRL + ch_scan('K')], cmd(Idm::Hover)),
+            (vec![vk(VK_F12)], cmd(Idm::GoToDefinition)),
+            (vec![CTRL + vk(VK_SPACE)], cmd(Idm::Complete)),
+            (vec![vk(VK_F7)], cmd(Idm::Build)),
+            (vec![vk(VK_F8)], cmd(Idm::NextError)),
+            (vec![SHIFT + vk(VK_F8)], cmd(Idm::PrevError)),
+            (vec![CTRL + (SHIFT + ch_scan('R'))], cmd(Idm::ToggleMacroRecording)),
+            (vec![CTRL + (SHIFT + ch_scan('P'))], cmd(Idm::PlayMacro)),
+            (vec![CTRL + ch_scan('N')], cmd(Idm::New)),
+            (vec![CTRL + ch_scan('O')], cmd(Idm::Open)),
+            (vec![CTRL + ch_scan('S')], cmd(Idm::Save)),
+            (vec![CTRL + (SHIFT + ch_scan('S'))], cmd(Idm::SaveAs)),
+
+            (vec![ALT + ch_scan('Q')], cmd(Idm::Exit)),
+            (vec![vk(VK_ESCAPE)], cmd(Idm::StopLoading)),
+        ],
+        Keymap::Emacs => {
+            let c_x = CTRL + ch_scan('X');
+            vec![
+                (vec![CTRL + ch_scan('F')], Action::Motion(Motion::Right)),
+                (vec![CTRL + ch_scan('B')], Action::Motion(Motion::Left)),
+                (vec![CTRL + ch_scan('N')], Action::Motion(Motion::Down)),
+                (vec![CTRL + ch_scan('P')], Action::Motion(Motion::Up)),
+                (vec![CTRL + ch_scan('A')], Action::Motion(Motion::Home)),
+                (vec![CTRL + ch_scan('E')], Action::Motion(Motion::End)),
+                (vec![ALT + ch_scan('F')], Action::Motion(Motion::CtrlRight)),
+                (vec![ALT + ch_scan('B')], Action::Motion(Motion::CtrlLeft)),
+                (vec![CTRL + ch_scan('V')], Action::Motion(Motion::PgDown)),
+                (vec![ALT + ch_scan('V')], Action::Motion(Motion::PgUp)),
+                // M-< and M->, assuming US layout
+                (vec![ALT + (SHIFT + vk(VK_OEM_COMMA))], Action::Motion(Motion::CtrlHome)),
+                (vec![ALT + (SHIFT + vk(VK_OEM_PERIOD))], Action::Motion(Motion::CtrlEnd)),
+
+                (vec![CTRL + vk(VK_SPACE)], Action::SetMark),
+                (vec![CTRL + ch_scan('G')], Action::Cancel),
+                (vec![CTRL + ch_scan('K')], Action::KillLine),
+                (vec![CTRL + ch_scan('W')], Action::KillRegion),
+                (vec![ALT + ch_scan('W')], Action::CopyRegion),
+                (vec![CTRL + ch_scan('Y')], Action::Yank),
+                (vec![ALT + ch_scan('Y')], Action::YankPop),
+
+                // C-/ and C-_, assuming US layout
+                (vec![CTRL + vk(VK_OEM_2)], cmd(Idm::Undo)),
+                (vec![CTRL + (SHIFT + vk(VK_OEM_MINUS))], cmd(Idm::Undo)),
+                (vec![c_x.clone(), ch_scan('U')], cmd(Idm::Undo)),
+                (vec![c_x.clone(), ch_scan('H')], cmd(Idm::SelectAll)),
+                (vec![CTRL + ch_scan('S')], cmd(Idm::Find)),
+                (vec![vk(VK_F3)], cmd(Idm::FindNext)),
+                (vec![ALT + (SHIFT + vk(VK_OEM_5))], cmd(Idm::FilterSelection)),
+                (vec![c_x.clone(), CTRL + (SHIFT + ch_scan('I'))], cmd(Idm::FormatDocument)),
+                (vec![CTRL + (ALT + ch_scan('F'))], cmd(Idm::GoToMatchingBracket)),
+                (vec![CTRL + (ALT + ch_scan('B'))], cmd(Idm::GoToMatchingBracket)),
+                (vec![CTRL + (ALT + vk(VK_SPACE))], cmd(Idm::SelectToMatchingBracket)),
+                (vec![CTRL + ch_scan('H'), vk(VK_OEM_PERIOD)], cmd(Idm::Hover)),
+                (vec![ALT + vk(VK_OEM_PERIOD)], cmd(Idm::GoToDefinition)),
+                (vec![ALT + vk(VK_OEM_2)], cmd(Idm::Complete)),
+                (vec![vk(VK_F7)], cmd(Idm::Build)),
+                (vec![c_x.clone(), vk(VK_OEM_3)], cmd(Idm::NextError)),
+                (vec![ALT + ch_scan('G'), ch_scan('N')], cmd(Idm::NextError)),
+                (vec![ALT + ch_scan('G'), ch_scan('P')], cmd(Idm::PrevError)),
+                (vec![ALT + ch_scan('G'), ch_scan('G')], cmd(Idm::GoToLine)),
+                (vec![c_x.clone(), SHIFT + ch_scan('9')], cmd(Idm::ToggleMacroRecording)),
+                (vec![c_x.clone(), SHIFT + ch_scan('0')], cmd(Idm::ToggleMacroRecording)),
+                (vec![c_x.clone(), ch_scan('E')], cmd(Idm::PlayMacro)),
+                (vec![c_x.clone(), CTRL + ch_scan('F')], cmd(Idm::Open)),
+                (vec![c_x.clone(), CTRL + ch_scan('S')], cmd(Idm::Save)),
+                (vec![c_x.clone(), CTRL + ch_scan('W')], cmd(Idm::SaveAs)),
+                (vec![c_x, CTRL + ch_scan('C')], cmd(Idm::Exit)),
+            ]
+        }
+    };
+    bindings.extend(font_bindings);
+    bindings
+}
 
-        (ALT + ch_scan('Q'), Idm::Exit),
-    ]
+// Later bindings override earlier ones, including the key sequences
+// that start with the same keys.
+fn add_key_binding(
+    bindings: &mut Vec<(Vec<KeyMatcher>, Action)>,
+    seq: Vec<KeyMatcher>,
+    action: Action,
+) {
+    bindings.retain(|(s, _)| {
+        let n = s.len().min(seq.len());
+        s[..n] != seq[..n]
+    });
+    bindings.push((seq, action));
 }
 
-fn handle_keydown(app_state: &mut Token<AppState>, k: KeyEvent) {
+fn run_script_command(app_state: &mut Token<AppState>, idx: usize) {
     let mut g = app_state.borrow_mut();
     let a = &mut *g;
-
-    if let Some(cmd) = a.match_key_event(&k) {
-        drop(g);
-        send_message(app_state, WM_COMMAND, cmd as usize, 0);
+    if !a.check_writable() {
         return;
     }
+    a.last_action = ActionType::Other;
+    a.mark_active = false;
+    a.view_state.make_undo_snapshot();
+    a.script_error = a.scripts.run_command(idx, &mut a.view_state).err();
+    if let Some(e) = &a.script_error {
+        info!("script error: {}", e);
+        unsafe {
+            MessageBeep(MB_ICONERROR);
+        }
+    }
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
 
-    let view_state = &mut a.view_state;
-
-    let ctrl_pressed = k.ctrl_pressed;
-    let shift_pressed = k.shift_pressed;
+// Returns true if the key was consumed by a key binding.
+fn handle_key_binding(app_state: &mut Token<AppState>, k: &KeyEvent) -> bool {
+    let m = app_state.borrow_mut().match_key_event(k);
+    let consumed = match m {
+        KeyMatch::Action(_) | KeyMatch::Consumed => true,
+        KeyMatch::NotBound => false,
+    };
+    app_state.borrow_mut().swallow_char = consumed;
+    if let KeyMatch::Action(action) = m {
+        run_action(app_state, action);
+    }
+    consumed
+}
 
-    let mut regular_movement_cmd = true;
-    match k.key_code {
-        VK_BACK => {
-            // TODO: also make shapshot before deleting newline
-            if a.last_action != ActionType::Backspace {
-                view_state.make_undo_snapshot();
-                a.last_action = ActionType::Backspace;
-            }
-            view_state.backspace();
-            regular_movement_cmd = false;
+fn run_action(app_state: &mut Token<AppState>, action: Action) {
+    match action {
+        Action::Command(cmd) => {
+            send_message(app_state, WM_COMMAND, cmd as usize, 0);
+            return;
         }
-        VK_DELETE => {
-            // TODO: also make shapshot before deleting newline
-            if a.last_action != ActionType::Del {
-                view_state.make_undo_snapshot();
-                a.last_action = ActionType::Del;
-            }
-            view_state.del();
-            regular_movement_cmd = false;
+        Action::Script(idx) => {
+            run_script_command(app_state, idx);
+            return;
         }
-        VK_LEFT => {
+        _ => {}
+    }
+    let mut g = app_state.borrow_mut();
+    let a = &mut *g;
+    match action {
+        Action::Command(_) | Action::Script(_) => unreachable!(),
+        Action::Motion(motion) => {
             a.last_action = ActionType::Other;
-            if ctrl_pressed {
-                view_state.ctrl_left()
+            a.view_state.move_cursor(motion);
+            if a.mark_active {
+                a.record(MacroCmd::Select(motion));
             } else {
-                view_state.left()
+                a.view_state.clear_selection();
+                a.record(MacroCmd::Move(motion));
             }
         }
-        VK_RIGHT => {
-            a.last_action = ActionType::Other;
-            if ctrl_pressed {
-                view_state.ctrl_right()
-            } else {
-                view_state.right()
+        Action::KillLine => {
+            if !a.check_writable() {
+                return;
             }
+            let append = a.last_action == ActionType::Kill;
+            if !append {
+                a.view_state.make_undo_snapshot();
+            }
+            a.mark_active = false;
+            a.record(MacroCmd::KillLine);
+            let s = a.view_state.kill_line();
+            a.kill_ring.kill(&s, append);
+            set_clipboard(a.hwnd, a.kill_ring.top().unwrap());
+            a.last_action = ActionType::Kill;
         }
-        VK_HOME => {
-            a.last_action = ActionType::Other;
-            if ctrl_pressed {
-                view_state.ctrl_home()
-            } else {
-                view_state.home()
+        Action::KillRegion => {
+            if !a.check_writable() {
+                return;
+            }
+            a.mark_active = false;
+            if a.view_state.has_selection() {
+                let append = a.last_action == ActionType::Kill;
+                if !append {
+                    a.view_state.make_undo_snapshot();
+                }
+                a.record(MacroCmd::Del);
+                let s = a.view_state.cut_selection();
+                a.kill_ring.kill(&s, append);
+                set_clipboard(a.hwnd, a.kill_ring.top().unwrap());
+                a.last_action = ActionType::Kill;
             }
         }
-        VK_END => {
+        Action::CopyRegion => {
+            a.mark_active = false;
             a.last_action = ActionType::Other;
-            if ctrl_pressed {
-                view_state.ctrl_end()
-            } else {
-                view_state.end()
+            if a.view_state.has_selection() {
+                let s = a.view_state.get_selection();
+                a.kill_ring.kill(&s, false);
+                set_clipboard(a.hwnd, &s);
+                a.view_state.clear_selection();
             }
         }
-        VK_UP => {
-            a.last_action = ActionType::Other;
-            if ctrl_pressed {
-                regular_movement_cmd = false;
-                view_state.scroll(1.0)
-            } else {
-                view_state.up()
+        Action::Yank => {
+            if !a.check_writable() {
+                return;
+            }
+            a.mark_active = false;
+            a.kill_ring.sync_with_clipboard(get_clipboard(a.hwnd));
+            if let Some(s) = a.kill_ring.yank() {
+                let s = s.to_owned();
+                a.view_state.make_undo_snapshot();
+                a.view_state.clear_selection();
+                a.record(MacroCmd::Paste(s.clone()));
+                a.view_state.paste(&s);
+                let end = a.view_state.cursor_pos();
+                a.last_yank = Some((end - s.chars().count(), end));
+                a.last_action = ActionType::Yank;
             }
         }
-        VK_DOWN => {
-            a.last_action = ActionType::Other;
-            if ctrl_pressed  {
-                regular_movement_cmd = false;
-                view_state.scroll(-1.0)
-            } else {
-                view_state.down()
+        Action::YankPop => {
+            if !a.check_writable() {
+                return;
             }
+            // Replaces just yanked text with the previous kill ring entry.
+            if a.last_action == ActionType::Yank {
+                if let (Some((start, end)), Some(s)) = (a.last_yank, a.kill_ring.yank_pop()) {
+                    let s = s.to_owned();
+                    if let Some(Some(MacroCmd::Paste(p))) = a.macro_recording.as_mut().map(|r| r.last_mut()) {
+                        *p = s.clone();
+                    }
+                    a.view_state.set_selection(start, end);
+                    a.view_state.paste(&s);
+                    a.last_yank = Some((start, start + s.chars().count()));
+                }
+            }
+        }
+        Action::SetMark => {
+            a.last_action = ActionType::Other;
+            a.mark_active = true;
+            a.view_state.clear_selection();
+        }
+        Action::Cancel => {
+            a.last_action = ActionType::Other;
+            a.mark_active = false;
+            a.view_state.clear_selection();
+            cancel_loading(a);
+        }
+    }
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+// Moves the cursor, Tab switches between the hex and ASCII columns.
+fn handle_hex_key(a: &mut AppState, k: &KeyEvent) {
+    let hex = a.hex.as_mut().unwrap();
+    let row = hex::BYTES_PER_ROW;
+    let col = hex.cursor() % row;
+    let page = (hex.page_rows() * row) as isize;
+    match k.key_code {
+        VK_LEFT | VK_BACK => hex.move_by(-1),
+        VK_RIGHT => hex.move_by(1),
+        VK_UP => hex.move_by(-(row as isize)),
+        VK_DOWN => hex.move_by(row as isize),
+        VK_PRIOR => hex.move_by(-page),
+        VK_NEXT => hex.move_by(page),
+        VK_HOME if k.ctrl_pressed => hex.move_to(0),
+        VK_HOME => hex.move_by(-(col as isize)),
+        VK_END if k.ctrl_pressed => hex.move_to(usize::MAX),
+        VK_END => hex.move_by((row - 1 - col) as isize),
+        VK_TAB => {
+            a.swallow_char = true;
+            hex.ascii = !hex.ascii;
+        }
+        _ => return,
+    }
+    a.last_action = ActionType::Other;
+    invalidate_rect(a.hwnd);
+}
+
+// Scrolls, there is no cursor.
+fn handle_mapped_key(a: &mut AppState, k: &KeyEvent) {
+    let doc = a.mapped.as_mut().unwrap();
+    let page = doc.page_lines() as f32;
+    match k.key_code {
+        VK_UP => doc.scroll(1.0),
+        VK_DOWN => doc.scroll(-1.0),
+        VK_PRIOR => doc.scroll(page),
+        VK_NEXT => doc.scroll(-page),
+        VK_HOME if k.ctrl_pressed => doc.scroll_to(0.0),
+        VK_END if k.ctrl_pressed => doc.scroll_to(f32::MAX),
+        VK_LEFT => doc.set_left_col(doc.left_col().saturating_sub(1)),
+        VK_RIGHT => doc.set_left_col(doc.left_col() + 1),
+        VK_HOME => doc.set_left_col(0),
+        _ => return,
+    }
+    a.last_action = ActionType::Other;
+    invalidate_rect(a.hwnd);
+}
+
+fn handle_keydown(app_state: &mut Token<AppState>, k: KeyEvent) {
+    if !k.is_modifier() {
+        let mut a = app_state.borrow_mut();
+        if a.hover.take().is_some() {
+            invalidate_rect(a.hwnd);
+        }
+    }
+    if app_state.borrow_mut().hex.is_some() {
+        if !handle_key_binding(app_state, &k) {
+            handle_hex_key(&mut app_state.borrow_mut(), &k);
+        }
+        return;
+    }
+    if app_state.borrow_mut().mapped.is_some() {
+        if !handle_key_binding(app_state, &k) {
+            handle_mapped_key(&mut app_state.borrow_mut(), &k);
         }
-        VK_PRIOR => {
+        return;
+    }
+    if handle_completion_key(&mut app_state.borrow_mut(), &k)
+        || handle_tab_key(&mut app_state.borrow_mut(), &k) {
+        return;
+    }
+    if handle_key_binding(app_state, &k) {
+        return;
+    }
+
+    let mut g = app_state.borrow_mut();
+    let a = &mut *g;
+
+    let ctrl_pressed = k.ctrl_pressed;
+    let shift_pressed = k.shift_pressed;
+
+    let motion = match k.key_code {
+        VK_LEFT if ctrl_pressed => Motion::CtrlLeft,
+        VK_LEFT => Motion::Left,
+        VK_RIGHT if ctrl_pressed => Motion::CtrlRight,
+        VK_RIGHT => Motion::Right,
+        VK_HOME if ctrl_pressed => Motion::CtrlHome,
+        VK_HOME => Motion::Home,
+        VK_END if ctrl_pressed => Motion::CtrlEnd,
+        VK_END => Motion::End,
+        VK_UP if !ctrl_pressed => Motion::Up,
+        VK_DOWN if !ctrl_pressed => Motion::Down,
+        VK_PRIOR => Motion::PgUp,
+        VK_NEXT => Motion::PgDown,
+        VK_UP => {
             a.last_action = ActionType::Other;
-            view_state.pg_up();
+            a.view_state.scroll(1.0);
+            invalidate_rect(a.hwnd);
+            return;
         }
-        VK_NEXT => {
+        VK_DOWN => {
             a.last_action = ActionType::Other;
-            view_state.pg_down();
+            a.view_state.scroll(-1.0);
+            invalidate_rect(a.hwnd);
+            return;
+        }
+        VK_BACK => {
+            if !a.check_writable() {
+                return;
+            }
+            // TODO: also make shapshot before deleting newline
+            if a.last_action != ActionType::Backspace {
+                a.view_state.make_undo_snapshot();
+                a.last_action = ActionType::Backspace;
+            }
+            a.mark_active = false;
+            if a.view_state.delete_auto_pair() {
+                a.record(MacroCmd::Backspace);
+                a.record(MacroCmd::Del);
+                invalidate_rect(a.hwnd);
+                a.update_title();
+                return;
+            }
+            let n = a.view_state.smart_backspace();
+            if n == 0 {
+                a.view_state.backspace();
+            }
+            for _ in 0..n.max(1) {
+                a.record(MacroCmd::Backspace);
+            }
+            invalidate_rect(a.hwnd);
+            a.update_title();
+            return;
+        }
+        VK_DELETE => {
+            if !a.check_writable() {
+                return;
+            }
+            // TODO: also make shapshot before deleting newline
+            if a.last_action != ActionType::Del {
+                a.view_state.make_undo_snapshot();
+                a.last_action = ActionType::Del;
+            }
+            a.mark_active = false;
+            a.record(MacroCmd::Del);
+            a.view_state.del();
+            invalidate_rect(a.hwnd);
+            a.update_title();
+            return;
         }
         VK_RETURN => {
+            if !a.check_writable() {
+                return;
+            }
             a.last_action = ActionType::InsertChar;
-            view_state.make_undo_snapshot();
-            view_state.insert_char('\n');
-            regular_movement_cmd = false;
+            a.mark_active = false;
+            a.view_state.make_undo_snapshot();
+            let text = a.view_state.newline_and_indent();
+            a.record(MacroCmd::Paste(text));
+            invalidate_rect(a.hwnd);
+            a.update_title();
+            return;
         }
         _ => return,
     };
-    if regular_movement_cmd && !shift_pressed {
-        view_state.clear_selection();
+    a.last_action = ActionType::Other;
+    a.view_state.move_cursor(motion);
+    if shift_pressed || a.mark_active {
+        a.record(MacroCmd::Select(motion));
+    } else {
+        a.view_state.clear_selection();
+        a.record(MacroCmd::Move(motion));
     }
     invalidate_rect(a.hwnd);
     a.update_title();
@@ -510,7 +2257,7 @@ fn get_app_state(hwnd: HWND) -> Token<AppState> {
     Token::new(cell)
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 enum Idm {
     New = 1,
     Open,
@@ -523,17 +2270,64 @@ enum Idm {
     Copy,
     Paste,
     SelectAll,
+    Find,
+    FindNext,
+    FilterSelection,
+    FormatDocument,
+    GoToLine,
+    ChangeIndent,
+    ChangeEncoding,
+    ChangeLineEnding,
+    GoToMatchingBracket,
+    SelectToMatchingBracket,
+    Hover,
+    GoToDefinition,
+    Complete,
+    Build,
+    NextError,
+    PrevError,
+    ErrorList,
     SmallerFont,
     LargerFont,
+    Fold,
+    Unfold,
+    FoldAll,
+    UnfoldAll,
+    ToggleWrap,
+    ToggleFollow,
+    ToggleHex,
+    ToggleMacroRecording,
+    PlayMacro,
+    PlayMacroTimes,
+    PlayMacroToEnd,
+    SaveMacro,
+    LoadMacro,
+    StopLoading,
 }
 
-fn create_app_menu() -> HMENU {
+// Not for the documents that are read only or still loading.
+fn edits_document(cmd: Idm) -> bool {
+    matches!(cmd,
+        Idm::Save | Idm::SaveAs | Idm::Undo | Idm::Redo | Idm::Cut | Idm::Paste
+        | Idm::FilterSelection | Idm::FormatDocument | Idm::ChangeIndent
+        | Idm::ChangeEncoding | Idm::ChangeLineEnding | Idm::Complete
+        | Idm::PlayMacro | Idm::PlayMacroTimes | Idm::PlayMacroToEnd)
+}
+
+// Script commands get menu ids starting from here.
+const SCRIPT_COMMAND_BASE: u16 = 1000;
+
+fn create_app_menu(scripts: &ScriptHost) -> HMENU {
     let file_menu = create_menu();
     append_menu_string(file_menu, Idm::New as u16, "&New\tCtrl-N");
     append_menu_string(file_menu, Idm::Open as u16, "&Open...\tCtrl-O");
     append_menu_string(file_menu, Idm::Save as u16, "&Save\tCtrl-S");
     append_menu_string(file_menu, Idm::SaveAs as u16, "&Save As...\tCtrl-Shift-S");
     append_menu_separator(file_menu);
+    append_menu_string(file_menu, Idm::ChangeEncoding as u16, "E&ncoding...");
+    append_menu_string(file_menu, Idm::ChangeLineEnding as u16, "&Line endings...");
+    append_menu_string(file_menu, Idm::StopLoading as u16, "S&top loading\tEsc");
+    append_menu_separator(file_menu);
     append_menu_string(file_menu, Idm::Exit as u16, "&Exit\tAlt-Q");
     let edit_menu = create_menu();
     append_menu_string(edit_menu, Idm::Undo as u16, "&Undo\tCtrl-Z");
@@ -547,13 +2341,63 @@ fn create_app_menu() -> HMENU {
 
     append_menu_separator(edit_menu);
     append_menu_string(edit_menu, Idm::SelectAll as u16, "&Select all\tCtrl-A");
+    append_menu_separator(edit_menu);
+    append_menu_string(edit_menu, Idm::Find as u16, "&Find...\tCtrl-F");
+    append_menu_string(edit_menu, Idm::FindNext as u16, "Find &next\tF3");
+    append_menu_separator(edit_menu);
+    append_menu_string(edit_menu, Idm::FilterSelection as u16, "Fi&lter selection through command...\tCtrl-|");
+    append_menu_string(edit_menu, Idm::FormatDocument as u16, "F&ormat document\tCtrl-Shift-I");
+    append_menu_string(edit_menu, Idm::ChangeIndent as u16, "&Indentation...");
+    append_menu_separator(edit_menu);
+    append_menu_string(edit_menu, Idm::GoToLine as u16, "&Go to line...\tCtrl-G");
+    append_menu_string(edit_menu, Idm::GoToMatchingBracket as u16, "Go to &matching bracket\tCtrl-]");
+    append_menu_string(edit_menu, Idm::SelectToMatchingBracket as u16, "Select to matching &bracket\tCtrl-Shift-]");
     let view_menu = create_menu();
     append_menu_string(view_menu, Idm::SmallerFont as u16, "&Smaller font\tCtrl-- or Ctrl-Wheel Up");
     append_menu_string(view_menu, Idm::LargerFont as u16, "&Larger font\tCtrl-+ or Ctrl-Wheel Down");
+    append_menu_separator(view_menu);
+    append_menu_string(view_menu, Idm::Fold as u16, "&Fold\tCtrl-Shift--");
+    append_menu_string(view_menu, Idm::Unfold as u16, "&Unfold\tCtrl-Shift-+");
+    append_menu_string(view_menu, Idm::FoldAll as u16, "Fold &all");
+    append_menu_string(view_menu, Idm::UnfoldAll as u16, "Unfold all");
+    append_menu_separator(view_menu);
+    append_menu_string(view_menu, Idm::ToggleWrap as u16, "&Word wrap\tAlt-Z");
+    append_menu_string(view_menu, Idm::ToggleFollow as u16, "F&ollow file\tCtrl-Shift-F");
+    append_menu_string(view_menu, Idm::ToggleHex as u16, "&Hex view\tCtrl-Shift-H");
+    let code_menu = create_menu();
+    append_menu_string(code_menu, Idm::Hover as u16, "Show &info\tCtrl-K");
+    append_menu_string(code_menu, Idm::GoToDefinition as u16, "Go to &definition\tF12");
+    append_menu_string(code_menu, Idm::Complete as u16, "&Complete\tCtrl-Space");
+    append_menu_separator(code_menu);
+    append_menu_string(code_menu, Idm::Build as u16, "&Build\tF7");
+    append_menu_string(code_menu, Idm::NextError as u16, "&Next error\tF8");
+    append_menu_string(code_menu, Idm::PrevError as u16, "&Previous error\tShift-F8");
+    append_menu_string(code_menu, Idm::ErrorList as u16, "&Error list...");
+    let macro_menu = create_menu();
+    append_menu_string(macro_menu, Idm::ToggleMacroRecording as u16, "&Start/stop recording\tCtrl-Shift-R");
+    append_menu_string(macro_menu, Idm::PlayMacro as u16, "&Play\tCtrl-Shift-P");
+    append_menu_string(macro_menu, Idm::PlayMacroTimes as u16, "Play &N times...");
+    append_menu_string(macro_menu, Idm::PlayMacroToEnd as u16, "Play to &end of file");
+    append_menu_separator(macro_menu);
+    append_menu_string(macro_menu, Idm::SaveMacro as u16, "S&ave macro...");
+    append_menu_string(macro_menu, Idm::LoadMacro as u16, "&Load macro...");
     let menu = create_menu();
     append_menu_popup(menu, file_menu, "File");
     append_menu_popup(menu, edit_menu, "Edit");
     append_menu_popup(menu, view_menu, "View");
+    append_menu_popup(menu, code_menu, "Code");
+    append_menu_popup(menu, macro_menu, "Macro");
+    if !scripts.commands.is_empty() {
+        let scripts_menu = create_menu();
+        for (i, cmd) in scripts.commands.iter().enumerate() {
+            let text = match &cmd.key {
+                Some(key) => format!("{}\t{}", cmd.title, key),
+                None => cmd.title.clone(),
+            };
+            append_menu_string(scripts_menu, SCRIPT_COMMAND_BASE + i as u16, &text);
+        }
+        append_menu_popup(menu, scripts_menu, "Scripts");
+    }
     menu
 }
 
@@ -561,11 +2405,11 @@ fn enable_available_menu_items(app_state: &mut AppState) {
     enable_or_disable_menu_item(
         app_state.menu,
         Idm::New as u16,
-        app_state.filename.is_some() || app_state.view_state.modified());
+        app_state.filename.is_some() || app_state.modified());
     enable_or_disable_menu_item(
         app_state.menu,
         Idm::Save as u16,
-        app_state.filename.is_none() || app_state.view_state.modified());
+        app_state.filename.is_none() || app_state.modified());
     enable_or_disable_menu_item(
         app_state.menu,
         Idm::Undo as u16,
@@ -582,6 +2426,14 @@ fn enable_available_menu_items(app_state: &mut AppState) {
         app_state.menu,
         Idm::Copy as u16,
         app_state.view_state.has_selection());
+    enable_or_disable_menu_item(
+        app_state.menu,
+        Idm::FilterSelection as u16,
+        app_state.view_state.has_selection());
+    enable_or_disable_menu_item(
+        app_state.menu,
+        Idm::FormatDocument as u16,
+        app_state.filename.as_ref().and_then(|p| app_state.config.formatter_for(p)).is_some());
     enable_or_disable_menu_item(
         app_state.menu,
         Idm::SmallerFont as u16,
@@ -590,9 +2442,172 @@ fn enable_available_menu_items(app_state: &mut AppState) {
         app_state.menu,
         Idm::LargerFont as u16,
         app_state.font_size < MAX_FONT_SIZE);
+    enable_or_disable_menu_item(app_state.menu, Idm::GoToDefinition as u16, app_state.lsp.is_some());
+    enable_or_disable_menu_item(app_state.menu, Idm::Build as u16, app_state.build.is_none());
+    for &id in &[Idm::NextError, Idm::PrevError, Idm::ErrorList] {
+        enable_or_disable_menu_item(app_state.menu, id as u16, !app_state.build_messages.is_empty());
+    }
+    let can_play = app_state.last_macro.is_some() && app_state.macro_recording.is_none();
+    for &id in &[Idm::PlayMacro, Idm::PlayMacroTimes, Idm::PlayMacroToEnd] {
+        enable_or_disable_menu_item(app_state.menu, id as u16, can_play);
+    }
+    enable_or_disable_menu_item(
+        app_state.menu,
+        Idm::SaveMacro as u16,
+        app_state.last_macro.is_some());
+    enable_or_disable_menu_item(
+        app_state.menu,
+        Idm::LoadMacro as u16,
+        app_state.macro_recording.is_none());
+}
+
+fn find(app_state: &mut Token<AppState>, needle: String) {
+    if needle.is_empty() {
+        return;
+    }
+    let found = {
+        let mut g = app_state.borrow_mut();
+        let a = &mut *g;
+        a.last_action = ActionType::Other;
+        a.record(MacroCmd::Find(needle.clone()));
+        let found = a.view_state.find_next(&needle);
+        a.last_search = Some(needle.clone());
+        invalidate_rect(a.hwnd);
+        found
+    };
+    if !found {
+        let msg = format!("Can't find {:?}.", needle);
+        message_box(app_state, "an editor", &msg, MB_OK | MB_ICONINFORMATION);
+    }
+}
+
+// Replaces the selection with the command output as one undo step.
+// If the command fails, the text is left as is.
+fn filter_selection(app_state: &mut Token<AppState>, command: String) {
+    let mut g = app_state.borrow_mut();
+    let a = &mut *g;
+    a.last_action = ActionType::Other;
+    a.last_filter_command = Some(command.clone());
+    let input = a.view_state.get_selection();
+    match filter::run_filter(&command, &input, a.config.filter_timeout) {
+        Ok(output) => {
+            if !output.stderr.is_empty() {
+                a.add_flash(output.stderr.trim_end());
+            }
+            let text = filter::match_trailing_newline(&input, output.stdout);
+            if text != input {
+                let (start, end) = a.view_state.selection_range();
+                a.view_state.make_undo_snapshot();
+                a.view_state.replace_text(start, end, &text);
+                a.view_state.set_selection(start, start + text.chars().count());
+            }
+        }
+        Err(e) => a.add_flash(&e),
+    }
+    invalidate_rect(a.hwnd);
+    a.update_title();
+}
+
+fn play_macro(app_state: &mut Token<AppState>, repeat: Repeat) {
+    let mut g = app_state.borrow_mut();
+    let a = &mut *g;
+    if a.macro_recording.is_some() {
+        return;
+    }
+    if let Some(m) = &a.last_macro {
+        a.last_action = ActionType::Other;
+        let count = m.play_repeatedly(&mut a.view_state, repeat);
+        info!("macro played {} times", count);
+        invalidate_rect(a.hwnd);
+        a.update_title();
+    }
+}
+
+// The ones that work differently on bytes.
+fn handle_hex_command(app_state: &mut Token<AppState>, cmd: Idm) {
+    match cmd {
+        Idm::Undo => {
+            let mut g = app_state.borrow_mut();
+            if !g.hex.as_mut().unwrap().undo() {
+                unsafe {
+                    MessageBeep(MB_OK);
+                }
+            }
+            invalidate_rect(g.hwnd);
+            g.update_title();
+        }
+        Idm::GoToLine => {
+            let current = format!("{:X}", app_state.borrow_mut().hex.as_ref().unwrap().cursor());
+            if let Some(s) = input_box(app_state, "an editor - go to offset", "Offset (hex):", &current) {
+                match hex::parse_offset(&s) {
+                    Some(offset) => {
+                        let mut g = app_state.borrow_mut();
+                        g.hex.as_mut().unwrap().move_to(offset);
+                        invalidate_rect(g.hwnd);
+                    }
+                    None => {
+                        let msg = format!("{:?} is not a hex offset.", s);
+                        message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
+        Idm::Find | Idm::FindNext => {
+            let last = app_state.borrow_mut().last_search.clone().filter(|s| hex::parse_pattern(s).is_some());
+            let needle = match (cmd, last) {
+                (Idm::FindNext, Some(needle)) => Some(needle),
+                (_, last) => input_box(
+                    app_state, "an editor - find", "Bytes (hex, or \"text\" in quotes):", &last.unwrap_or_default()),
+            };
+            let needle = match needle {
+                Some(needle) => needle,
+                None => return,
+            };
+            let msg = match hex::parse_pattern(&needle) {
+                Some(pattern) => {
+                    let mut g = app_state.borrow_mut();
+                    g.last_search = Some(needle.clone());
+                    let found = g.hex.as_mut().unwrap().find(&pattern);
+                    invalidate_rect(g.hwnd);
+                    if found {
+                        return;
+                    }
+                    format!("Can't find {}.", needle.trim())
+                }
+                None => format!("{:?} is not hex bytes or quoted text.", needle),
+            };
+            message_box(app_state, "an editor", &msg, MB_OK | MB_ICONINFORMATION);
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Lines can be reached once they are indexed.
+fn go_to_mapped_line(app_state: &mut Token<AppState>) {
+    let current = (app_state.borrow_mut().mapped.as_ref().unwrap().top_line() + 1).to_string();
+    let s = match input_box(app_state, "an editor - go to line", "Line:", &current) {
+        Some(s) => s,
+        None => return,
+    };
+    let msg = match s.trim().parse::<usize>() {
+        Ok(line) if line > 0 => {
+            let mut g = app_state.borrow_mut();
+            if g.mapped.as_mut().unwrap().go_to_line(line - 1) {
+                invalidate_rect(g.hwnd);
+                return;
+            }
+            format!("Line {} is not indexed yet.", line)
+        }
+        _ => format!("{:?} is not a line number.", s),
+    };
+    message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
 }
 
 fn handle_menu_command(app_state: &mut Token<AppState>, id: u16) {
+    if id >= SCRIPT_COMMAND_BASE {
+        run_script_command(app_state, (id - SCRIPT_COMMAND_BASE) as usize);
+        return;
+    }
     let cmd = if id == Idm::New as u16 { Idm::New }
         else if id == Idm::Open as u16 { Idm::Open }
         else if id == Idm::Save as u16 { Idm::Save }
@@ -604,30 +2619,112 @@ fn handle_menu_command(app_state: &mut Token<AppState>, id: u16) {
         else if id == Idm::Copy as u16 { Idm::Copy }
         else if id == Idm::Paste as u16 { Idm::Paste }
         else if id == Idm::SelectAll as u16 { Idm::SelectAll }
+        else if id == Idm::Find as u16 { Idm::Find }
+        else if id == Idm::FindNext as u16 { Idm::FindNext }
+        else if id == Idm::FilterSelection as u16 { Idm::FilterSelection }
+        else if id == Idm::FormatDocument as u16 { Idm::FormatDocument }
+        else if id == Idm::GoToLine as u16 { Idm::GoToLine }
+        else if id == Idm::ChangeIndent as u16 { Idm::ChangeIndent }
+        else if id == Idm::ChangeEncoding as u16 { Idm::ChangeEncoding }
+        else if id == Idm::ChangeLineEnding as u16 { Idm::ChangeLineEnding }
+        else if id == Idm::GoToMatchingBracket as u16 { Idm::GoToMatchingBracket }
+        else if id == Idm::SelectToMatchingBracket as u16 { Idm::SelectToMatchingBracket }
+        else if id == Idm::Hover as u16 { Idm::Hover }
+        else if id == Idm::GoToDefinition as u16 { Idm::GoToDefinition }
+        else if id == Idm::Complete as u16 { Idm::Complete }
+        else if id == Idm::Build as u16 { Idm::Build }
+        else if id == Idm::NextError as u16 { Idm::NextError }
+        else if id == Idm::PrevError as u16 { Idm::PrevError }
+        else if id == Idm::ErrorList as u16 { Idm::ErrorList }
         else if id == Idm::SmallerFont as u16 { Idm::SmallerFont }
         else if id == Idm::LargerFont as u16 { Idm::LargerFont }
+        else if id == Idm::Fold as u16 { Idm::Fold }
+        else if id == Idm::Unfold as u16 { Idm::Unfold }
+        else if id == Idm::FoldAll as u16 { Idm::FoldAll }
+        else if id == Idm::UnfoldAll as u16 { Idm::UnfoldAll }
+        else if id == Idm::ToggleWrap as u16 { Idm::ToggleWrap }
+        else if id == Idm::ToggleFollow as u16 { Idm::ToggleFollow }
+        else if id == Idm::ToggleHex as u16 { Idm::ToggleHex }
+        else if id == Idm::ToggleMacroRecording as u16 { Idm::ToggleMacroRecording }
+        else if id == Idm::PlayMacro as u16 { Idm::PlayMacro }
+        else if id == Idm::PlayMacroTimes as u16 { Idm::PlayMacroTimes }
+        else if id == Idm::PlayMacroToEnd as u16 { Idm::PlayMacroToEnd }
+        else if id == Idm::SaveMacro as u16 { Idm::SaveMacro }
+        else if id == Idm::LoadMacro as u16 { Idm::LoadMacro }
+        else if id == Idm::StopLoading as u16 { Idm::StopLoading }
         else { panic!("{}", id) };
 
+    if app_state.borrow_mut().hex.is_some() {
+        match cmd {
+            Idm::Undo | Idm::GoToLine | Idm::Find | Idm::FindNext => {
+                handle_hex_command(app_state, cmd);
+                return;
+            }
+            // the bytes are saved as they are
+            Idm::Save | Idm::SaveAs => {}
+            _ if edits_document(cmd) => {
+                unsafe {
+                    MessageBeep(MB_OK);
+                }
+                return;
+            }
+            _ => {}
+        }
+    } else if app_state.borrow_mut().mapped.is_some() {
+        match cmd {
+            Idm::GoToLine => {
+                go_to_mapped_line(app_state);
+                return;
+            }
+            // not with the whole file decoded on the UI thread
+            Idm::Find | Idm::FindNext | Idm::ToggleHex | Idm::ToggleFollow => {
+                unsafe {
+                    MessageBeep(MB_OK);
+                }
+                return;
+            }
+            _ if edits_document(cmd) && !app_state.borrow_mut().check_writable() => return,
+            _ => {}
+        }
+    } else if edits_document(cmd) && !app_state.borrow_mut().check_writable() {
+        return;
+    }
+    app_state.borrow_mut().mark_active = false;
     match cmd {
+        Idm::StopLoading => {
+            let mut g = app_state.borrow_mut();
+            cancel_loading(&mut g);
+            invalidate_rect(g.hwnd);
+        }
         Idm::Exit => {
             let hwnd = app_state.borrow_mut().hwnd;
             let res = unsafe { PostMessageW(hwnd, WM_CLOSE, 0, 0) };
             assert!(res != 0, "{}", Error::last_os_error());
         }
         Idm::New => {
-            let modified = app_state.borrow_mut().view_state.modified();
+            let modified = app_state.borrow_mut().modified();
             if !modified ||
                 prompt_about_unsaved_changes(app_state) {
                 let mut app_state = app_state.borrow_mut();
+                stop_loading(&mut app_state);
+                stop_following(&mut app_state);
+                close_mapped(&mut app_state);
+                app_state.read_only = false;
+                app_state.hex = None;
                 app_state.last_action = ActionType::Other;
                 app_state.filename = None;
                 app_state.view_state.load("", false);
+                app_state.view_state.indent_style = app_state.config.indent;
+                app_state.view_state.set_tab_width(None);
+                app_state.editorconfig = editorconfig::Settings::default();
+                update_syntax(&mut app_state);
+                open_in_language_server(&mut app_state);
                 invalidate_rect(app_state.hwnd);
                 app_state.update_title();
             }
         }
         Idm::Open => {
-            let modified = app_state.borrow_mut().view_state.modified();
+            let modified = app_state.borrow_mut().modified();
             if !modified ||
                 prompt_about_unsaved_changes(app_state) {
                 if let Some(path) = file_dialog(app_state, FileDialogType::Open) {
@@ -644,7 +2741,7 @@ fn handle_menu_command(app_state: &mut Token<AppState>, id: u16) {
             let a = &mut *g;
             match &a.filename {
                 Some(path) => {
-                    if a.view_state.modified() {
+                    if a.modified() {
                         let path = path.clone();
                         drop(g);
                         save_document(app_state, path);
@@ -692,6 +2789,10 @@ fn handle_menu_command(app_state: &mut Token<AppState>, id: u16) {
             let a = &mut *g;
             a.last_action = ActionType::Other;
             a.view_state.make_undo_snapshot();
+            if a.view_state.has_selection() {
+                // the clipboard side is not replayed
+                a.record(MacroCmd::Del);
+            }
             let s = a.view_state.cut_selection();
             set_clipboard(a.hwnd, &s);
             invalidate_rect(a.hwnd);
@@ -710,6 +2811,7 @@ fn handle_menu_command(app_state: &mut Token<AppState>, id: u16) {
             a.last_action = ActionType::Other;
             let s = get_clipboard(a.hwnd);
             if let Some(s) = s {
+                a.record(MacroCmd::Paste(s.clone()));
                 a.view_state.make_undo_snapshot();
                 a.view_state.paste(&s);
                 invalidate_rect(a.hwnd);
@@ -723,6 +2825,296 @@ fn handle_menu_command(app_state: &mut Token<AppState>, id: u16) {
             a.view_state.select_all();
             invalidate_rect(a.hwnd);
         }
+        Idm::Find => {
+            let text = {
+                let a = app_state.borrow_mut();
+                if a.view_state.has_selection() {
+                    a.view_state.get_selection()
+                } else {
+                    a.last_search.clone().unwrap_or_default()
+                }
+            };
+            if let Some(needle) = input_box(app_state, "an editor - find", "Find:", &text) {
+                find(app_state, needle);
+            }
+        }
+        Idm::FindNext => {
+            let needle = app_state.borrow_mut().last_search.clone();
+            match needle {
+                Some(needle) => find(app_state, needle),
+                None => handle_menu_command(app_state, Idm::Find as u16),
+            }
+        }
+        Idm::FilterSelection => {
+            let (has_selection, text) = {
+                let a = app_state.borrow_mut();
+                (a.view_state.has_selection(), a.last_filter_command.clone().unwrap_or_default())
+            };
+            if !has_selection {
+                return;
+            }
+            if let Some(command) = input_box(app_state, "an editor - filter", "Command:", &text) {
+                if !command.trim().is_empty() {
+                    filter_selection(app_state, command);
+                }
+            }
+        }
+        Idm::FormatDocument => {
+            let mut g = app_state.borrow_mut();
+            let a = &mut *g;
+            if let Some(path) = a.filename.clone() {
+                format_document(a, &path);
+                a.update_title();
+            }
+        }
+        Idm::Fold | Idm::Unfold | Idm::FoldAll | Idm::UnfoldAll => {
+            let mut a = app_state.borrow_mut();
+            a.last_action = ActionType::Other;
+            match cmd {
+                Idm::Fold => { a.view_state.fold(); }
+                Idm::Unfold => { a.view_state.unfold(); }
+                Idm::FoldAll => a.view_state.fold_all(),
+                _ => a.view_state.unfold_all(),
+            }
+            invalidate_rect(a.hwnd);
+        }
+        Idm::ToggleWrap => {
+            let mut g = app_state.borrow_mut();
+            let a = &mut *g;
+            // between no wrap and the configured wrap
+            let wrap = match (a.view_state.wrap(), a.config.wrap) {
+                (Wrap::None, Wrap::None) => Wrap::Window,
+                (Wrap::None, w) => w,
+                _ => Wrap::None,
+            };
+            a.view_state.set_wrap(wrap, a.config.wrap_indent);
+            invalidate_rect(a.hwnd);
+        }
+        Idm::ToggleHex => {
+            let mut g = app_state.borrow_mut();
+            if g.loading.is_some() {
+                unsafe {
+                    MessageBeep(MB_OK);
+                }
+            } else {
+                toggle_hex(&mut g);
+            }
+        }
+        Idm::ToggleFollow => {
+            let mut g = app_state.borrow_mut();
+            let a = &mut *g;
+            if a.follow.is_some() {
+                stop_following(a);
+            } else if a.filename.is_none() || a.loading.is_some() || a.hex.is_some() {
+                unsafe {
+                    MessageBeep(MB_OK);
+                }
+            } else if a.modified() {
+                // the document has to match the file
+                a.add_flash("Save the changes first.");
+                invalidate_rect(a.hwnd);
+            } else {
+                start_following(a);
+            }
+        }
+        Idm::GoToMatchingBracket | Idm::SelectToMatchingBracket => {
+            let mut a = app_state.borrow_mut();
+            a.last_action = ActionType::Other;
+            if a.view_state.go_to_matching_bracket(cmd == Idm::SelectToMatchingBracket) {
+                invalidate_rect(a.hwnd);
+            }
+        }
+        Idm::Complete => {
+            let mut a = app_state.borrow_mut();
+            a.last_action = ActionType::Other;
+            request_completion(&mut a);
+        }
+        Idm::Hover | Idm::GoToDefinition => {
+            let mut g = app_state.borrow_mut();
+            let a = &mut *g;
+            a.last_action = ActionType::Other;
+            sync_language_server(a);
+            let pos = a.view_state.lsp_position(a.view_state.cursor_pos());
+            let sent = match &mut a.lsp {
+                Some(client) => match cmd {
+                    Idm::Hover => client.hover(pos),
+                    _ => client.definition(pos),
+                },
+                None => false,
+            };
+            // without a server, at least the diagnostics can be shown
+            if !sent && cmd == Idm::Hover {
+                let text: Vec<String> = a.view_state.diagnostics_at(a.view_state.cursor_pos())
+                    .iter()
+                    .map(|d| format!("{:?}: {}", d.severity, d.message))
+                    .collect();
+                show_hover(a, &text.join("\n\n"));
+            }
+        }
+        Idm::Build => start_build(app_state),
+        Idm::NextError | Idm::PrevError => {
+            let idx = {
+                let a = app_state.borrow_mut();
+                let n = a.build_messages.len();
+                match (a.current_build_message, cmd) {
+                    _ if n == 0 => None,
+                    (None, Idm::NextError) => Some(0),
+                    (None, _) => Some(n - 1),
+                    (Some(i), Idm::NextError) => Some((i + 1) % n),
+                    (Some(i), _) => Some((i + n - 1) % n),
+                }
+            };
+            if let Some(idx) = idx {
+                go_to_build_message(app_state, idx);
+            }
+        }
+        Idm::ErrorList => {
+            let (items, current) = {
+                let a = app_state.borrow_mut();
+                let items = a.build_messages.iter().map(|m| {
+                    let name = m.path.file_name().map_or(m.path.to_string_lossy(), |n| n.to_string_lossy());
+                    let message = m.message.lines().next().unwrap_or("");
+                    format!("{}:{}: {:?}: {}", name, m.line + 1, m.severity, message)
+                }).collect();
+                (items, a.current_build_message)
+            };
+            if let Some(idx) = list_box(app_state, "an editor - errors", items, current) {
+                go_to_build_message(app_state, idx);
+            }
+        }
+        Idm::ToggleMacroRecording => {
+            let mut g = app_state.borrow_mut();
+            let a = &mut *g;
+            match a.macro_recording.take() {
+                Some(commands) => {
+                    info!("recorded macro: {:?}", commands);
+                    a.last_macro = Some(Macro { commands });
+                }
+                None => a.macro_recording = Some(Vec::new()),
+            }
+            a.update_title();
+        }
+        Idm::PlayMacro => play_macro(app_state, Repeat::Times(1)),
+        Idm::PlayMacroTimes => {
+            if let Some(s) = input_box(app_state, "an editor - play macro", "Number of times:", "1") {
+                match s.trim().parse() {
+                    Ok(n) => play_macro(app_state, Repeat::Times(n)),
+                    Err(_) => {
+                        let msg = format!("{:?} is not a number.", s);
+                        message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
+        Idm::PlayMacroToEnd => play_macro(app_state, Repeat::UntilEnd),
+        Idm::GoToLine => {
+            let (line, col, _) = app_state.borrow_mut().view_state.cursor_line_col();
+            let current = format!("{}:{}", line, col);
+            if let Some(s) = input_box(app_state, "an editor - go to line", "Line[:column]:", &current) {
+                let mut parts = s.trim().splitn(2, ':').map(|p| p.trim().parse::<usize>());
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(line)), col) if line > 0 => {
+                        let col = match col {
+                            Some(Ok(c)) => c.max(1),
+                            _ => 1,
+                        };
+                        let mut a = app_state.borrow_mut();
+                        a.last_action = ActionType::Other;
+                        let pos = a.view_state.line_col_to_pos(line - 1, col - 1);
+                        a.view_state.set_selection(pos, pos);
+                        invalidate_rect(a.hwnd);
+                    }
+                    _ => {
+                        let msg = format!("{:?} is not a line number.", s);
+                        message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
+        Idm::ChangeIndent => {
+            let current = match app_state.borrow_mut().view_state.indent_style {
+                IndentStyle::Tabs => "tab".to_owned(),
+                IndentStyle::Spaces(n) => n.to_string(),
+            };
+            if let Some(s) = input_box(app_state, "an editor - indentation", "\"tab\" or number of spaces:", &current) {
+                match IndentStyle::parse(s.trim()) {
+                    Ok(style) => {
+                        let mut a = app_state.borrow_mut();
+                        a.view_state.indent_style = style;
+                        invalidate_rect(a.hwnd);
+                    }
+                    Err(e) => {
+                        message_box(app_state, "an editor - error", &e, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
+        // they apply on save
+        Idm::ChangeEncoding => {
+            let current = app_state.borrow_mut().editorconfig.charset.unwrap_or(editorconfig::Charset::Utf8);
+            let prompt = "Encoding (utf-8, utf-8-bom, latin1, utf-16le, utf-16be):";
+            if let Some(s) = input_box(app_state, "an editor - encoding", prompt, current.name()) {
+                match editorconfig::Charset::parse(&s.trim().to_lowercase()) {
+                    Some(charset) => {
+                        let mut a = app_state.borrow_mut();
+                        if Some(charset) != a.editorconfig.charset {
+                            a.editorconfig.charset = Some(charset);
+                            a.view_state.mark_modified();
+                            a.update_title();
+                        }
+                    }
+                    None => {
+                        let msg = format!("Unknown encoding {:?}.", s);
+                        message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
+        Idm::ChangeLineEnding => {
+            let current = app_state.borrow_mut().editorconfig.end_of_line.unwrap_or(editorconfig::EndOfLine::Lf);
+            let prompt = "Line endings (lf, crlf, cr):";
+            if let Some(s) = input_box(app_state, "an editor - line endings", prompt, current.name()) {
+                match editorconfig::EndOfLine::parse(&s.trim().to_lowercase()) {
+                    Some(end_of_line) => {
+                        let mut a = app_state.borrow_mut();
+                        if Some(end_of_line) != a.editorconfig.end_of_line {
+                            a.editorconfig.end_of_line = Some(end_of_line);
+                            a.view_state.mark_modified();
+                            a.update_title();
+                        }
+                    }
+                    None => {
+                        let msg = format!("Unknown line endings {:?}.", s);
+                        message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
+        Idm::SaveMacro => {
+            let text = app_state.borrow_mut().last_macro.as_ref().map(Macro::to_text);
+            if let Some(text) = text {
+                if let Some(path) = file_dialog(app_state, FileDialogType::SaveAs) {
+                    if let Err(e) = std::fs::write(&path, text) {
+                        let msg = format!("Can't write to {}.\n{}", path.to_string_lossy(), e);
+                        message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
+        Idm::LoadMacro => {
+            if let Some(path) = file_dialog(app_state, FileDialogType::Open) {
+                let res = std::fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| Macro::from_text(&s));
+                match res {
+                    Ok(m) => app_state.borrow_mut().last_macro = Some(m),
+                    Err(e) => {
+                        let msg = format!("Can't load macro from {}.\n{}", path.to_string_lossy(), e);
+                        message_box(app_state, "an editor - error", &msg, MB_OK | MB_ICONERROR);
+                    }
+                }
+            }
+        }
         Idm::SmallerFont => {
             let mut g = app_state.borrow_mut();
             let a = &mut *g;
@@ -791,23 +3183,62 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
         WM_CLOSE => {
             info!("WM_CLOSE");
             let app_state = &mut get_app_state(hWnd);
-            let modified = app_state.borrow_mut().view_state.modified();
+            let modified = app_state.borrow_mut().modified();
             if !modified ||
                prompt_about_unsaved_changes(app_state) {
                 unsafe { DestroyWindow(hWnd); }
             }
             0
         }
+        WM_LSP => {
+            let app_state = &mut get_app_state(hWnd);
+            handle_lsp_events(app_state);
+            0
+        }
+        WM_TIMER if wParam == LOADING_TIMER => {
+            continue_loading(&mut get_app_state(hWnd).borrow_mut());
+            0
+        }
+        WM_TIMER if wParam == INDEX_TIMER => {
+            continue_indexing(&mut get_app_state(hWnd).borrow_mut());
+            0
+        }
+        WM_TIMER if wParam == FOLLOW_TIMER => {
+            poll_followed_file(&mut get_app_state(hWnd));
+            0
+        }
+        WM_BUILD_DONE => {
+            info!("WM_BUILD_DONE");
+            finish_build(&mut get_app_state(hWnd).borrow_mut());
+            0
+        }
         WM_PAINT => {
             info!("WM_PAINT");
             let app_state = &mut get_app_state(hWnd);
-            let flash = {
+            let (flash, (range, top, page), (h_range, left, h_page)) = {
                 let mut app_state = app_state.borrow_mut();
-                paint(&mut *app_state);
+                // every edit ends up here, so it's a good place to batch them
+                sync_language_server(&mut app_state);
+                let hr = paint(&mut *app_state);
                 let ret = unsafe { ValidateRect(hWnd, null()) };
                 assert!(ret != 0);
-                app_state.flash.take()
+                if hr == D2DERR_RECREATE_TARGET {
+                    info!("device lost, recreating resources");
+                    recreate_resources(&mut app_state);
+                    // and painting again
+                    invalidate_rect(hWnd);
+                } else {
+                    assert!(hr == S_OK, "0x{:x}", hr);
+                }
+                let scroll = match (&app_state.hex, &app_state.mapped) {
+                    (Some(hex), _) => (hex.scroll_position(), (0.0, 0.0, 0.0)),
+                    (_, Some(doc)) => (doc.scroll_position(), (0.0, 0.0, 0.0)),
+                    _ => (app_state.view_state.scroll_position(), app_state.view_state.h_scroll_position()),
+                };
+                (app_state.flash.take(), scroll.0, scroll.1)
             };
+            set_scroll_info(app_state, SB_VERT, range as i32, page as u32, top as i32);
+            set_scroll_info(app_state, SB_HORZ, h_range as i32, h_page as u32, left as i32);
             if let Some(s) = flash {
                 info!("flash");
                 message_box(app_state, "an editor", &s, MB_OK | MB_ICONINFORMATION);
@@ -820,8 +3251,6 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
             let app_state = &mut get_app_state(hWnd);
             let mut g = app_state.borrow_mut();
             let a = &mut *g;
-            let resources = &a.resources;
-            let view_state = &mut a.view_state;
 
             let render_size = D2D_SIZE_U {
                 width: GET_X_LPARAM(lParam) as u32,
@@ -831,12 +3260,36 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
             if render_size.width == 0 && render_size.height == 0 {
                 info!("minimize");
             } else {
-                let hr = unsafe { resources.render_target.Resize(&render_size) };
-                assert!(hr == S_OK, "0x{:x}", hr);
+                let hr = unsafe { a.resources.render_target.Resize(&render_size) };
+                if hr == D2DERR_RECREATE_TARGET {
+                    // at the new size
+                    recreate_resources(a);
+                } else {
+                    assert!(hr == S_OK, "0x{:x}", hr);
+                }
 
+                let resources = &a.resources;
                 let size = unsafe { resources.render_target.GetSize() };
-                view_state.resize(size.width - PADDING_LEFT, size.height);
+                a.view_state.resize(size.width - PADDING_LEFT, size.height - resources.status_bar_height);
+            }
+            0
+        }
+        WM_DPICHANGED => {
+            info!("WM_DPICHANGED {}", LOWORD(wParam as u32));
+            let app_state = &mut get_app_state(hWnd);
+            {
+                let mut g = app_state.borrow_mut();
+                let a = &mut *g;
+                // the sizes in DIPs stay, but the text is laid out again
+                // for the new pixel grid
+                a.resources = Resources::new(hWnd, &a.d2d_factory, &a.dwrite_factory);
+                a.resources.text_format = create_text_format(&a.dwrite_factory, a.font_size);
+                a.view_state.change_text_format(a.resources.text_format.clone());
             }
+            // keeps the window the same size in DIPs
+            let rect = unsafe { *(lParam as *const RECT) };
+            set_window_rect(app_state, &rect);
+            invalidate_rect(hWnd);
             0
         }
         WM_ENTERMENULOOP => {
@@ -901,13 +3354,46 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
         WM_LBUTTONDOWN => {
             info!("WM_LBUTTONDOWN");
             let app_state = &mut get_app_state(hWnd);
+            let (x, y) = mouse_pos(&app_state.borrow_mut(), lParam);
+            let status_field = status_field_at(&app_state.borrow_mut(), x, y);
+            if let Some(field) = status_field {
+                if let Some(cmd) = field.and_then(StatusField::command) {
+                    handle_menu_command(app_state, cmd as u16);
+                }
+                return 0;
+            }
             let mut app_state = app_state.borrow_mut();
 
-            app_state.left_button_pressed = true;
-            let x = GET_X_LPARAM(lParam);
-            let y = GET_Y_LPARAM(lParam);
+            if app_state.hex.is_some() {
+                let (char_width, row_height) = hex_metrics(&app_state.resources, &app_state.dwrite_factory);
+                let col = ((x - PADDING_LEFT) / char_width).max(0.0) as usize;
+                app_state.hex.as_mut().unwrap().click((y / row_height) as usize, col);
+                invalidate_rect(app_state.hwnd);
+                return 0;
+            }
+            if app_state.mapped.is_some() {
+                return 0;
+            }
+            app_state.completion = None;
             app_state.last_action = ActionType::Other;
-            app_state.view_state.click(x as f32 - PADDING_LEFT, y as f32);
+            app_state.mark_active = false;
+            let gutter_width = app_state.view_state.gutter_width();
+            if x < gutter_width {
+                let line_no = app_state.view_state.line_at(y);
+                app_state.gutter_drag_line = Some(line_no);
+                app_state.view_state.select_lines(line_no, line_no);
+                invalidate_rect(app_state.hwnd);
+                unsafe { SetCapture(hWnd); }
+                return 0;
+            }
+            // left of the diagnostic markers
+            if x < gutter_width + PADDING_LEFT - 4.0 && app_state.view_state.toggle_fold(y) {
+                invalidate_rect(app_state.hwnd);
+                return 0;
+            }
+            app_state.left_button_pressed = true;
+            let left = text_left(&app_state);
+            app_state.view_state.click(x - left, y);
             let shift_pressed = unsafe { GetKeyState(VK_SHIFT) } as u16 & 0x8000 != 0;
             if !shift_pressed {
                 app_state.view_state.clear_selection();
@@ -921,6 +3407,7 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
             let app_state = &mut get_app_state(hWnd);
             let mut app_state = app_state.borrow_mut();
             app_state.left_button_pressed = false;
+            app_state.gutter_drag_line = None;
             let res = unsafe { ReleaseCapture() };
             assert!(res != 0, "{}", Error::last_os_error());
             0
@@ -929,9 +3416,9 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
             info!("WM_LBUTTONDBLCLK");
             let app_state = &mut get_app_state(hWnd);
             let mut app_state = app_state.borrow_mut();
-            let x = GET_X_LPARAM(lParam);
-            let y = GET_Y_LPARAM(lParam);
-            app_state.view_state.double_click(x as f32 - PADDING_LEFT, y as f32);
+            let (x, y) = mouse_pos(&app_state, lParam);
+            let left = text_left(&app_state);
+            app_state.view_state.double_click(x - left, y);
             invalidate_rect(app_state.hwnd);
             0
         }
@@ -939,12 +3426,97 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
             // info!("WM_MOUSEMOVE");
             let app_state = &mut get_app_state(hWnd);
             let mut app_state = app_state.borrow_mut();
-            if app_state.left_button_pressed {
-                let x = GET_X_LPARAM(lParam);
-                let y = GET_Y_LPARAM(lParam);
-                app_state.view_state.click(x as f32 - PADDING_LEFT, y as f32);
+            let (x, y) = mouse_pos(&app_state, lParam);
+            if let Some(anchor_line) = app_state.gutter_drag_line {
+                let line_no = app_state.view_state.line_at(y);
+                app_state.view_state.select_lines(anchor_line, line_no);
+                invalidate_rect(app_state.hwnd);
+            } else if app_state.left_button_pressed {
+                let left = text_left(&app_state);
+                app_state.view_state.click(x - left, y);
+                invalidate_rect(app_state.hwnd);
+            }
+            0
+        }
+        WM_VSCROLL => {
+            let app_state = &mut get_app_state(hWnd);
+            let mut app_state = app_state.borrow_mut();
+            if let Some(hex) = &mut app_state.hex {
+                let (range, top, page) = hex.scroll_position();
+                let top = match LOWORD(wParam as u32) as isize {
+                    SB_LINEUP => top - 1.0,
+                    SB_LINEDOWN => top + 1.0,
+                    SB_PAGEUP => top - page,
+                    SB_PAGEDOWN => top + page,
+                    SB_TOP => 0.0,
+                    SB_BOTTOM => range,
+                    SB_THUMBTRACK | SB_THUMBPOSITION => get_scroll_track_pos(hWnd, SB_VERT) as f32,
+                    _ => return 0,
+                };
+                hex.scroll_to(top);
                 invalidate_rect(app_state.hwnd);
+                return 0;
+            }
+            if let Some(doc) = &mut app_state.mapped {
+                let (range, top, page) = doc.scroll_position();
+                let top = match LOWORD(wParam as u32) as isize {
+                    SB_LINEUP => top - 1.0,
+                    SB_LINEDOWN => top + 1.0,
+                    SB_PAGEUP => top - page,
+                    SB_PAGEDOWN => top + page,
+                    SB_TOP => 0.0,
+                    SB_BOTTOM => range,
+                    SB_THUMBTRACK | SB_THUMBPOSITION => get_scroll_track_pos(hWnd, SB_VERT) as f32,
+                    _ => return 0,
+                };
+                doc.scroll_to(top);
+                invalidate_rect(app_state.hwnd);
+                return 0;
+            }
+            let (range, top, page) = app_state.view_state.scroll_position();
+            match LOWORD(wParam as u32) as isize {
+                SB_LINEUP => app_state.view_state.scroll(1.0),
+                SB_LINEDOWN => app_state.view_state.scroll(-1.0),
+                SB_PAGEUP => app_state.view_state.scroll_to(top - page),
+                SB_PAGEDOWN => app_state.view_state.scroll_to(top + page),
+                SB_TOP => app_state.view_state.scroll_to(0.0),
+                SB_BOTTOM => app_state.view_state.scroll_to(range),
+                SB_THUMBTRACK | SB_THUMBPOSITION => {
+                    let pos = get_scroll_track_pos(hWnd, SB_VERT);
+                    app_state.view_state.scroll_to(pos as f32);
+                }
+                _ => return 0,
+            }
+            invalidate_rect(app_state.hwnd);
+            0
+        }
+        WM_HSCROLL => {
+            let app_state = &mut get_app_state(hWnd);
+            let mut app_state = app_state.borrow_mut();
+            let (range, left, page) = app_state.view_state.h_scroll_position();
+            match LOWORD(wParam as u32) as isize {
+                SB_LINELEFT => app_state.view_state.h_scroll(-3.0),
+                SB_LINERIGHT => app_state.view_state.h_scroll(3.0),
+                SB_PAGELEFT => app_state.view_state.h_scroll_to(left - page),
+                SB_PAGERIGHT => app_state.view_state.h_scroll_to(left + page),
+                SB_LEFT => app_state.view_state.h_scroll_to(0.0),
+                SB_RIGHT => app_state.view_state.h_scroll_to(range),
+                SB_THUMBTRACK | SB_THUMBPOSITION => {
+                    let pos = get_scroll_track_pos(hWnd, SB_HORZ);
+                    app_state.view_state.h_scroll_to(pos as f32);
+                }
+                _ => return 0,
             }
+            invalidate_rect(app_state.hwnd);
+            0
+        }
+        WM_MOUSEHWHEEL => {
+            let delta = GET_WHEEL_DELTA_WPARAM(wParam);
+            info!("WM_MOUSEHWHEEL {}", delta);
+            let app_state = &mut get_app_state(hWnd);
+            let mut app_state = app_state.borrow_mut();
+            app_state.view_state.h_scroll(f32::from(delta) / 120.0 * 3.0);
+            invalidate_rect(app_state.hwnd);
             0
         }
         WM_MOUSEWHEEL => {
@@ -955,7 +3527,11 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
             let mut app_state = app_state.borrow_mut();
 
             let ctrl_pressed = unsafe { GetKeyState(VK_CONTROL) } as u16 & 0x8000 != 0;
-            if ctrl_pressed {
+            let shift_pressed = unsafe { GetKeyState(VK_SHIFT) } as u16 & 0x8000 != 0;
+            if shift_pressed {
+                // wheel up scrolls left
+                app_state.view_state.h_scroll(-f32::from(delta) / 120.0 * 3.0);
+            } else if ctrl_pressed {
                 let delta = f32::from(delta) / 120.0;
                 app_state.font_size += delta;
                 app_state.font_size = app_state.font_size.max(MIN_FONT_SIZE);
@@ -974,7 +3550,12 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
                         0)};
                 assert!(res != 0, "{}", Error::last_os_error());
                 let delta = f32::from(delta) / 120.0 * scroll_lines as f32;
-                app_state.view_state.scroll(delta);
+                let a = &mut *app_state;
+                match (&mut a.hex, &mut a.mapped) {
+                    (Some(hex), _) => hex.scroll(delta),
+                    (_, Some(doc)) => doc.scroll(delta),
+                    _ => a.view_state.scroll(delta),
+                }
             }
             invalidate_rect(app_state.hwnd);
             0
@@ -982,16 +3563,46 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
         WM_CHAR => {
             let c: char = std::char::from_u32(wParam as u32).unwrap();
             info!("WM_CHAR {:?}", c);
-            if wParam >= 32 || wParam == 9 /* tab */ {
-                let app_state = &mut get_app_state(hWnd);
-                let mut app_state = app_state.borrow_mut();
+            let app_state = &mut get_app_state(hWnd);
+            let mut app_state = app_state.borrow_mut();
+            if std::mem::replace(&mut app_state.swallow_char, false) {
+                return 0;
+            }
+            if let Some(hex) = &mut app_state.hex {
+                if wParam >= 32 && !hex.type_char(c) {
+                    unsafe {
+                        MessageBeep(MB_OK);
+                    }
+                }
+                invalidate_rect(app_state.hwnd);
+                app_state.update_title();
+                return 0;
+            }
+            if (wParam >= 32 || wParam == 9 /* tab */) && app_state.check_writable() {
+                app_state.mark_active = false;
                 if app_state.last_action != ActionType::InsertChar {
                     app_state.view_state.make_undo_snapshot();
                     app_state.last_action = ActionType::InsertChar;
                 }
-                app_state.view_state.insert_char(c);
+                let auto_close = app_state.config.auto_close;
+                match app_state.view_state.type_char(c, auto_close) {
+                    Typed::Char => app_state.record(MacroCmd::InsertChar(c)),
+                    Typed::Pair(closer) => {
+                        app_state.record(MacroCmd::InsertChar(c));
+                        app_state.record(MacroCmd::InsertChar(closer));
+                        app_state.record(MacroCmd::Move(Motion::Left));
+                    }
+                    Typed::Skipped => app_state.record(MacroCmd::Move(Motion::Right)),
+                }
                 invalidate_rect(app_state.hwnd);
                 app_state.update_title();
+                after_key(&mut app_state);
+                let n = app_state.config.complete_after;
+                let cursor = app_state.view_state.cursor_pos();
+                if app_state.completion.is_none() && n > 0 && completion::is_word_char(c)
+                    && cursor - app_state.view_state.word_start(cursor) == n {
+                    request_completion(&mut app_state);
+                }
             }
             0
         }
@@ -1000,16 +3611,15 @@ fn my_window_proc(hWnd: HWND, msg: UINT, wParam: WPARAM, lParam: LPARAM) -> LRES
             info!("WM_KEYDOWN {:?}", ke);
             let app_state = &mut get_app_state(hWnd);
             handle_keydown(app_state, ke);
+            after_key(&mut app_state.borrow_mut());
             0
         }
         WM_SYSKEYDOWN => {
             let ke = key_util::KeyEvent::new(wParam, lParam);
             info!("WM_SYSKEYDOWN {:?}", ke);
             let app_state = &mut get_app_state(hWnd);
-            let cmd = app_state.borrow_mut().match_key_event(&ke);
-
-            if let Some(cmd) = cmd {
-                send_message(app_state, WM_COMMAND, cmd as usize, 0);
+            if handle_key_binding(app_state, &ke) {
+                after_key(&mut app_state.borrow_mut());
                 0
             } else {
                 unsafe { DefWindowProcW(hWnd, msg, wParam, lParam) }
@@ -1090,6 +3700,9 @@ fn main() -> Result<(), Error> {
     env_logger::init();
 
     std::panic::set_hook(Box::new(panic_hook));
+    if !enable_per_monitor_dpi_awareness() {
+        info!("no per monitor DPI awareness: {}", Error::last_os_error());
+    }
     let hwnd = create_window("an_editor", "window title", Some(my_window_proc))?;
     unsafe {
         STATIC_HWND = Some(hwnd);
@@ -0,0 +1,243 @@
+// Files too large to edit are viewed read only, straight from the bytes
+// of a memory mapping. Nothing is decoded but the lines on screen.
+// Where the lines start is indexed a slice at a time, so the first
+// screen shows up right away and more of the file becomes reachable
+// as the index grows.
+
+use super::editorconfig::{self, Charset};
+
+// the start of every this many lines is kept
+const INDEX_STEP: usize = 1024;
+// of a line, the rest is not shown
+pub const MAX_LINE_BYTES: usize = 16 << 10;
+
+pub struct MappedDocument<D> {
+    data: D,
+    charset: Option<Charset>,
+    // starts of lines 0, INDEX_STEP, 2 * INDEX_STEP and so on
+    index: Vec<usize>,
+    // how far the data is indexed, and the line breaks before that
+    scanned: usize,
+    scanned_lines: usize,
+    top_line: usize,
+    page_lines: usize,
+    // in chars, for the lines wider than the window
+    left_col: usize,
+}
+
+impl<D: AsRef<[u8]>> MappedDocument<D> {
+    pub fn new(data: D, charset: Option<Charset>) -> MappedDocument<D> {
+        MappedDocument {
+            data,
+            charset,
+            index: vec![0],
+            scanned: 0,
+            scanned_lines: 0,
+            top_line: 0,
+            page_lines: 1,
+            left_col: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.as_ref().len()
+    }
+
+    // Of a line break, in bytes.
+    fn unit(&self) -> usize {
+        match self.charset {
+            Some(Charset::Utf16Be) | Some(Charset::Utf16Le) => 2,
+            _ => 1,
+        }
+    }
+
+    // The first LF in from..to. Lone CRs are not line breaks here.
+    fn find_break(&self, from: usize, to: usize) -> Option<usize> {
+        let data = &self.data.as_ref()[from..to];
+        let lf: &[u8] = match self.charset {
+            Some(Charset::Utf16Be) => &[0, b'\n'],
+            Some(Charset::Utf16Le) => &[b'\n', 0],
+            _ => return data.iter().position(|&b| b == b'\n').map(|i| from + i),
+        };
+        data.chunks_exact(2).position(|c| c == lf).map(|i| from + 2 * i)
+    }
+
+    // Indexes up to max_bytes more (keep it even for UTF-16),
+    // returns true when the whole file is indexed.
+    pub fn index_more(&mut self, max_bytes: usize) -> bool {
+        let end = self.scanned.saturating_add(max_bytes).min(self.size());
+        while let Some(p) = self.find_break(self.scanned, end) {
+            self.scanned = p + self.unit();
+            self.scanned_lines += 1;
+            if self.scanned_lines.is_multiple_of(INDEX_STEP) {
+                self.index.push(self.scanned);
+            }
+        }
+        self.scanned = end;
+        self.indexed()
+    }
+
+    pub fn indexed(&self) -> bool {
+        self.scanned == self.size()
+    }
+
+    pub fn percent(&self) -> usize {
+        (self.scanned * 100).checked_div(self.size()).unwrap_or(100)
+    }
+
+    // Exact once indexed, guessed from the lines so far until then.
+    pub fn num_lines(&self) -> usize {
+        if self.indexed() || self.scanned == 0 {
+            return self.scanned_lines + 1;
+        }
+        let guess = self.scanned_lines as f64 * self.size() as f64 / self.scanned as f64;
+        (guess as usize).max(self.scanned_lines + 1)
+    }
+
+    // The last line that can be shown so far.
+    fn last_known_line(&self) -> usize {
+        self.scanned_lines
+    }
+
+    fn line_start(&self, line: usize) -> usize {
+        assert!(line <= self.last_known_line());
+        let mut start = self.index[line / INDEX_STEP];
+        for _ in 0..line % INDEX_STEP {
+            start = self.find_break(start, self.scanned).unwrap() + self.unit();
+        }
+        start
+    }
+
+    // Without the line break, cut at MAX_LINE_BYTES.
+    fn line_text(&self, start: usize) -> String {
+        let end = (start + MAX_LINE_BYTES).min(self.size());
+        let end = self.find_break(start, end).unwrap_or(end);
+        let (text, _) = editorconfig::decode(&self.data.as_ref()[start..end], self.charset);
+        let text = text.strip_suffix('\r').unwrap_or(&text);
+        // a lone CR would break the line on screen
+        text.replace('\r', " ")
+    }
+
+    // From the top line, one more than fits, so that the last one
+    // can be partly shown.
+    pub fn visible_lines(&self) -> Vec<String> {
+        let last = self.last_known_line().min(self.top_line + self.page_lines);
+        let mut start = self.line_start(self.top_line);
+        let mut lines = Vec::new();
+        for line in self.top_line..=last {
+            lines.push(self.line_text(start).chars().skip(self.left_col).collect());
+            if line < last {
+                start = self.find_break(start, self.scanned).unwrap() + self.unit();
+            }
+        }
+        lines
+    }
+
+    pub fn top_line(&self) -> usize {
+        self.top_line
+    }
+
+    pub fn page_lines(&self) -> usize {
+        self.page_lines
+    }
+
+    pub fn set_page_lines(&mut self, n: usize) {
+        self.page_lines = n.max(1);
+    }
+
+    pub fn left_col(&self) -> usize {
+        self.left_col
+    }
+
+    pub fn set_left_col(&mut self, col: usize) {
+        self.left_col = col.min(MAX_LINE_BYTES);
+    }
+
+    // Returns false if the line is past what's indexed so far.
+    pub fn go_to_line(&mut self, line: usize) -> bool {
+        if line > self.last_known_line() && !self.indexed() {
+            return false;
+        }
+        self.scroll_to(line as f32);
+        true
+    }
+
+    // In lines, positive is up.
+    pub fn scroll(&mut self, delta: f32) {
+        self.scroll_to(self.top_line as f32 - delta.round());
+    }
+
+    // The last page is full once the end of the file is known.
+    pub fn scroll_to(&mut self, top: f32) {
+        let last = if self.indexed() {
+            (self.last_known_line() + 1).saturating_sub(self.page_lines)
+        } else {
+            self.last_known_line()
+        };
+        self.top_line = (top.max(0.0) as usize).min(last);
+    }
+
+    // Range, top and page, in lines.
+    pub fn scroll_position(&self) -> (f32, f32, f32) {
+        (self.num_lines() as f32, self.top_line as f32, self.page_lines as f32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn numbered_lines(n: usize) -> String {
+        (0..n).map(|i| format!("line {}\r\n", i)).collect()
+    }
+
+    #[test]
+    fn indexing() {
+        let text = numbered_lines(3000);
+        let mut doc = MappedDocument::new(text.as_bytes(), None);
+        doc.set_page_lines(2);
+        assert_eq!(doc.visible_lines(), vec!["line 0"]);
+        // not reachable yet
+        assert!(!doc.go_to_line(2500));
+
+        assert!(!doc.index_more(text.len() / 2));
+        assert!(doc.num_lines() > 2900 && doc.num_lines() < 3200);
+        assert!(doc.go_to_line(1200));
+        assert_eq!(doc.visible_lines(), vec!["line 1200", "line 1201", "line 1202"]);
+
+        while !doc.index_more(1000) {}
+        assert_eq!(doc.num_lines(), 3001);
+        assert_eq!(doc.percent(), 100);
+        assert!(doc.go_to_line(2048));
+        assert_eq!(doc.visible_lines(), vec!["line 2048", "line 2049", "line 2050"]);
+        // the empty last line after the last line break ends the last page
+        doc.scroll_to(f32::MAX);
+        assert_eq!(doc.top_line(), 2999);
+        assert_eq!(doc.visible_lines(), vec!["line 2999", ""]);
+        doc.scroll(10.0);
+        assert_eq!(doc.top_line(), 2989);
+
+        doc.set_left_col(5);
+        assert_eq!(doc.visible_lines()[0], "2989");
+    }
+
+    #[test]
+    fn lines() {
+        let mut doc = MappedDocument::new(b"a\rb\n\ncafe\xCC\x81".to_vec(), None);
+        doc.set_page_lines(10);
+        doc.index_more(usize::MAX);
+        assert_eq!(doc.visible_lines(), vec!["a b", "", "cafe\u{301}"]);
+
+        let long = "x".repeat(MAX_LINE_BYTES + 10) + "\ny";
+        let mut doc = MappedDocument::new(long.as_bytes(), None);
+        doc.index_more(usize::MAX);
+        let lines = doc.visible_lines();
+        assert_eq!((lines[0].len(), lines[1].as_str()), (MAX_LINE_BYTES, "y"));
+
+        let utf16: Vec<u8> = "\u{FEFF}\u{A0A}\r\nz".encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+        let mut doc = MappedDocument::new(utf16, Some(Charset::Utf16Le));
+        doc.index_more(2);
+        doc.index_more(usize::MAX);
+        assert_eq!(doc.visible_lines(), vec!["\u{A0A}", "z"]);
+    }
+}
@@ -0,0 +1,192 @@
+// Vertical navigation over visual rows. A line is one or more rows
+// (more when it wraps), the rows can have different heights,
+// and the hidden lines have none.
+
+pub trait Rows {
+    fn num_lines(&self) -> usize;
+    // empty for the hidden lines
+    fn row_heights(&mut self, line_no: usize) -> Vec<f32>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Row {
+    pub line: usize,
+    pub row: usize,
+}
+
+pub fn row_height(rows: &mut impl Rows, r: Row) -> f32 {
+    rows.row_heights(r.line)[r.row]
+}
+
+pub fn next_row(rows: &mut impl Rows, r: Row) -> Option<Row> {
+    if r.row + 1 < rows.row_heights(r.line).len() {
+        return Some(Row { line: r.line, row: r.row + 1 });
+    }
+    (r.line + 1..rows.num_lines())
+        .find(|&i| !rows.row_heights(i).is_empty())
+        .map(|line| Row { line, row: 0 })
+}
+
+pub fn prev_row(rows: &mut impl Rows, r: Row) -> Option<Row> {
+    if r.row > 0 {
+        return Some(Row { line: r.line, row: r.row - 1 });
+    }
+    (0..r.line).rev()
+        .map(|i| (i, rows.row_heights(i).len()))
+        .find(|&(_, n)| n > 0)
+        .map(|(line, n)| Row { line, row: n - 1 })
+}
+
+// By up to n rows, negative is up. Returns the row and the distance
+// from the top of r to its top.
+pub fn move_rows(rows: &mut impl Rows, r: Row, n: isize) -> (Row, f32) {
+    let mut cur = r;
+    let mut dist = 0.0;
+    for _ in 0..n.unsigned_abs() {
+        if n > 0 {
+            let Some(next) = next_row(rows, cur) else { break };
+            dist += row_height(rows, cur);
+            cur = next;
+        } else {
+            let Some(prev) = prev_row(rows, cur) else { break };
+            dist -= row_height(rows, prev);
+            cur = prev;
+        }
+    }
+    (cur, dist)
+}
+
+// The farthest row such that the rows from r to it fit in the page,
+// but at least the next one. The distance is as in move_rows().
+pub fn page_down(rows: &mut impl Rows, r: Row, page: f32) -> (Row, f32) {
+    let (mut cur, mut dist) = move_rows(rows, r, 1);
+    while let Some(next) = next_row(rows, cur) {
+        let top = dist + row_height(rows, cur);
+        if top + row_height(rows, next) > page {
+            break;
+        }
+        cur = next;
+        dist = top;
+    }
+    (cur, dist)
+}
+
+pub fn page_up(rows: &mut impl Rows, r: Row, page: f32) -> (Row, f32) {
+    let (mut cur, mut dist) = move_rows(rows, r, -1);
+    let bottom = row_height(rows, r);
+    while let Some(prev) = prev_row(rows, cur) {
+        let top = dist - row_height(rows, prev);
+        if bottom - top > page {
+            break;
+        }
+        cur = prev;
+        dist = top;
+    }
+    (cur, dist)
+}
+
+// How far the content moves down when scrolling by delta rows (up when
+// negative), each row counts with its own height. The view top is
+// offset pixels below the top of the row.
+pub fn scroll_distance(rows: &mut impl Rows, top: Row, offset: f32, delta: f32) -> f32 {
+    let up = delta > 0.0;
+    let mut left = delta.abs();
+    let mut cur = top;
+    // the part of the row that is yet to scroll by
+    let mut avail = if up { offset } else { row_height(rows, top) - offset };
+    let mut dist = 0.0;
+    loop {
+        let h = row_height(rows, cur);
+        if left * h <= avail {
+            dist += left * h;
+            break;
+        }
+        dist += avail;
+        left -= avail / h;
+        let next = if up { prev_row(rows, cur) } else { next_row(rows, cur) };
+        match next {
+            Some(r) => {
+                cur = r;
+                avail = row_height(rows, r);
+            }
+            None => break,
+        }
+    }
+    if up { dist } else { -dist }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // heights of the rows of each line
+    struct FakeRows(Vec<Vec<f32>>);
+
+    impl Rows for FakeRows {
+        fn num_lines(&self) -> usize {
+            self.0.len()
+        }
+
+        fn row_heights(&mut self, line_no: usize) -> Vec<f32> {
+            self.0[line_no].clone()
+        }
+    }
+
+    fn row(line: usize, row: usize) -> Row {
+        Row { line, row }
+    }
+
+    fn fake() -> FakeRows {
+        // line 2 is hidden, line 3 wraps and has a tall emoji row
+        FakeRows(vec![
+            vec![10.0],
+            vec![20.0],
+            vec![],
+            vec![10.0, 30.0, 10.0],
+            vec![15.0],
+        ])
+    }
+
+    #[test]
+    fn moving() {
+        let mut rows = fake();
+        assert_eq!(next_row(&mut rows, row(1, 0)), Some(row(3, 0)));
+        assert_eq!(prev_row(&mut rows, row(3, 0)), Some(row(1, 0)));
+        assert_eq!(next_row(&mut rows, row(4, 0)), None);
+        assert_eq!(prev_row(&mut rows, row(0, 0)), None);
+
+        assert_eq!(move_rows(&mut rows, row(0, 0), 3), (row(3, 1), 40.0));
+        assert_eq!(move_rows(&mut rows, row(3, 2), -2), (row(3, 0), -40.0));
+        assert_eq!(move_rows(&mut rows, row(3, 2), 5), (row(4, 0), 10.0));
+        assert_eq!(move_rows(&mut rows, row(1, 0), -5), (row(0, 0), -10.0));
+    }
+
+    #[test]
+    fn paging() {
+        let mut rows = fake();
+        // rows at 0, 10, 30, 40, 70, 80
+        assert_eq!(page_down(&mut rows, row(0, 0), 45.0), (row(3, 0), 30.0));
+        assert_eq!(page_down(&mut rows, row(0, 0), 80.0), (row(3, 2), 70.0));
+        // at least one row even if it doesn't fit
+        assert_eq!(page_down(&mut rows, row(3, 0), 5.0), (row(3, 1), 10.0));
+        assert_eq!(page_down(&mut rows, row(4, 0), 100.0), (row(4, 0), 0.0));
+
+        assert_eq!(page_up(&mut rows, row(4, 0), 60.0), (row(3, 1), -40.0));
+        assert_eq!(page_up(&mut rows, row(3, 1), 1000.0), (row(0, 0), -40.0));
+        assert_eq!(page_up(&mut rows, row(0, 0), 100.0), (row(0, 0), 0.0));
+    }
+
+    #[test]
+    fn scrolling() {
+        let mut rows = fake();
+        // the view top is in the middle of the tall row
+        assert_eq!(scroll_distance(&mut rows, row(3, 1), 15.0, 0.5), 15.0);
+        assert_eq!(scroll_distance(&mut rows, row(3, 1), 15.0, 1.0), 20.0);
+        assert_eq!(scroll_distance(&mut rows, row(3, 1), 15.0, 2.0), 35.0);
+        assert_eq!(scroll_distance(&mut rows, row(3, 1), 15.0, -1.0), -20.0);
+        assert_eq!(scroll_distance(&mut rows, row(3, 1), 15.0, -3.0), -40.0);
+        // stops at the ends of the document
+        assert_eq!(scroll_distance(&mut rows, row(1, 0), 0.0, 10.0), 10.0);
+        assert_eq!(scroll_distance(&mut rows, row(4, 0), 5.0, -10.0), -10.0);
+    }
+}
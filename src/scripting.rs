@@ -0,0 +1,226 @@
+// User scripts in Rhai (https://rhai.rs).
+//
+// Every *.rhai file in the scripts directory is run once at startup.
+// At the top level a script can register commands:
+//
+//     register_command("Upper case", "upper");
+//     register_command("Sort lines", "sort_lines", "Ctrl+Shift+L");
+//
+//     fn upper(editor) {
+//         let s = editor.selection_start();
+//         let e = editor.selection_end();
+//         let text = editor.slice(s, e);
+//         text.make_upper();
+//         editor.replace(s, e, text);
+//     }
+//
+// Positions are in chars, like everywhere in ViewState.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::rc::Rc;
+
+use log::info;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST, INT};
+
+use super::view_state::ViewState;
+
+// Handle to the view state that script commands receive.
+// It's only valid while the command runs (see ScriptHost::run_command()),
+// after that it's nulled, so that a stashed handle can't do any harm.
+#[derive(Clone)]
+struct Editor(Rc<Cell<*mut ViewState>>);
+
+impl Editor {
+    fn with<R>(&self, f: impl FnOnce(&mut ViewState) -> R) -> Result<R, Box<EvalAltResult>> {
+        let p = self.0.get();
+        if p.is_null() {
+            return Err("editor is only available while the command runs".into());
+        }
+        Ok(f(unsafe { &mut *p }))
+    }
+
+    fn try_with<R>(
+        &self,
+        f: impl FnOnce(&mut ViewState) -> Result<R, Box<EvalAltResult>>,
+    ) -> Result<R, Box<EvalAltResult>> {
+        self.with(f)?
+    }
+}
+
+fn to_pos(view_state: &ViewState, pos: INT) -> Result<usize, Box<EvalAltResult>> {
+    if pos < 0 || pos as usize > view_state.document_len() {
+        return Err(format!("position {} is out of range", pos).into());
+    }
+    Ok(pos as usize)
+}
+
+fn to_range(view_state: &ViewState, start: INT, end: INT) -> Result<(usize, usize), Box<EvalAltResult>> {
+    let start = to_pos(view_state, start)?;
+    let end = to_pos(view_state, end)?;
+    if start > end {
+        return Err(format!("bad range {}..{}", start, end).into());
+    }
+    Ok((start, end))
+}
+
+pub struct ScriptCommand {
+    pub title: String,
+    pub key: Option<String>,
+    script: usize,
+    fn_name: String,
+}
+
+// A script stuck in a loop or a runaway recursion is stopped
+// with an error instead of hanging or crashing the editor.
+const MAX_OPERATIONS: u64 = 10_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FUNCTION_EXPR_DEPTH: usize = 32;
+
+#[derive(Default)]
+struct Registrations {
+    current_script: usize,
+    commands: Vec<ScriptCommand>,
+}
+
+pub struct ScriptHost {
+    engine: Engine,
+    scripts: Vec<AST>,
+    pub commands: Vec<ScriptCommand>,
+}
+
+fn register_editor_api(engine: &mut Engine) {
+    engine.register_type_with_name::<Editor>("Editor");
+
+    engine.register_fn("content", |e: &mut Editor| e.with(|vs| vs.content()));
+    engine.register_fn("len", |e: &mut Editor| e.with(|vs| vs.document_len() as INT));
+    engine.register_fn("cursor", |e: &mut Editor| e.with(|vs| vs.cursor_pos() as INT));
+    engine.register_fn("selection_start", |e: &mut Editor| {
+        e.with(|vs| vs.selection_range().0 as INT)
+    });
+    engine.register_fn("selection_end", |e: &mut Editor| {
+        e.with(|vs| vs.selection_range().1 as INT)
+    });
+    engine.register_fn("selection", |e: &mut Editor| e.with(|vs| vs.get_selection()));
+    engine.register_fn("set_cursor", |e: &mut Editor, pos: INT| {
+        e.try_with(|vs| {
+            let pos = to_pos(vs, pos)?;
+            vs.set_selection(pos, pos);
+            Ok(())
+        })
+    });
+    engine.register_fn("select", |e: &mut Editor, start: INT, end: INT| {
+        e.try_with(|vs| {
+            let (start, end) = to_range(vs, start, end)?;
+            vs.set_selection(start, end);
+            Ok(())
+        })
+    });
+    engine.register_fn("slice", |e: &mut Editor, start: INT, end: INT| {
+        e.try_with(|vs| {
+            let (start, end) = to_range(vs, start, end)?;
+            Ok(vs.slice_string(start, end))
+        })
+    });
+    engine.register_fn("replace", |e: &mut Editor, start: INT, end: INT, text: &str| {
+        e.try_with(|vs| {
+            let (start, end) = to_range(vs, start, end)?;
+            vs.replace_text(start, end, text);
+            Ok(())
+        })
+    });
+    engine.register_fn("insert", |e: &mut Editor, text: &str| e.with(|vs| vs.paste(text)));
+}
+
+impl ScriptHost {
+    // Runs all *.rhai files from the directory in alphabetical order.
+    // Scripts that fail are skipped, the error messages are returned.
+    pub fn load(dir: Option<&Path>) -> (ScriptHost, Vec<String>) {
+        let registrations = Rc::new(RefCell::new(Registrations::default()));
+
+        let mut engine = Engine::new();
+        engine.on_print(|s| info!("script: {}", s));
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FUNCTION_EXPR_DEPTH);
+        register_editor_api(&mut engine);
+        {
+            let r = registrations.clone();
+            engine.register_fn("register_command", move |title: &str, fn_name: &str| {
+                let mut r = r.borrow_mut();
+                let script = r.current_script;
+                r.commands.push(ScriptCommand {
+                    title: title.to_owned(),
+                    key: None,
+                    script,
+                    fn_name: fn_name.to_owned(),
+                });
+            });
+            let r = registrations.clone();
+            engine.register_fn("register_command", move |title: &str, fn_name: &str, key: &str| {
+                let mut r = r.borrow_mut();
+                let script = r.current_script;
+                r.commands.push(ScriptCommand {
+                    title: title.to_owned(),
+                    key: Some(key.to_owned()),
+                    script,
+                    fn_name: fn_name.to_owned(),
+                });
+            });
+        }
+
+        let mut scripts = Vec::new();
+        let mut errors = Vec::new();
+        let mut paths: Vec<_> = match dir.map(std::fs::read_dir) {
+            Some(Ok(entries)) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension() == Some(OsStr::new("rhai")))
+                .collect(),
+            _ => Vec::new(),
+        };
+        paths.sort();
+        for path in paths {
+            info!("running {}", path.to_string_lossy());
+            let num_commands = registrations.borrow().commands.len();
+            registrations.borrow_mut().current_script = scripts.len();
+            let res = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|source| engine.compile(&source).map_err(|e| e.to_string()))
+                .and_then(|ast| {
+                    engine.run_ast_with_scope(&mut Scope::new(), &ast)
+                        .map(|()| ast)
+                        .map_err(|e| e.to_string())
+                });
+            match res {
+                Ok(ast) => scripts.push(ast),
+                Err(e) => {
+                    registrations.borrow_mut().commands.truncate(num_commands);
+                    errors.push(format!("{}: {}", path.to_string_lossy(), e));
+                }
+            }
+        }
+
+        let commands = std::mem::take(&mut registrations.borrow_mut().commands);
+        (ScriptHost { engine, scripts, commands }, errors)
+    }
+
+    // It's up to the caller to make an undo snapshot,
+    // so that the whole command is undone in one step.
+    pub fn run_command(&self, idx: usize, view_state: &mut ViewState) -> Result<(), String> {
+        let cmd = &self.commands[idx];
+        let p = Rc::new(Cell::new(view_state as *mut ViewState));
+        let res = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new().eval_ast(false),
+            &mut Scope::new(),
+            &self.scripts[cmd.script],
+            &cmd.fn_name,
+            (Editor(p.clone()),),
+        );
+        p.set(null_mut());
+        res.map(|_| ()).map_err(|e| format!("{}: {}", cmd.title, e))
+    }
+}
@@ -0,0 +1,370 @@
+// TextMate-style snippets: "$1", "${2:default}", "$0" for the final
+// cursor position. Tab stops with the same number mirror each other.
+//
+// Snippets for files with extension "rs" are read from snippets\rs.snippets
+// in the config dir, in the snipMate format:
+//     # comment
+//     snippet fn
+//         fn ${1:name}(${2}) {
+//             $0
+//         }
+// Body lines are indented with a tab or four spaces, which is removed.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::diff::Edit;
+
+pub struct Snippet {
+    pub trigger: String,
+    pub body: String,
+}
+
+pub fn parse_snippets(s: &str) -> Result<Vec<Snippet>, String> {
+    let mut result: Vec<Snippet> = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        if let Some(trigger) = line.strip_prefix("snippet ") {
+            let trigger = trigger.trim();
+            if trigger.is_empty() {
+                return Err(format!("line {}: no trigger", i + 1));
+            }
+            result.push(Snippet { trigger: trigger.to_owned(), body: String::new() });
+            continue;
+        }
+        let body_line = line.strip_prefix('\t').or_else(|| line.strip_prefix("    "));
+        match (body_line, result.last_mut()) {
+            (Some(body_line), Some(snippet)) => {
+                if !snippet.body.is_empty() {
+                    snippet.body.push('\n');
+                }
+                snippet.body.push_str(body_line);
+            }
+            _ => if !line.trim().is_empty() && !line.starts_with('#') {
+                return Err(format!("line {}: expected 'snippet <trigger>' or indented body", i + 1));
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Keyed by lowercase extension.
+pub fn load_snippets(dir: &Path) -> (HashMap<String, Vec<Snippet>>, Vec<String>) {
+    let mut snippets = HashMap::new();
+    let mut errors = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return (snippets, errors),
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().map(|e| e == "snippets") != Some(true) {
+            continue;
+        }
+        let ext = path.file_stem().unwrap().to_string_lossy().to_lowercase();
+        let res = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| parse_snippets(&s));
+        match res {
+            Ok(s) => {
+                snippets.insert(ext, s);
+            }
+            Err(e) => errors.push(format!("{}: {}", path.to_string_lossy(), e)),
+        }
+    }
+    (snippets, errors)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TabStop {
+    pub number: usize,
+    // char ranges, the first one is where the cursor goes
+    pub ranges: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Expansion {
+    pub text: String,
+    // in the order of visiting, $0 is the last
+    pub tab_stops: Vec<TabStop>,
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    i: usize,
+    indent: &'a str,
+    // text of the first placeholder with each number, to fill the mirrors
+    defaults: HashMap<usize, String>,
+    text: String,
+    len: usize,
+    ranges: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> Parser<'a> {
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.len += 1;
+        if c == '\n' {
+            self.text.push_str(self.indent);
+            self.len += self.indent.chars().count();
+        }
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.i;
+        while self.i < self.chars.len() && self.chars[self.i].is_ascii_digit() {
+            self.i += 1;
+        }
+        let s: String = self.chars[start..self.i].iter().collect();
+        s.parse().ok()
+    }
+
+    // Until the closing brace if nested, otherwise until the end.
+    fn parse(&mut self, nested: bool) -> Result<(), String> {
+        while self.i < self.chars.len() {
+            let c = self.chars[self.i];
+            let next = self.chars.get(self.i + 1).cloned();
+            self.i += 1;
+            match c {
+                '\\' if matches!(next, Some('$') | Some('}') | Some('\\')) => {
+                    self.push(next.unwrap());
+                    self.i += 1;
+                }
+                '}' if nested => return Ok(()),
+                '$' if next.map(|c| c.is_ascii_digit()) == Some(true) => {
+                    let n = self.number().unwrap();
+                    self.stop(n, false)?;
+                }
+                '$' if next == Some('{') => {
+                    self.i += 1;
+                    let n = self.number().ok_or("expected tab stop number after '${'")?;
+                    match self.chars.get(self.i) {
+                        Some('}') => {
+                            self.i += 1;
+                            self.stop(n, false)?;
+                        }
+                        Some(':') => {
+                            self.i += 1;
+                            self.stop(n, true)?;
+                        }
+                        _ => return Err(format!("expected '}}' or ':' after '${{{}'", n)),
+                    }
+                }
+                _ => self.push(c),
+            }
+        }
+        if nested {
+            return Err("unclosed '${'".to_owned());
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, n: usize, with_default: bool) -> Result<(), String> {
+        let start = self.len;
+        let text_start = self.text.len();
+        if with_default {
+            self.parse(true)?;
+        }
+        if start == self.len {
+            if let Some(d) = self.defaults.get(&n).cloned() {
+                self.text.push_str(&d);
+                self.len += d.chars().count();
+            }
+        } else {
+            let d = self.text[text_start..].to_owned();
+            self.defaults.entry(n).or_insert(d);
+        }
+        self.ranges.push((n, start, self.len));
+        Ok(())
+    }
+}
+
+// Lines after the first one get the indent.
+pub fn expand(body: &str, indent: &str) -> Result<Expansion, String> {
+    let mut defaults = HashMap::new();
+    // first pass finds the defaults for the mirrors that come before them
+    for _ in 0..2 {
+        let mut p = Parser {
+            chars: body.chars().collect(),
+            i: 0,
+            indent,
+            defaults,
+            text: String::new(),
+            len: 0,
+            ranges: Vec::new(),
+        };
+        p.parse(false)?;
+        defaults = p.defaults;
+        if defaults.is_empty() || p.ranges.iter().all(|&(n, s, e)| s < e || !defaults.contains_key(&n)) {
+            let mut tab_stops: Vec<TabStop> = Vec::new();
+            for (n, s, e) in p.ranges {
+                match tab_stops.iter_mut().find(|t| t.number == n) {
+                    Some(t) => t.ranges.push((s, e)),
+                    None => tab_stops.push(TabStop { number: n, ranges: vec![(s, e)] }),
+                }
+            }
+            if tab_stops.iter().all(|t| t.number != 0) {
+                tab_stops.push(TabStop { number: 0, ranges: vec![(p.len, p.len)] });
+            }
+            // 0 is the last one
+            tab_stops.sort_by_key(|t| if t.number == 0 { usize::MAX } else { t.number });
+            return Ok(Expansion { text: p.text, tab_stops });
+        }
+    }
+    unreachable!()
+}
+
+// Where a range ends up after start..end is replaced with new_len chars.
+// Insertions at the range boundaries become part of it, that's how typing
+// in a placeholder grows it.
+fn adjust_range(r: (usize, usize), start: usize, end: usize, new_len: usize) -> (usize, usize) {
+    let (s, e) = r;
+    if end < s || (end == s && start < end) {
+        (s + new_len - (end - start), e + new_len - (end - start))
+    } else if start > e || (start == e && start < end) {
+        (s, e)
+    } else {
+        let e = if e >= end { e + new_len - (end - start) } else { start + new_len };
+        (s.min(start), e)
+    }
+}
+
+// Tab stops of an inserted snippet, they follow the edits.
+pub struct Session {
+    tab_stops: Vec<TabStop>,
+    current: usize,
+    extent: (usize, usize),
+}
+
+impl Session {
+    pub fn new(pos: usize, e: Expansion) -> Session {
+        let tab_stops = e.tab_stops.into_iter()
+            .map(|t| TabStop {
+                number: t.number,
+                ranges: t.ranges.iter().map(|&(s, e)| (s + pos, e + pos)).collect(),
+            })
+            .collect();
+        Session {
+            tab_stops,
+            current: 0,
+            extent: (pos, pos + e.text.chars().count()),
+        }
+    }
+
+    pub fn current_range(&self) -> (usize, usize) {
+        self.tab_stops[self.current].ranges[0]
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tab_stops[self.current].number == 0
+    }
+
+    // Returns false if there is no tab stop in that direction.
+    pub fn advance(&mut self, forward: bool) -> bool {
+        if forward && self.current + 1 < self.tab_stops.len() {
+            self.current += 1;
+            true
+        } else if !forward && self.current > 0 {
+            self.current -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn contains(&self, pos: usize) -> bool {
+        self.extent.0 <= pos && pos <= self.extent.1
+    }
+
+    pub fn adjust(&mut self, start: usize, end: usize, new_len: usize) {
+        for t in &mut self.tab_stops {
+            for r in &mut t.ranges {
+                *r = adjust_range(*r, start, end, new_len);
+            }
+        }
+        self.extent = adjust_range(self.extent, start, end, new_len);
+    }
+
+    // Edits that copy the current placeholder to its mirrors, sorted.
+    pub fn mirror_edits(&self, slice: impl Fn(usize, usize) -> String) -> Vec<Edit> {
+        let ranges = &self.tab_stops[self.current].ranges;
+        let (s, e) = ranges[0];
+        let text = slice(s, e);
+        let mut edits: Vec<Edit> = ranges[1..].iter()
+            .filter(|&&(s, e)| slice(s, e) != text)
+            .map(|&(start, end)| Edit { start, end, text: text.clone() })
+            .collect();
+        edits.sort_by_key(|e| e.start);
+        edits
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expansion() {
+        let e = expand("fn ${1:name}($2) {\n    $0\n}", "  ").unwrap();
+        assert_eq!(e.text, "fn name() {\n      \n  }");
+        assert_eq!(e.tab_stops, vec![
+            TabStop { number: 1, ranges: vec![(3, 7)] },
+            TabStop { number: 2, ranges: vec![(8, 8)] },
+            TabStop { number: 0, ranges: vec![(18, 18)] },
+        ]);
+
+        // mirrors, nesting, escapes, implicit $0
+        let e = expand("$1 ${1:x} ${2:a ${3:b}} \\$4 $", "").unwrap();
+        assert_eq!(e.text, "x x a b $4 $");
+        assert_eq!(e.tab_stops, vec![
+            TabStop { number: 1, ranges: vec![(0, 1), (2, 3)] },
+            TabStop { number: 2, ranges: vec![(4, 7)] },
+            TabStop { number: 3, ranges: vec![(6, 7)] },
+            TabStop { number: 0, ranges: vec![(12, 12)] },
+        ]);
+
+        assert!(expand("${1:oops", "").is_err());
+        assert!(expand("${x}", "").is_err());
+    }
+
+    #[test]
+    fn session() {
+        let mut doc: Vec<char> = "let x = 1;\n".chars().collect();
+        let e = expand("${1:a} + $1", "").unwrap();
+        doc.splice(8..9, e.text.chars());
+        let mut session = Session::new(8, e);
+        assert_eq!(session.current_range(), (8, 9));
+
+        // select the placeholder and type over it
+        let edit = |session: &mut Session, doc: &mut Vec<char>, start, end, text: &str| {
+            doc.splice(start..end, text.chars());
+            session.adjust(start, end, text.chars().count());
+        };
+        edit(&mut session, &mut doc, 8, 9, "");
+        edit(&mut session, &mut doc, 8, 8, "b");
+        edit(&mut session, &mut doc, 9, 9, "c");
+        let slice = |doc: &Vec<char>, s: usize, e: usize| doc[s..e].iter().collect::<String>();
+        let edits = session.mirror_edits(|s, e| slice(&doc, s, e));
+        assert_eq!(edits, vec![Edit { start: 13, end: 14, text: "bc".to_owned() }]);
+        edit(&mut session, &mut doc, 13, 14, "bc");
+        assert_eq!(slice(&doc, 0, doc.len()), "let x = bc + bc;\n");
+        assert!(session.mirror_edits(|s, e| slice(&doc, s, e)).is_empty());
+
+        assert!(session.contains(14));
+        assert!(!session.contains(16));
+        assert!(session.advance(true));
+        assert!(session.is_finished());
+        assert_eq!(session.current_range(), (15, 15));
+        assert!(!session.advance(true));
+    }
+
+    #[test]
+    fn snippet_file() {
+        let s = "# comment\nsnippet fn\n\tfn $1() {\n\t    $0\n\t}\n\nsnippet p\n    println!(\"$1\");\n";
+        let snippets = parse_snippets(s).unwrap();
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(snippets[0].trigger, "fn");
+        assert_eq!(snippets[0].body, "fn $1() {\n    $0\n}");
+        assert_eq!(snippets[1].body, "println!(\"$1\");");
+        assert!(parse_snippets("oops\n").is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::ptr::null_mut;
 
 use winapi::shared::winerror::{S_OK, HRESULT_FROM_WIN32, ERROR_INSUFFICIENT_BUFFER};
 use winapi::um::dwrite::*;
+use winapi::um::d2d1::*;
 
 use super::com_ptr::ComPtr;
 
@@ -12,6 +13,8 @@ pub struct TextLayout {
     pub line_height: f32,
     line_metrics: Vec<DWRITE_LINE_METRICS>,
     len: usize,
+    // rows after the first are shifted right by that much
+    indent: f32,
 }
 
 impl TextLayout {
@@ -82,6 +85,48 @@ impl TextLayout {
             line_height: ht_metrics.height,
             line_metrics,
             len: text.len() - 1,
+            indent: 0.0,
+        }
+    }
+
+    // The layout has to be made narrower by the indent beforehand.
+    pub fn set_hanging_indent(&mut self, indent: f32) {
+        if self.line_metrics.len() > 1 {
+            self.indent = indent;
+            self.width += indent;
+        }
+    }
+
+    fn row_shift(&self, y: f32) -> f32 {
+        if y >= self.line_metrics[0].height * 0.5 { self.indent } else { 0.0 }
+    }
+
+    pub fn draw(&self, rt: &ComPtr<ID2D1HwndRenderTarget>, x: f32, y: f32, brush: &ComPtr<ID2D1Brush>) {
+        if self.indent == 0.0 {
+            unsafe {
+                rt.DrawTextLayout(
+                    D2D1_POINT_2F { x, y },
+                    self.raw.as_raw(),
+                    brush.as_raw(),
+                    D2D1_DRAW_TEXT_OPTIONS_NONE,
+                );
+            }
+            return;
+        }
+        // the first row in place and the rest shifted, each clipped to its part
+        let first_height = self.line_metrics[0].height;
+        for &(shift, top, bottom) in &[(0.0, y, y + first_height), (self.indent, y + first_height, y + self.height)] {
+            let clip = D2D1_RECT_F { left: -1e9, top, right: 1e9, bottom };
+            unsafe {
+                rt.PushAxisAlignedClip(&clip, D2D1_ANTIALIAS_MODE_ALIASED);
+                rt.DrawTextLayout(
+                    D2D1_POINT_2F { x: x + shift, y },
+                    self.raw.as_raw(),
+                    brush.as_raw(),
+                    D2D1_DRAW_TEXT_OPTIONS_NONE,
+                );
+                rt.PopAxisAlignedClip();
+            }
         }
     }
 
@@ -98,7 +143,7 @@ impl TextLayout {
             );
             assert!(hr == S_OK, "0x{:x}", hr);
         }
-        (x, y)
+        (x + self.row_shift(y), y)
     }
 
     pub fn cursor_coords_trailing(&self, pos: usize) -> (f32, f32) {
@@ -115,7 +160,7 @@ impl TextLayout {
             );
             assert!(hr == S_OK, "0x{:x}", hr);
         }
-        (x, y)
+        (x + self.row_shift(y), y)
     }
 
     pub fn coords_to_pos(&self, x: f32, y: f32) -> usize {
@@ -124,7 +169,7 @@ impl TextLayout {
         let mut metrics = unsafe { std::mem::zeroed() };
         unsafe {
             let hr = self.raw.HitTestPoint(
-                x, y, &mut is_trailing_hit, &mut is_inside, &mut metrics);
+                x - self.row_shift(y), y, &mut is_trailing_hit, &mut is_inside, &mut metrics);
             assert!(hr == S_OK, "0x{:x}", hr);
         }
         metrics.textPosition as usize + is_trailing_hit as usize
@@ -139,6 +184,16 @@ impl TextLayout {
         result
     }
 
+    pub fn row_heights(&self) -> Vec<f32> {
+        self.line_metrics.iter().map(|lm| lm.height).collect()
+    }
+
+    // At a wrap boundary it's the row that starts there, like cursor_coords().
+    pub fn row_of(&self, pos: usize) -> usize {
+        let bounds = self.line_boundaries();
+        bounds[1..bounds.len() - 1].iter().filter(|&&b| b <= pos).count()
+    }
+
     pub fn get_selection_rects(&self, start_pos: usize, end_pos: usize) -> Vec<(f32, f32, f32, f32)> {
         assert!(start_pos <= end_pos && end_pos <= self.len + 1);
         let mut metrics = vec![unsafe { std::mem::zeroed() }; self.line_metrics.len()];
@@ -157,7 +212,7 @@ impl TextLayout {
         assert!(hr == S_OK, "0x{:x}", hr);
         metrics.truncate(actual_count as usize);
 
-        let mut result: Vec<_> = metrics.into_iter().map(|m| { (m.left, m.top, m.width, m.height) }).collect();
+        let mut result: Vec<_> = metrics.into_iter().map(|m| (m.left + self.row_shift(m.top), m.top, m.width, m.height)).collect();
         if end_pos == self.len + 1 {
             result.last_mut().unwrap().2 += self.line_height * 0.5;
         }
@@ -1,11 +1,21 @@
 use std::ptr::null_mut;
 
+use log::debug;
+use winapi::shared::winerror::S_OK;
 use winapi::um::dwrite::*;
 use winapi::um::d2d1::*;
 
 use super::com_ptr::ComPtr;
 use super::text_layout::TextLayout;
 use super::line_gap_buffer::{Line, LineGapBuffer};
+use super::diff::Edit;
+use super::lsp::{self, Position, Severity, TextChange};
+use super::completion::{self, WordRanker};
+use super::snippet::{self, Expansion};
+use super::indent::{self, IndentStyle};
+use super::brackets::{self, LineScan, ScanState, Syntax};
+use super::rows::{self, Row, Rows};
+use super::layout_cache::LayoutCache;
 
 #[derive(Debug)]
 struct SliceEdit {
@@ -20,13 +30,131 @@ struct UndoSnapshot {
     cursor_pos: usize,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Motion {
+    Left,
+    Right,
+    CtrlLeft,
+    CtrlRight,
+    Home,
+    End,
+    CtrlHome,
+    CtrlEnd,
+    Up,
+    Down,
+    PgUp,
+    PgDown,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiagnosticSource {
+    Lsp,
+    Build,
+}
+
+// Char range in the document, it follows the edits.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub start: usize,
+    pub end: usize,
+    pub severity: Severity,
+    pub message: String,
+    pub source: DiagnosticSource,
+    // index in the list it came from
+    pub id: usize,
+}
+
+// Where a position ends up after start..end is replaced with new_len chars.
+// Positions inside the replaced range keep their offset if possible.
+fn adjust_pos(pos: usize, start: usize, end: usize, new_len: usize) -> usize {
+    if pos <= start {
+        pos
+    } else if pos >= end {
+        pos - (end - start) + new_len
+    } else {
+        pos.min(start + new_len)
+    }
+}
+
+// Per-line caches, LineGapBuffer resets them when the line changes.
+#[derive(Default)]
+struct LineData {
+    layout: Option<TextLayout>,
+    // for the layout cache eviction
+    layout_used: u64,
+    // for word completion
+    words: Option<Vec<String>>,
+    // for bracket matching
+    scan: Option<LineScan>,
+    // number of lines hidden after this one, if it's folded
+    folded: Option<usize>,
+    // inside a fold, takes no space on screen
+    hidden: bool,
+}
+
+// In lines.
+pub const DEFAULT_LAYOUT_CACHE_CAPACITY: usize = 5000;
+
+// Don't look for the matching bracket further than that,
+// nor scan further back than that for it.
+const MAX_BRACKET_SCAN_LINES: usize = 10000;
+
+// Word completion looks at the lines this far from the cursor,
+// words further away hardly count anyway.
+const WORD_COMPLETION_LINES: usize = 2000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    // distance from the cursor line, except for the cursor line itself
+    Relative,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Wrap {
+    // long lines scroll horizontally
+    None,
+    Window,
+    // in digit widths
+    Column(usize),
+}
+
+// Layout width for the lines that don't wrap.
+const NO_WRAP_WIDTH: f32 = 1e6;
+
+// Around the line numbers.
+const GUTTER_MARGIN: f32 = 6.0;
+// Between the gutter and the text, for the fold and diagnostic markers.
+pub const MARKERS_WIDTH: f32 = 16.0;
+
+// What typing a char did, for macro recording.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Typed {
+    Char,
+    // the char and the auto-inserted closer, the cursor is between them
+    Pair(char),
+    // moved over the auto-inserted closer
+    Skipped,
+}
+
+#[derive(Clone, Copy)]
+pub struct Brushes<'a> {
+    pub text: &'a ComPtr<ID2D1Brush>,
+    pub selection: &'a ComPtr<ID2D1Brush>,
+    pub current_line: &'a ComPtr<ID2D1Brush>,
+    pub gutter: &'a ComPtr<ID2D1Brush>,
+    // indexed by Severity
+    pub diagnostics: &'a [ComPtr<ID2D1Brush>],
+}
+
 pub struct ViewState {
     width: f32,
     height: f32,
     text_format: ComPtr<IDWriteTextFormat>,
     dwrite_factory: ComPtr<IDWriteFactory>,
 
-    document: LineGapBuffer<Option<TextLayout>>,
+    document: LineGapBuffer<LineData>,
     cursor_pos: usize,
     selection_pos: usize,
 
@@ -43,6 +171,44 @@ pub struct ViewState {
     redo_snapshots: Vec<UndoSnapshot>,
 
     unmodified_snapshot: Option<usize>,
+
+    // edits for the language server, if it's listening
+    changes: Option<Vec<TextChange>>,
+    diagnostics: Vec<Diagnostic>,
+    // tab stops of the snippet being filled in
+    snippet: Option<snippet::Session>,
+
+    pub indent_style: IndentStyle,
+    // in spaces, None for the DirectWrite default
+    tab_width: Option<usize>,
+    default_tab_stop: f32,
+
+    syntax: Syntax,
+    // Lines scan_start..scanned_lines have up to date scans. Past 0,
+    // the state at scan_start is a guess, so that a jump far into
+    // the document doesn't scan everything above.
+    scan_start: usize,
+    scanned_lines: usize,
+    // positions of auto-inserted closers that can be typed over
+    auto_closed: Vec<usize>,
+
+    line_numbers: LineNumbers,
+    digit_width: f32,
+    // in the number of lines, the gutter width depends on it
+    gutter_digits: usize,
+
+    wrap: Wrap,
+    // wrapped rows keep the leading whitespace of the line
+    hanging_indent: bool,
+    // horizontal scroll offset of the text area
+    scroll_x: f32,
+
+    layout_cache: LayoutCache,
+    // inside folds
+    hidden_lines: usize,
+    // of the laid out lines, None when the widest one was dropped
+    // and it has to be found again
+    widest_layout: Option<f32>,
 }
 
 impl ViewState {
@@ -52,6 +218,8 @@ impl ViewState {
         text_format: ComPtr<IDWriteTextFormat>,
         dwrite_factory: ComPtr<IDWriteFactory>,
     ) -> ViewState {
+        let default_tab_stop = unsafe { text_format.GetIncrementalTabStop() };
+        let digit_width = TextLayout::new("0", &dwrite_factory, &text_format, 1e6).width;
         ViewState {
             width,
             height,
@@ -68,13 +236,151 @@ impl ViewState {
             redo_slice_edits: Vec::new(),
             redo_snapshots: Vec::new(),
             unmodified_snapshot: Some(0),
+            changes: None,
+            diagnostics: Vec::new(),
+            snippet: None,
+            indent_style: IndentStyle::Spaces(4),
+            tab_width: None,
+            default_tab_stop,
+            syntax: brackets::syntax_for(""),
+            scan_start: 0,
+            scanned_lines: 0,
+            auto_closed: Vec::new(),
+            line_numbers: LineNumbers::Off,
+            digit_width,
+            gutter_digits: 1,
+            wrap: Wrap::Window,
+            hanging_indent: false,
+            scroll_x: 0.0,
+            layout_cache: LayoutCache::new(DEFAULT_LAYOUT_CACHE_CAPACITY),
+            hidden_lines: 0,
+            widest_layout: Some(0.0),
         }
     }
 
     pub fn change_text_format(&mut self, text_format: ComPtr<IDWriteTextFormat>) {
+        self.default_tab_stop = unsafe { text_format.GetIncrementalTabStop() };
+        self.digit_width = TextLayout::new("0", &self.dwrite_factory, &text_format, 1e6).width;
         self.text_format = text_format;
+        self.set_tab_width(self.tab_width);
+    }
+
+    pub fn set_tab_width(&mut self, tab_width: Option<usize>) {
+        self.tab_width = tab_width;
+        let tab_stop = match tab_width {
+            Some(n) => TextLayout::new(&" ".repeat(n), &self.dwrite_factory, &self.text_format, 1e6).width,
+            None => self.default_tab_stop,
+        };
+        let hr = unsafe { self.text_format.SetIncrementalTabStop(tab_stop) };
+        assert!(hr == S_OK, "0x{:x}", hr);
+        self.invalidate_layouts();
+    }
+
+    // Tab width in columns, the default tab stop is about 4.
+    fn tab_columns(&self) -> usize {
+        self.tab_width.unwrap_or(4)
+    }
+
+    fn invalidate_layouts(&mut self) {
         for i in 0..self.document.num_lines() {
-            *self.document.get_line_mut(i).data = None;
+            self.document.get_line_mut(i).data.layout = None;
+        }
+        self.layout_cache.clear();
+        self.widest_layout = Some(0.0);
+        self.log_layout_cache_stats();
+    }
+
+    pub fn set_layout_cache_capacity(&mut self, capacity: usize) {
+        self.layout_cache.capacity = capacity;
+    }
+
+    // Drops the least recently used layouts if there are too many,
+    // the ones on screen and on the cursor line stay.
+    fn evict_layouts(&mut self, line_no1: usize, line_no2: usize) {
+        if !self.layout_cache.is_full() {
+            return;
+        }
+        let used = (0..self.document.num_lines())
+            .filter_map(|i| {
+                let data = self.document.get_line(i).data;
+                data.layout.as_ref().map(|_| (i, data.layout_used))
+            })
+            .collect();
+        let cursor_line = self.document.find_line(self.cursor_pos);
+        let evicted = self.layout_cache.evict(used, |i| (line_no1..line_no2).contains(&i) || i == cursor_line);
+        for i in evicted {
+            self.drop_layout(i);
+        }
+        self.log_layout_cache_stats();
+    }
+
+    fn drop_layout(&mut self, line_no: usize) {
+        if let Some(layout) = self.document.get_line_mut(line_no).data.layout.take() {
+            self.layout_cache.dropped(line_no, layout.height);
+            if self.widest_layout.is_some_and(|w| layout.width >= w) {
+                self.widest_layout = None;
+            }
+        }
+    }
+
+    // Hides or shows the line, keeping count of the hidden ones.
+    // Hidden lines take no space, so they don't keep layouts.
+    fn set_hidden(&mut self, line_no: usize, hidden: bool) {
+        if hidden {
+            self.drop_layout(line_no);
+        }
+        let data = self.document.get_line_mut(line_no).data;
+        if data.hidden != hidden {
+            data.hidden = hidden;
+            if hidden {
+                self.hidden_lines += 1;
+            } else {
+                self.hidden_lines -= 1;
+            }
+        }
+    }
+
+    fn log_layout_cache_stats(&self) {
+        let c = &self.layout_cache;
+        debug!("layout cache: {} hits, {} misses, {} evicted", c.hits, c.misses, c.evicted);
+    }
+
+    pub fn set_line_numbers(&mut self, line_numbers: LineNumbers) {
+        self.line_numbers = line_numbers;
+        self.invalidate_layouts();
+    }
+
+    pub fn wrap(&self) -> Wrap {
+        self.wrap
+    }
+
+    pub fn set_wrap(&mut self, wrap: Wrap, hanging_indent: bool) {
+        self.wrap = wrap;
+        self.hanging_indent = hanging_indent;
+        self.scroll_x = 0.0;
+        self.invalidate_layouts();
+    }
+
+    fn text_width(&self) -> f32 {
+        self.width - self.gutter_width()
+    }
+
+    // Left of the fold and diagnostic markers.
+    pub fn gutter_width(&self) -> f32 {
+        match self.line_numbers {
+            LineNumbers::Off => 0.0,
+            _ => self.digit_width * self.gutter_digits as f32 + 2.0 * GUTTER_MARGIN,
+        }
+    }
+
+    // The text gets narrower when the line count gets another digit.
+    fn update_gutter_digits(&mut self) {
+        let digits = self.document.num_lines().to_string().len();
+        if digits != self.gutter_digits {
+            self.gutter_digits = digits;
+            if self.line_numbers != LineNumbers::Off {
+                self.invalidate_layouts();
+            }
         }
     }
 
@@ -87,7 +393,52 @@ impl ViewState {
             end: start + text.len(),
             old_text: self.document.slice_string(start, end),
         };
+        let change = self.changes.as_ref().map(|_| TextChange {
+            start: self.lsp_position(start),
+            end: self.lsp_position(end),
+            text: text.iter().collect(),
+        });
+        if let (Some(changes), Some(change)) = (&mut self.changes, change) {
+            changes.push(change);
+        }
+        for d in &mut self.diagnostics {
+            d.start = adjust_pos(d.start, start, end, text.len());
+            d.end = adjust_pos(d.end, start, end, text.len());
+        }
+        if let Some(session) = &mut self.snippet {
+            session.adjust(start, end, text.len());
+        }
+        // edits inside a fold unfold it, typing on the folded line doesn't
+        let first_line = self.document.find_line(start);
+        let last_line = self.document.find_line(end);
+        let kept_fold = if first_line == last_line && !text.contains(&'\n') {
+            self.document.get_line(first_line).data.folded
+        } else {
+            None
+        };
+        for i in first_line..=last_line {
+            self.reveal(i);
+            if let (Some(n), None) = (self.document.get_line(i).data.folded, kept_fold) {
+                self.unfold_range(i, i + n);
+            }
+        }
+        self.rescan_from(first_line);
+        self.auto_closed.retain(|&p| p < start || p >= end);
+        for p in &mut self.auto_closed {
+            if *p >= end {
+                *p = *p - (end - start) + text.len();
+            }
+        }
+        for i in first_line..=last_line {
+            self.drop_layout(i);
+        }
         self.document.replace_slice(start, end, text);
+        let new_last_line = self.document.find_line(start + text.len());
+        self.layout_cache.lines_replaced(first_line, last_line, new_last_line);
+        if kept_fold.is_some() {
+            self.document.get_line_mut(first_line).data.folded = kept_fold;
+        }
+        self.update_gutter_digits();
         Some(result)
     }
 
@@ -157,6 +508,9 @@ impl ViewState {
     pub fn load(&mut self, text: &str, initially_modified: bool) {
         let text: Vec<char> = text.chars().collect();
         self.document.replace_slice(0, self.document.len(), &text);
+        self.layout_cache.clear();
+        self.widest_layout = Some(0.0);
+        self.hidden_lines = 0;
         self.undo_snapshots.clear();
         self.undo_slice_edits.clear();
         self.redo_snapshots.clear();
@@ -169,6 +523,117 @@ impl ViewState {
         self.anchor_y = 0.0;
         self.anchor_x = 0.0;
         self.unmodified_snapshot = if initially_modified { None } else { Some(0) };
+        if let Some(changes) = &mut self.changes {
+            changes.clear();
+        }
+        self.diagnostics.clear();
+        self.snippet = None;
+        self.rescan_from(0);
+        self.auto_closed.clear();
+        self.update_gutter_digits();
+    }
+
+    // For the text that comes from the file, loaded in the background
+    // or appended to it later. It's not an edit, so there's no undo.
+    pub fn append_loaded(&mut self, text: &str) {
+        let len = self.document.len();
+        let change = self.changes.as_ref().map(|_| TextChange {
+            start: self.lsp_position(len),
+            end: self.lsp_position(len),
+            text: text.to_owned(),
+        });
+        if let (Some(changes), Some(change)) = (&mut self.changes, change) {
+            changes.push(change);
+        }
+        let text: Vec<char> = text.chars().collect();
+        let last_line = self.document.num_lines() - 1;
+        // followed text appended to a folded line would be hidden
+        self.reveal(last_line);
+        self.drop_layout(last_line);
+        self.document.replace_slice(len, len, &text);
+        self.layout_cache.lines_replaced(last_line, last_line, self.document.num_lines() - 1);
+        self.rescan_from(last_line);
+        self.update_gutter_digits();
+    }
+
+    // Keeps the end of the document in view if it was, and the cursor
+    // at the end if it was there.
+    pub fn append_followed(&mut self, text: &str) {
+        let pinned = self.end_on_screen();
+        let cursor_at_end = self.cursor_pos == self.document.len() && !self.has_selection();
+        self.append_loaded(text);
+        if cursor_at_end {
+            self.cursor_pos = self.document.len();
+            self.selection_pos = self.cursor_pos;
+        }
+        if pinned {
+            self.scroll_to_end();
+        }
+    }
+
+    // While tracking is on, all the edits are recorded for take_changes().
+    pub fn track_changes(&mut self, on: bool) {
+        self.changes = if on { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_changes(&mut self) -> Vec<TextChange> {
+        self.changes.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn lsp_position(&self, pos: usize) -> Position {
+        let line_no = self.document.find_line(pos);
+        let line = self.document.get_line(line_no);
+        Position {
+            line: line_no,
+            character: lsp::utf16_len(&self.document.slice_string(line.start, pos)),
+        }
+    }
+
+    // Out of range positions are clamped.
+    pub fn pos_from_lsp(&self, p: Position) -> usize {
+        if p.line >= self.document.num_lines() {
+            return self.document.len();
+        }
+        let line = self.document.get_line(p.line);
+        let text = self.document.slice_string(line.start, line.end);
+        line.start + lsp::char_offset(&text, p.character)
+    }
+
+    // Columns are in chars here. Out of range positions are clamped.
+    pub fn line_col_to_pos(&self, line_no: usize, column: usize) -> usize {
+        if line_no >= self.document.num_lines() {
+            return self.document.len();
+        }
+        let line = self.document.get_line(line_no);
+        (line.start + column).min(line.end)
+    }
+
+    // Replaces diagnostics from the same source.
+    pub fn set_diagnostics(&mut self, source: DiagnosticSource, diagnostics: Vec<Diagnostic>) {
+        self.diagnostics.retain(|d| d.source != source);
+        self.diagnostics.extend(diagnostics);
+    }
+
+    pub fn find_diagnostic(&self, source: DiagnosticSource, id: usize) -> Option<&Diagnostic> {
+        self.diagnostics.iter().find(|d| d.source == source && d.id == id)
+    }
+
+    pub fn diagnostics_at(&self, pos: usize) -> Vec<&Diagnostic> {
+        self.diagnostics.iter().filter(|d| d.start <= pos && pos <= d.end).collect()
+    }
+
+    // For changes that are not edits, like the encoding.
+    pub fn mark_modified(&mut self) {
+        self.unmodified_snapshot = None;
+    }
+
+    // Line, column and visual column of the cursor, from 1.
+    pub fn cursor_line_col(&self) -> (usize, usize, usize) {
+        let line_no = self.document.find_line(self.cursor_pos);
+        let start = self.document.get_line(line_no).start;
+        let before_cursor = self.document.slice_string(start, self.cursor_pos);
+        // DirectWrite's default tab stop is not a whole number of chars
+        (line_no + 1, self.cursor_pos - start + 1, indent::columns(&before_cursor, self.tab_columns()) + 1)
     }
 
     pub fn set_unmodified_snapshot(&mut self) {
@@ -179,6 +644,14 @@ impl ViewState {
         self.document.slice_string(0, self.document.len())
     }
 
+    pub fn document_len(&self) -> usize {
+        self.document.len()
+    }
+
+    pub fn cursor_pos(&self) -> usize {
+        self.cursor_pos
+    }
+
     pub fn clear_selection(&mut self) {
         self.selection_pos = self.cursor_pos;
     }
@@ -205,6 +678,15 @@ impl ViewState {
         self.ensure_cursor_on_screen();
     }
 
+    pub fn set_selection(&mut self, selection_pos: usize, cursor_pos: usize) {
+        assert!(selection_pos <= self.document.len());
+        assert!(cursor_pos <= self.document.len());
+        self.selection_pos = selection_pos;
+        self.cursor_pos = cursor_pos;
+        self.ensure_cursor_on_screen();
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+    }
+
     pub fn has_selection(&self) -> bool {
         self.cursor_pos != self.selection_pos
     }
@@ -215,6 +697,84 @@ impl ViewState {
         self.document.slice_string(a, b)
     }
 
+    pub fn selection_range(&self) -> (usize, usize) {
+        (self.cursor_pos.min(self.selection_pos), self.cursor_pos.max(self.selection_pos))
+    }
+
+    pub fn slice_string(&self, start: usize, end: usize) -> String {
+        self.document.slice_string(start, end)
+    }
+
+    // Unlike paste(), can edit anywhere in the document.
+    // The cursor and the selection stay attached to the surrounding text.
+    pub fn replace_text(&mut self, start: usize, end: usize, text: &str) {
+        self.replace_text_keeping_view(start, end, text);
+        self.ensure_cursor_on_screen();
+    }
+
+    fn replace_text_keeping_view(&mut self, start: usize, end: usize, text: &str) {
+        let text: Vec<char> = text.chars().collect();
+        self.replace_slice(start, end, &text);
+        self.cursor_pos = adjust_pos(self.cursor_pos, start, end, text.len());
+        self.selection_pos = adjust_pos(self.selection_pos, start, end, text.len());
+        self.anchor_pos = adjust_pos(self.anchor_pos, start, end, text.len());
+    }
+
+    // Edits are sorted and refer to positions in the current text.
+    // Unlike replace_text(), doesn't scroll to the cursor.
+    pub fn apply_edits(&mut self, edits: &[Edit]) {
+        for e in edits.iter().rev() {
+            self.replace_text_keeping_view(e.start, e.end, &e.text);
+        }
+    }
+
+    // Replaces start..end with the snippet and selects its first tab stop.
+    pub fn insert_snippet(&mut self, start: usize, end: usize, e: Expansion) {
+        self.replace_text(start, end, &e.text);
+        self.snippet = Some(snippet::Session::new(start, e));
+        self.select_tab_stop();
+    }
+
+    fn select_tab_stop(&mut self) {
+        let session = self.snippet.as_ref().unwrap();
+        let (s, e) = session.current_range();
+        if session.is_finished() {
+            self.snippet = None;
+        }
+        self.set_selection(s, e);
+    }
+
+    // Returns false if there is no snippet to move in.
+    pub fn next_tab_stop(&mut self, forward: bool) -> bool {
+        match &mut self.snippet {
+            Some(session) => {
+                session.advance(forward);
+                self.select_tab_stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // After each key: copies the current snippet placeholder to its
+    // mirrors, and forgets the auto-inserted closers the cursor left behind.
+    pub fn after_input(&mut self) {
+        let line = self.document.get_line(self.document.find_line(self.cursor_pos));
+        let (line_start, line_end) = (line.start, line.end);
+        self.auto_closed.retain(|&p| line_start <= p && p < line_end);
+
+        let session = match &self.snippet {
+            Some(session) => session,
+            None => return,
+        };
+        if !session.contains(self.cursor_pos) {
+            self.snippet = None;
+            return;
+        }
+        let edits = session.mirror_edits(|s, e| self.document.slice_string(s, e));
+        self.apply_edits(&edits);
+    }
+
     pub fn cut_selection(&mut self) -> String {
         let a = self.cursor_pos.min(self.selection_pos);
         let b = self.cursor_pos.max(self.selection_pos);
@@ -227,6 +787,24 @@ impl ViewState {
         result
     }
 
+    // Deletes from the cursor to the end of the line,
+    // or the line break itself if the cursor is already at the end.
+    pub fn kill_line(&mut self) -> String {
+        let line_no = self.document.find_line(self.cursor_pos);
+        let line = self.document.get_line(line_no);
+        let end = if self.cursor_pos < line.end {
+            line.end
+        } else {
+            (line.end + 1).min(self.document.len())
+        };
+        let result = self.document.slice_string(self.cursor_pos, end);
+        self.replace_slice(self.cursor_pos, end, &[]);
+        self.clear_selection();
+        self.ensure_cursor_on_screen();
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+        result
+    }
+
     pub fn insert_char(&mut self, c: char) {
         if self.selection_pos != self.cursor_pos {
             let a = self.cursor_pos.min(self.selection_pos);
@@ -284,9 +862,42 @@ impl ViewState {
         }
     }
 
+    pub fn move_cursor(&mut self, motion: Motion) {
+        match motion {
+            Motion::Left => self.left(),
+            Motion::Right => self.right(),
+            Motion::CtrlLeft => self.ctrl_left(),
+            Motion::CtrlRight => self.ctrl_right(),
+            Motion::Home => self.home(),
+            Motion::End => self.end(),
+            Motion::CtrlHome => self.ctrl_home(),
+            Motion::CtrlEnd => self.ctrl_end(),
+            Motion::Up => self.up(),
+            Motion::Down => self.down(),
+            Motion::PgUp => self.pg_up(),
+            Motion::PgDown => self.pg_down(),
+        }
+    }
+
+    // Selects the next occurrence of the needle after the cursor.
+    pub fn find_next(&mut self, needle: &str) -> bool {
+        let needle: Vec<char> = needle.chars().collect();
+        if needle.is_empty() || needle.len() > self.document.len() {
+            return false;
+        }
+        let from = self.cursor_pos.max(self.selection_pos);
+        for start in from..=self.document.len() - needle.len() {
+            if needle.iter().enumerate().all(|(i, &c)| self.document.get_char(start + i) == c) {
+                self.set_selection(start, start + needle.len());
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn left(&mut self) {
         if self.cursor_pos > 0 {
-            self.cursor_pos -= 1;
+            self.cursor_pos = self.skip_folded(self.cursor_pos - 1, false);
             self.ensure_cursor_on_screen();
             self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
         }
@@ -294,7 +905,7 @@ impl ViewState {
 
     pub fn right(&mut self) {
         if self.cursor_pos < self.document.len() {
-            self.cursor_pos += 1;
+            self.cursor_pos = self.skip_folded(self.cursor_pos + 1, true);
             self.ensure_cursor_on_screen();
             self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
         }
@@ -311,6 +922,7 @@ impl ViewState {
             }
             self.cursor_pos -= 1;
         }
+        self.cursor_pos = self.skip_folded(self.cursor_pos, false);
         self.ensure_cursor_on_screen();
         self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
     }
@@ -326,6 +938,7 @@ impl ViewState {
                 break;
             }
         }
+        self.cursor_pos = self.skip_folded(self.cursor_pos, true);
         self.ensure_cursor_on_screen();
         self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
     }
@@ -334,7 +947,7 @@ impl ViewState {
         let line_no = self.document.find_line(self.cursor_pos);
         self.ensure_layout(line_no);
         let line = self.document.get_line(line_no);
-        let layout = line.data.as_ref().unwrap();
+        let layout = line.data.layout.as_ref().unwrap();
         let bounds = layout.line_boundaries();
         self.cursor_pos = line.start + bounds.into_iter()
             .filter(|&x| x < self.cursor_pos - line.start)
@@ -348,7 +961,7 @@ impl ViewState {
         let line_no = self.document.find_line(self.cursor_pos);
         self.ensure_layout(line_no);
         let line = self.document.get_line(line_no);
-        let layout = line.data.as_ref().unwrap();
+        let layout = line.data.layout.as_ref().unwrap();
         let bounds = layout.line_boundaries();
         let &end = bounds.last().unwrap();
         self.cursor_pos = line.start + bounds.into_iter()
@@ -371,83 +984,264 @@ impl ViewState {
     }
 
     pub fn up(&mut self) {
-        let (_x, y) = self.pos_to_coord(self.cursor_pos);
-
-        let line_no = self.document.find_line(self.cursor_pos);
-        self.ensure_layout(line_no);
-        let line = self.document.get_line(line_no);
-        let h = line.data.as_ref().unwrap().line_height;
-        // TODO: what if line above has different height?
-        self.cursor_pos = self.coord_to_pos(self.anchor_x, y - h * 0.5);
-        self.ensure_cursor_on_screen();
+        let r = self.cursor_row();
+        let (row, dist) = rows::move_rows(self, r, -1);
+        self.move_cursor_to_row(row, dist);
     }
 
     pub fn down(&mut self) {
-        let (_x, y) = self.pos_to_coord(self.cursor_pos);
+        let r = self.cursor_row();
+        let (row, dist) = rows::move_rows(self, r, 1);
+        self.move_cursor_to_row(row, dist);
+    }
 
-        let line_no = self.document.find_line(self.cursor_pos);
-        self.ensure_layout(line_no);
-        let line = self.document.get_line(line_no);
-        let h = line.data.as_ref().unwrap().line_height;
-        // TODO: what if line below has different height?
-        self.cursor_pos = self.coord_to_pos(self.anchor_x, h.mul_add(1.5, y));
-        self.ensure_cursor_on_screen();
+    fn end_on_screen(&mut self) -> bool {
+        let (anchor_line, anchor_line_y) = self.anchor_line_and_y();
+        let (_y, _line_no1, line_no2) = self.lines_on_screen(anchor_line, anchor_line_y);
+        line_no2 == self.document.num_lines()
+    }
+
+    // With the last row at the bottom.
+    pub fn scroll_to_end(&mut self) {
+        let end = Row { line: self.document.num_lines(), row: 0 };
+        let last = rows::prev_row(self, end).unwrap();
+        let h = rows::row_height(self, last);
+        // the end of the last visible line is on its last row
+        self.anchor_pos = self.document.get_line(last.line).end;
+        self.anchor_y = self.height - h;
+        self.clip_scroll_position_to_document();
     }
 
     pub fn scroll(&mut self, delta: f32) {
-        let line_no = self.document.find_line(self.cursor_pos);
-        self.ensure_layout(line_no);
-        let line = self.document.get_line(line_no);
-        let layout = line.data.as_ref().unwrap();
-        // TODO: what if lines have different heights
-        self.anchor_y += delta * layout.line_height;
+        let (top, offset) = self.row_at_top();
+        self.anchor_y += rows::scroll_distance(self, top, offset, delta);
         self.clip_scroll_position_to_document();
     }
 
     pub fn pg_up(&mut self) {
-        let (_x, y) = self.pos_to_coord(self.cursor_pos);
-
-        let line_no = self.document.find_line(self.cursor_pos);
-        self.ensure_layout(line_no);
-        let line = self.document.get_line(line_no);
-        let h = line.data.as_ref().unwrap().line_height;
-        // TODO: what if lines has different heights?
-        self.cursor_pos = self.coord_to_pos(self.anchor_x, h.mul_add(1.5, y - self.height));
-        self.ensure_cursor_on_screen();
+        let r = self.cursor_row();
+        let (row, dist) = rows::page_up(self, r, self.height);
+        self.move_cursor_to_row(row, dist);
     }
 
     pub fn pg_down(&mut self) {
-        let (_x, y) = self.pos_to_coord(self.cursor_pos);
+        let r = self.cursor_row();
+        let (row, dist) = rows::page_down(self, r, self.height);
+        self.move_cursor_to_row(row, dist);
+    }
 
+    fn cursor_row(&mut self) -> Row {
         let line_no = self.document.find_line(self.cursor_pos);
         self.ensure_layout(line_no);
         let line = self.document.get_line(line_no);
-        let h = line.data.as_ref().unwrap().line_height;
-        // TODO: what if lines has different heights?
-        self.cursor_pos = self.coord_to_pos(self.anchor_x, y - h * 0.5 + self.height);
+        let row = line.data.layout.as_ref().unwrap().row_of(self.cursor_pos - line.start);
+        Row { line: line_no, row }
+    }
+
+    // To the middle of the row that is dist below the top of the cursor row,
+    // at anchor_x.
+    fn move_cursor_to_row(&mut self, row: Row, dist: f32) {
+        let (_x, y) = self.pos_to_coord(self.cursor_pos);
+        let h = rows::row_height(self, row);
+        self.cursor_pos = self.coord_to_pos(self.anchor_x, y + dist + h * 0.5);
         self.ensure_cursor_on_screen();
     }
 
+    // The row at the top of the view and how far above the view its top is.
+    fn row_at_top(&mut self) -> (Row, f32) {
+        let (anchor_line, anchor_line_y) = self.anchor_line_and_y();
+        let (mut y, line, _) = self.lines_on_screen(anchor_line, anchor_line_y);
+        if line < self.document.num_lines() {
+            for (row, h) in self.row_heights(line).into_iter().enumerate() {
+                if y + h > 0.0 {
+                    return (Row { line, row }, (-y).max(0.0));
+                }
+                y += h;
+            }
+        }
+        // scrolled past the end
+        let end = Row { line: self.document.num_lines(), row: 0 };
+        (rows::prev_row(self, end).unwrap(), 0.0)
+    }
+
     fn ensure_cursor_on_screen(&mut self) {
-        // TODO: when jumping large distances it will force layout
-        // on all lines in between, it's slow
-        let (_x, y) = self.pos_to_coord(self.cursor_pos);
-        let i = self.document.find_line(self.cursor_pos);
-        self.ensure_layout(i);
-        let line = self.document.get_line(i);
-        let layout = line.data.as_ref().unwrap();
+        let cursor_line = self.document.find_line(self.cursor_pos);
+        self.reveal(cursor_line);
+        // Getting the coordinates lays out all lines between the anchor
+        // and the cursor, so for a long jump the view moves to the cursor
+        // first, with the cursor row a third of the way down.
+        let (anchor_line, anchor_line_y) = self.anchor_line_and_y();
+        let (_y0, line_no1, line_no2) = self.lines_on_screen(anchor_line, anchor_line_y);
+        let page = line_no2 - line_no1;
+        if cursor_line + page < line_no1 || cursor_line > line_no2 + page {
+            self.anchor_pos = self.cursor_pos;
+            self.anchor_y = self.height / 3.0;
+        }
+        let (x, y) = self.pos_to_coord(self.cursor_pos);
+        // the lines wrapped at the window edge always fit
+        let margin = 2.0 * self.digit_width;
+        if self.wrap == Wrap::Window {
+            self.scroll_x = 0.0;
+        } else if x < self.scroll_x + margin {
+            self.scroll_x = (x - margin).max(0.0);
+        } else if x > self.scroll_x + self.text_width() - margin {
+            self.scroll_x = x - self.text_width() + margin;
+        }
+        let r = self.cursor_row();
+        let h = rows::row_height(self, r);
         if y < 0.0 {
             self.anchor_pos = self.cursor_pos;
             self.anchor_y = 0.0;
         }
-        // TODO: what if lines has different heights
-        if y + layout.line_height > self.height {
+        if y + h > self.height {
             self.anchor_pos = self.cursor_pos;
-            self.anchor_y = self.height - layout.line_height;
+            self.anchor_y = self.height - h;
+        }
+        self.clip_scroll_position_to_document();
+    }
+
+    // The average of the laid out lines, kept by the layout cache,
+    // so that the estimate doesn't force layout of the whole document.
+    fn average_line_height(&mut self) -> f32 {
+        if let Some(h) = self.layout_cache.average_height() {
+            return h;
+        }
+        self.ensure_layout(0);
+        self.document.get_line(0).data.layout.as_ref().unwrap().height
+    }
+
+    // Number of visible lines before the line. Without folds it's
+    // the line number, with them the visible lines are walked,
+    // jumping over the folded ones.
+    fn visible_index(&self, line_no: usize) -> usize {
+        if self.hidden_lines == 0 {
+            return line_no;
+        }
+        let mut hidden = 0;
+        let mut i = 0;
+        while i < line_no {
+            let n = self.document.get_line(i).data.folded.unwrap_or(0);
+            hidden += n.min(line_no - i - 1);
+            i += n + 1;
+        }
+        line_no - hidden
+    }
+
+    // The reverse of visible_index(), clamped to the last visible line.
+    fn nth_visible_line(&self, n: usize) -> usize {
+        let num_lines = self.document.num_lines();
+        if self.hidden_lines == 0 {
+            return n.min(num_lines - 1);
+        }
+        let mut i = 0;
+        for _ in 0..n {
+            let next = i + self.document.get_line(i).data.folded.unwrap_or(0) + 1;
+            if next >= num_lines {
+                break;
+            }
+            i = next;
+        }
+        i
+    }
+
+    // The first line is never hidden.
+    fn prev_visible_line(&self, line_no: usize) -> Option<usize> {
+        let mut i = line_no.checked_sub(1)?;
+        while self.document.get_line(i).data.hidden {
+            i -= 1;
+        }
+        Some(i)
+    }
+
+    // Of a visible line, the average if it's not laid out.
+    fn estimated_height(&self, line_no: usize, average: f32) -> f32 {
+        self.document.get_line(line_no).data.layout.as_ref().map_or(average, |l| l.height)
+    }
+
+    // Offset of the visible line (or the end) in the scroll range. The laid
+    // out lines above count with their heights, the rest as the average.
+    fn estimated_y(&mut self, line_no: usize, average: f32) -> f32 {
+        let document = &self.document;
+        self.layout_cache.move_mark(line_no, document.num_lines(), |i| {
+            document.get_line(i).data.layout.as_ref().map(|l| l.height)
+        });
+        let (count, height) = self.layout_cache.above();
+        height as f32 + self.visible_index(line_no).saturating_sub(count) as f32 * average
+    }
+
+    // For the scroll bar: the estimated height of the scroll range
+    // (the last line can be scrolled up to the top), the offset
+    // of the view in it and the view height.
+    pub fn scroll_position(&mut self) -> (f32, f32, f32) {
+        let (anchor_line, anchor_line_y) = self.anchor_line_and_y();
+        let (y0, top_line, _) = self.lines_on_screen(anchor_line, anchor_line_y);
+        let average = self.average_line_height();
+        let top = self.estimated_y(top_line, average) - y0;
+        let last_line = self.document.num_lines() - 1;
+        let last_line = match self.document.get_line(last_line).data.hidden {
+            true => self.prev_visible_line(last_line).unwrap(),
+            false => last_line,
+        };
+        let (count, height) = (self.layout_cache.count(), self.layout_cache.height());
+        let visible = self.document.num_lines() - self.hidden_lines;
+        let total = height as f32 + visible.saturating_sub(count) as f32 * average;
+        let range = total - self.estimated_height(last_line, average) + self.height;
+        (range, top, self.height)
+    }
+
+    // Puts the view at the offset from scroll_position(). The line is
+    // guessed with the average height, then it's walked from there.
+    pub fn scroll_to(&mut self, top: f32) {
+        let average = self.average_line_height();
+        let top = top.max(0.0);
+        let mut line_no = self.nth_visible_line((top / average) as usize);
+        let mut y = self.estimated_y(line_no, average);
+        while y > top {
+            match self.prev_visible_line(line_no) {
+                Some(prev) => {
+                    line_no = prev;
+                    y -= self.estimated_height(line_no, average);
+                }
+                None => break,
+            }
+        }
+        loop {
+            let h = self.estimated_height(line_no, average);
+            let next = self.next_visible_line(line_no);
+            if next == self.document.num_lines() || y + h > top {
+                break;
+            }
+            line_no = next;
+            y += h;
         }
+        self.anchor_pos = self.document.get_line(line_no).start;
+        self.anchor_y = y - top;
         self.clip_scroll_position_to_document();
     }
 
+    // The same for the horizontal scroll, only the laid out lines count.
+    pub fn h_scroll_position(&mut self) -> (f32, f32, f32) {
+        let document = &self.document;
+        let widest = *self.widest_layout.get_or_insert_with(|| {
+            (0..document.num_lines())
+                .filter_map(|i| document.get_line(i).data.layout.as_ref().map(|l| l.width))
+                .fold(0.0, f32::max)
+        });
+        // room for the cursor after the longest line
+        let range = (widest + 2.0 * self.digit_width).max(self.scroll_x + self.text_width());
+        (range, self.scroll_x, self.text_width())
+    }
+
+    pub fn h_scroll_to(&mut self, x: f32) {
+        let (range, _, page) = self.h_scroll_position();
+        self.scroll_x = x.min(range - page).max(0.0);
+    }
+
+    // In digit widths.
+    pub fn h_scroll(&mut self, delta: f32) {
+        self.h_scroll_to(self.scroll_x + delta * self.digit_width);
+    }
+
     fn clip_scroll_position_to_document(&mut self) {
         let (anchor_line, anchor_line_y) = self.anchor_line_and_y();
         let (y1, line_no1, line_no2) =
@@ -470,10 +1264,13 @@ impl ViewState {
             self.lines_on_screen(anchor_line, anchor_line_y);
 
         for line_no in line_no1..line_no2 {
+            if self.document.get_line(line_no).data.hidden {
+                continue;
+            }
             self.ensure_layout(line_no);
             let line = self.document.get_line(line_no);
             let line_start = line.start;
-            let layout = line.data.as_ref().unwrap();
+            let layout = line.data.layout.as_ref().unwrap();
             let bounds = layout.line_boundaries();
             for &b in &bounds[..bounds.len() - 1] {
                 let (_x, y) = self.pos_to_coord(line_start + b);
@@ -488,46 +1285,582 @@ impl ViewState {
 
     fn ensure_layout(&mut self, line_no: usize) {
         let line = self.document.get_line(line_no);
-        if line.data.is_none() {
+        if line.data.layout.is_some() {
+            self.document.get_line_mut(line_no).data.layout_used = self.layout_cache.hit();
+        } else {
             let line_text = self.document.slice_string(line.start, line.end);
-            let layout = TextLayout::new(
-                &line_text, &self.dwrite_factory, &self.text_format, self.width);
+            let max_width = match self.wrap {
+                Wrap::None => NO_WRAP_WIDTH,
+                Wrap::Window => self.text_width(),
+                // the font is proportional, so it's only approximate
+                Wrap::Column(n) => self.digit_width * n as f32,
+            };
+            let ws = &line_text[..line_text.len() - line_text.trim_start_matches([' ', '\t']).len()];
+            let indent = if self.hanging_indent && self.wrap != Wrap::None && !ws.is_empty() {
+                // keep at least half of the width for the text
+                TextLayout::new(ws, &self.dwrite_factory, &self.text_format, NO_WRAP_WIDTH).width.min(max_width * 0.5)
+            } else {
+                0.0
+            };
+            let mut layout = TextLayout::new(
+                &line_text, &self.dwrite_factory, &self.text_format, max_width - indent);
+            layout.set_hanging_indent(indent);
+            let layout_used = self.layout_cache.miss(line_no, layout.height);
+            if let Some(w) = &mut self.widest_layout {
+                *w = w.max(layout.width);
+            }
             let line = self.document.get_line_mut(line_no);
-            *line.data = Some(layout);
+            line.data.layout = Some(layout);
+            line.data.layout_used = layout_used;
         }
     }
 
     pub fn coord_to_pos(&mut self, x: f32, y: f32) -> usize {
         let (mut i, mut y0) = self.anchor_line_and_y();
         while i > 0 && y0 > y {
-            self.ensure_layout(i - 1);
-            let line = self.document.get_line(i - 1);
-            let layout = line.data.as_ref().unwrap();
             i -= 1;
-            y0 -= layout.height;
+            y0 -= self.visible_height(i);
         }
         loop {
-            self.ensure_layout(i);
-            let line = self.document.get_line(i);
-            let layout = line.data.as_ref().unwrap();
-            if y < y0 + layout.height || i + 1 == self.document.num_lines() {
-                let pos = layout.coords_to_pos(x, y - y0);
-                assert!(pos <= line.end - line.start);
-                return line.start + pos;
+            let next = self.next_visible_line(i);
+            if !self.document.get_line(i).data.hidden {
+                let h = self.visible_height(i);
+                if y < y0 + h || next == self.document.num_lines() {
+                    let line = self.document.get_line(i);
+                    let layout = line.data.layout.as_ref().unwrap();
+                    let pos = layout.coords_to_pos(x, y - y0);
+                    assert!(pos <= line.end - line.start);
+                    return line.start + pos;
+                }
+                y0 += h;
             }
+            i = next;
+        }
+    }
+
+    // Folded lines take no space.
+    fn visible_height(&mut self, line_no: usize) -> f32 {
+        if self.document.get_line(line_no).data.hidden {
+            return 0.0;
+        }
+        self.ensure_layout(line_no);
+        self.document.get_line(line_no).data.layout.as_ref().unwrap().height
+    }
+
+    fn next_visible_line(&self, line_no: usize) -> usize {
+        let mut i = line_no + 1;
+        while i < self.document.num_lines() && self.document.get_line(i).data.hidden {
             i += 1;
-            y0 += layout.height;
+        }
+        i
+    }
+
+    // Moves pos out of the folded lines, to the end of the folded line
+    // or to the start of the next visible one.
+    fn skip_folded(&self, pos: usize, forward: bool) -> usize {
+        let mut i = self.document.find_line(pos);
+        if !self.document.get_line(i).data.hidden {
+            return pos;
+        }
+        let next = self.next_visible_line(i);
+        if forward && next < self.document.num_lines() {
+            return self.document.get_line(next).start;
+        }
+        while self.document.get_line(i).data.hidden {
+            i -= 1;
+        }
+        self.document.get_line(i).end
+    }
+
+    // Last line of the foldable region starting at line_no: the line before
+    // the closing bracket, or else the end of the block indented deeper.
+    fn fold_region(&mut self, line_no: usize) -> Option<usize> {
+        let mut open = Vec::new();
+        for (p, c) in self.line_brackets(line_no) {
+            if brackets::closing(c).is_some() {
+                open.push((p, c));
+            } else {
+                open.pop();
+            }
+        }
+        if let Some(&(p, c)) = open.first() {
+            if let Some(m) = self.find_match(p, c) {
+                let end = self.document.find_line(m);
+                if end > line_no + 1 {
+                    return Some(end - 1);
+                }
+            }
+        }
+        let document = &self.document;
+        let line_text = |i| {
+            let line = document.get_line(i);
+            document.slice_string(line.start, line.end)
+        };
+        indent::block_end(line_no, document.num_lines(), line_text, self.tab_columns())
+    }
+
+    // Hides the regions, given as (folded line, last line).
+    fn fold_regions(&mut self, regions: &[(usize, usize)]) {
+        // the cursor goes to the end of the outermost folded line hiding it,
+        // and stays where it was on the screen
+        let cursor_line = self.document.find_line(self.cursor_pos);
+        let target = regions.iter()
+            .filter(|&&(h, end)| h < cursor_line && cursor_line <= end)
+            .map(|&(h, _)| h)
+            .min()
+            .map_or(self.cursor_pos, |h| self.document.get_line(h).end);
+        let (_x, y) = self.pos_to_coord(target);
+        for &(h, end) in regions {
+            self.document.get_line_mut(h).data.folded = Some(end - h);
+            for i in h + 1..=end {
+                self.set_hidden(i, true);
+            }
+        }
+        let selection_line = self.document.find_line(self.selection_pos);
+        if target != self.cursor_pos || self.document.get_line(selection_line).data.hidden {
+            self.selection_pos = target;
+        }
+        self.cursor_pos = target;
+        self.anchor_pos = target;
+        self.anchor_y = y;
+        self.ensure_cursor_on_screen();
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+    }
+
+    // Folds the innermost region around the cursor that is not folded yet.
+    pub fn fold(&mut self) -> bool {
+        let cursor_line = self.document.find_line(self.cursor_pos);
+        for h in (cursor_line.saturating_sub(MAX_BRACKET_SCAN_LINES)..=cursor_line).rev() {
+            let data = &self.document.get_line(h).data;
+            if data.hidden || data.folded.is_some() {
+                continue;
+            }
+            match self.fold_region(h) {
+                Some(end) if end >= cursor_line => {
+                    self.fold_regions(&[(h, end)]);
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    // The folds inside stay folded.
+    fn unfold_line(&mut self, line_no: usize) -> bool {
+        let n = match self.document.get_line(line_no).data.folded {
+            Some(n) => n,
+            None => return false,
+        };
+        self.document.get_line_mut(line_no).data.folded = None;
+        let mut i = line_no + 1;
+        while i <= line_no + n {
+            self.set_hidden(i, false);
+            i += self.document.get_line(i).data.folded.unwrap_or(0) + 1;
+        }
+        true
+    }
+
+    pub fn unfold(&mut self) -> bool {
+        self.unfold_line(self.document.find_line(self.cursor_pos))
+    }
+
+    // Including the nested folds.
+    fn unfold_range(&mut self, first_line: usize, last_line: usize) {
+        for i in first_line..=last_line.min(self.document.num_lines() - 1) {
+            self.document.get_line_mut(i).data.folded = None;
+            self.set_hidden(i, false);
+        }
+    }
+
+    // Unfolds everything that hides the line.
+    fn reveal(&mut self, line_no: usize) {
+        let mut h = line_no;
+        while self.document.get_line(h).data.hidden {
+            h -= 1;
+        }
+        if h < line_no {
+            let n = self.document.get_line(h).data.folded.unwrap_or(0);
+            self.unfold_range(h, (h + n).max(line_no));
+        }
+    }
+
+    // The same regions as fold_region() finds, for all lines at once.
+    pub fn fold_all(&mut self) {
+        let n = self.document.num_lines();
+        let bracket_ends = brackets::matching_lines(
+            (0..n).map(|i| self.line_brackets(i).into_iter().map(|(_, c)| c).collect()),
+            MAX_BRACKET_SCAN_LINES);
+        let document = &self.document;
+        let indent_ends = indent::block_ends(
+            (0..n).map(|i| {
+                let line = document.get_line(i);
+                document.slice_string(line.start, line.end)
+            }),
+            self.tab_columns());
+        let mut regions = Vec::new();
+        for h in 0..n {
+            if self.document.get_line(h).data.folded.is_some() {
+                continue;
+            }
+            let end = match bracket_ends[h] {
+                Some(end) if end > h + 1 => Some(end - 1),
+                _ => indent_ends[h],
+            };
+            if let Some(end) = end {
+                regions.push((h, end));
+            }
+        }
+        self.fold_regions(&regions);
+    }
+
+    pub fn unfold_all(&mut self) {
+        self.unfold_range(0, self.document.num_lines() - 1);
+    }
+
+    // A click in the gutter folds or unfolds the line.
+    pub fn toggle_fold(&mut self, y: f32) -> bool {
+        let pos = self.coord_to_pos(0.0, y);
+        let line_no = self.document.find_line(pos);
+        if self.unfold_line(line_no) {
+            return true;
+        }
+        match self.fold_region(line_no) {
+            Some(end) => {
+                self.fold_regions(&[(line_no, end)]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Line under the point, for the clicks in the gutter.
+    pub fn line_at(&mut self, y: f32) -> usize {
+        let pos = self.coord_to_pos(0.0, y);
+        self.document.find_line(pos)
+    }
+
+    // Whole lines with their line breaks, the cursor on the line_no side.
+    pub fn select_lines(&mut self, anchor_line: usize, line_no: usize) {
+        let first = anchor_line.min(line_no);
+        // a folded line comes with the hidden ones
+        let next = self.next_visible_line(anchor_line.max(line_no));
+        let start = self.document.get_line(first).start;
+        let end = if next < self.document.num_lines() {
+            self.document.get_line(next).start
+        } else {
+            self.document.len()
+        };
+        if line_no < anchor_line {
+            self.set_selection(end, start);
+        } else {
+            self.set_selection(start, end);
         }
     }
 
     pub fn click(&mut self, x: f32, y: f32) {
-        self.cursor_pos = self.coord_to_pos(x, y);
+        self.cursor_pos = self.coord_to_pos(x + self.scroll_x, y);
         self.ensure_cursor_on_screen();
         self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
     }
 
+    // Start of the identifier that ends at pos.
+    pub fn word_start(&self, pos: usize) -> usize {
+        let mut start = pos;
+        while start > 0 {
+            if !completion::is_word_char(self.document.get_char(start - 1)) {
+                break;
+            }
+            start -= 1;
+        }
+        start
+    }
+
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+        self.rescan_from(0);
+        for i in 0..self.document.num_lines() {
+            self.document.get_line_mut(i).data.scan = None;
+        }
+    }
+
+    // Scans from the line on are out of date.
+    fn rescan_from(&mut self, line_no: usize) {
+        self.scanned_lines = self.scanned_lines.min(line_no);
+        self.scan_start = self.scan_start.min(self.scanned_lines);
+    }
+
+    // Scans the lines up to line_no that are not scanned yet, or whose
+    // scans started in a different state because of the edits above.
+    // Far from the scanned lines it starts over at most
+    // MAX_BRACKET_SCAN_LINES above, in the state the line before was
+    // last scanned with, or in code.
+    fn ensure_scan(&mut self, line_no: usize) {
+        if (self.scan_start..self.scanned_lines).contains(&line_no) {
+            return;
+        }
+        if line_no < self.scan_start || line_no > self.scanned_lines + MAX_BRACKET_SCAN_LINES {
+            self.scan_start = line_no.saturating_sub(MAX_BRACKET_SCAN_LINES);
+            self.scanned_lines = self.scan_start;
+        }
+        let mut state = match self.scanned_lines {
+            0 => ScanState::Code,
+            n => self.document.get_line(n - 1).data.scan.as_ref().map_or(ScanState::Code, |s| s.end_state),
+        };
+        for i in self.scanned_lines..=line_no {
+            let line = self.document.get_line(i);
+            match &line.data.scan {
+                Some(scan) if scan.start_state == state => state = scan.end_state,
+                _ => {
+                    let chars: Vec<char> = (line.start..line.end).map(|p| self.document.get_char(p)).collect();
+                    let scan = brackets::scan_line(&chars, state, &self.syntax);
+                    state = scan.end_state;
+                    self.document.get_line_mut(i).data.scan = Some(scan);
+                }
+            }
+        }
+        self.scanned_lines = line_no + 1;
+    }
+
+    // Brackets of the line outside of comments and strings, with document positions.
+    fn line_brackets(&mut self, line_no: usize) -> Vec<(usize, char)> {
+        self.ensure_scan(line_no);
+        let line = self.document.get_line(line_no);
+        line.data.scan.as_ref().unwrap().brackets.iter()
+            .map(|&(p, c)| (line.start + p, c))
+            .collect()
+    }
+
+    // The bracket next to the cursor, preferring the outside of a pair
+    // as in "(..)|" or "|(..)".
+    fn bracket_at_cursor(&mut self) -> Option<(usize, char)> {
+        let line_no = self.document.find_line(self.cursor_pos);
+        let line_brackets = self.line_brackets(line_no);
+        let at = |p: usize| line_brackets.iter().find(|&&(q, _)| q == p).cloned();
+        let before = if self.cursor_pos > 0 { at(self.cursor_pos - 1) } else { None };
+        let after = at(self.cursor_pos);
+        let is_open = |b: Option<(usize, char)>| b.map(|(_, c)| brackets::closing(c).is_some());
+        match (before, after) {
+            (Some(b), _) if is_open(Some(b)) == Some(false) => Some(b),
+            (_, Some(a)) if is_open(Some(a)) == Some(true) => Some(a),
+            (b, a) => a.or(b),
+        }
+    }
+
+    // Position of the bracket next to the cursor, and of its match
+    // if there is one.
+    pub fn matching_bracket(&mut self) -> Option<(usize, Option<usize>)> {
+        let (pos, c) = self.bracket_at_cursor()?;
+        Some((pos, self.find_match(pos, c)))
+    }
+
+    // None if the bracket at pos is unmatched or matched with a different kind.
+    fn find_match(&mut self, pos: usize, c: char) -> Option<usize> {
+        let line_no = self.document.find_line(pos);
+        let forward = brackets::closing(c).is_some();
+        let mut stack = Vec::new();
+        let last_line = if forward {
+            (line_no + MAX_BRACKET_SCAN_LINES).min(self.document.num_lines() - 1)
+        } else {
+            line_no.saturating_sub(MAX_BRACKET_SCAN_LINES)
+        };
+        let mut i = line_no;
+        loop {
+            let mut line_brackets = self.line_brackets(i);
+            if forward {
+                line_brackets.retain(|&(p, _)| p > pos);
+            } else {
+                line_brackets.retain(|&(p, _)| p < pos);
+                line_brackets.reverse();
+            }
+            for (p, b) in line_brackets {
+                if (brackets::closing(b).is_some()) == forward {
+                    stack.push(b);
+                } else if stack.pop().is_none() {
+                    let pair_ok = if forward { brackets::closing(c) == Some(b) } else { brackets::closing(b) == Some(c) };
+                    return if pair_ok { Some(p) } else { None };
+                }
+            }
+            if i == last_line {
+                return None;
+            }
+            i = if forward { i + 1 } else { i - 1 };
+        }
+    }
+
+    // Cursor before the bracket goes after the match and vice versa,
+    // so that it stays on the same side of the pair.
+    pub fn go_to_matching_bracket(&mut self, select: bool) -> bool {
+        let (pos, m) = match self.matching_bracket() {
+            Some((pos, Some(m))) => (pos, m),
+            _ => return false,
+        };
+        if select {
+            self.set_selection(pos.min(m), pos.max(m) + 1);
+        } else {
+            let target = if self.cursor_pos == pos { m + 1 } else { m };
+            self.set_selection(target, target);
+        }
+        true
+    }
+
+    // Auto-closes brackets and quotes, and types over the closers
+    // inserted this way.
+    pub fn type_char(&mut self, c: char, auto_close: bool) -> Typed {
+        let next = if self.cursor_pos < self.document.len() {
+            Some(self.document.get_char(self.cursor_pos))
+        } else {
+            None
+        };
+        if !self.has_selection() && next == Some(c) && self.auto_closed.contains(&self.cursor_pos) {
+            let pos = self.cursor_pos;
+            self.auto_closed.retain(|&p| p != pos);
+            self.set_selection(pos + 1, pos + 1);
+            return Typed::Skipped;
+        }
+        let prev = if self.cursor_pos > 0 { Some(self.document.get_char(self.cursor_pos - 1)) } else { None };
+        let closer = if self.syntax.quotes.contains(c) {
+            // not an apostrophe in a word
+            Some(c).filter(|_| !prev.is_some_and(completion::is_word_char))
+        } else {
+            brackets::closing(c)
+        };
+        let closer = closer.filter(|_| {
+            auto_close && !self.has_selection()
+                && next.is_none_or(|n| n.is_whitespace() || ")]};,".contains(n))
+        });
+        self.insert_char(c);
+        match closer {
+            Some(closer) => {
+                self.replace_slice(self.cursor_pos, self.cursor_pos, &[closer]);
+                self.auto_closed.push(self.cursor_pos);
+                Typed::Pair(closer)
+            }
+            None => Typed::Char,
+        }
+    }
+
+    // Backspace between an auto-inserted pair deletes both.
+    pub fn delete_auto_pair(&mut self) -> bool {
+        if self.has_selection() || self.cursor_pos == 0 || !self.auto_closed.contains(&self.cursor_pos) {
+            return false;
+        }
+        let opener = self.document.get_char(self.cursor_pos - 1);
+        let closer = self.document.get_char(self.cursor_pos);
+        if brackets::closing(opener) != Some(closer) && !(opener == closer && self.syntax.quotes.contains(opener)) {
+            return false;
+        }
+        self.cursor_pos -= 1;
+        self.replace_slice(self.cursor_pos, self.cursor_pos + 2, &[]);
+        self.clear_selection();
+        self.ensure_cursor_on_screen();
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+        true
+    }
+
+    // Leading whitespace of the line containing pos.
+    pub fn line_indent(&self, pos: usize) -> String {
+        let line = self.document.get_line(self.document.find_line(pos));
+        (line.start..line.end)
+            .map(|i| self.document.get_char(i))
+            .take_while(|&c| c == ' ' || c == '\t')
+            .collect()
+    }
+
+    // Breaks the line keeping the indentation, one level deeper after
+    // a block opener. Returns what's inserted.
+    pub fn newline_and_indent(&mut self) -> String {
+        let (start, _) = self.selection_range();
+        let line_start = self.document.get_line(self.document.find_line(start)).start;
+        let before = self.document.slice_string(line_start, start);
+        let text = format!("\n{}", indent::newline_indent(&before, self.indent_style));
+        self.paste(&text);
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+        text
+    }
+
+    // Tab without selection. Returns what's inserted.
+    pub fn insert_indent(&mut self) -> String {
+        let line_start = self.document.get_line(self.document.find_line(self.cursor_pos)).start;
+        let text = indent::tab_text(self.cursor_pos - line_start, self.indent_style);
+        self.paste(&text);
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+        text
+    }
+
+    // Adds or removes one level of indentation in the lines touched
+    // by the selection, or in the cursor line.
+    pub fn indent_lines(&mut self, forward: bool) {
+        let (start, end) = self.selection_range();
+        let first = self.document.find_line(start);
+        let mut last = self.document.find_line(end);
+        // the line where the selection ends at the very beginning is not selected
+        if last > first && self.document.get_line(last).start == end {
+            last -= 1;
+        }
+        for i in (first..=last).rev() {
+            let line = self.document.get_line(i);
+            let (line_start, line_end) = (line.start, line.end);
+            if forward {
+                if line_start < line_end {
+                    self.replace_text_keeping_view(line_start, line_start, &self.indent_style.unit());
+                }
+            } else {
+                let n = indent::outdent_len(&self.line_indent(line_start), self.indent_style, self.tab_columns());
+                self.replace_text_keeping_view(line_start, line_start + n, "");
+            }
+        }
+        self.ensure_cursor_on_screen();
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+    }
+
+    // Backspace in the leading spaces removes a whole indentation level.
+    // Returns the number of chars removed, 0 if it's not the case.
+    pub fn smart_backspace(&mut self) -> usize {
+        if self.has_selection() || self.indent_style == IndentStyle::Tabs {
+            return 0;
+        }
+        let line_start = self.document.get_line(self.document.find_line(self.cursor_pos)).start;
+        let before = self.document.slice_string(line_start, self.cursor_pos);
+        if before.is_empty() || before.chars().any(|c| c != ' ') {
+            return 0;
+        }
+        let n = indent::outdent_len(&before, self.indent_style, self.tab_columns());
+        self.cursor_pos -= n;
+        self.replace_slice(self.cursor_pos, self.cursor_pos + n, &[]);
+        self.clear_selection();
+        self.ensure_cursor_on_screen();
+        self.anchor_x = self.pos_to_coord(self.cursor_pos).0;
+        n
+    }
+
+    // Words starting with the one before the cursor, best first.
+    // Only the lines changed since the last call are split into words again.
+    pub fn word_completions(&mut self) -> Vec<String> {
+        let cursor_line = self.document.find_line(self.cursor_pos);
+        let start = self.word_start(self.cursor_pos);
+        let mut end = self.cursor_pos;
+        while end < self.document.len() && completion::is_word_char(self.document.get_char(end)) {
+            end += 1;
+        }
+        let prefix = self.document.slice_string(start, self.cursor_pos);
+        let current_word = self.document.slice_string(start, end);
+        let mut ranker = WordRanker::new(&prefix, &current_word, cursor_line);
+        let first = cursor_line.saturating_sub(WORD_COMPLETION_LINES);
+        let last = (cursor_line + WORD_COMPLETION_LINES).min(self.document.num_lines() - 1);
+        for i in first..=last {
+            let line = self.document.get_line(i);
+            if line.data.words.is_none() {
+                let words = completion::split_words(&self.document.slice_string(line.start, line.end));
+                self.document.get_line_mut(i).data.words = Some(words);
+            }
+            ranker.add_line(i, self.document.get_line(i).data.words.as_ref().unwrap());
+        }
+        ranker.finish()
+    }
+
     pub fn double_click(&mut self, x: f32, y: f32) {
-        let pos = self.coord_to_pos(x, y);
+        let pos = self.coord_to_pos(x + self.scroll_x, y);
         let mut start = pos;
         while start > 0 {
             if !self.document.get_char(start - 1).is_alphanumeric() {
@@ -552,28 +1885,39 @@ impl ViewState {
         let line_no = self.document.find_line(pos);
         self.ensure_layout(line_no);
         let line = self.document.get_line(line_no);
-        let layout = line.data.as_ref().unwrap();
+        let layout = line.data.layout.as_ref().unwrap();
         let (x, y) = layout.cursor_coords(pos - line.start);
         (x, anchor_line_y + self.vertical_offset(anchor_line, line_no) + y)
     }
 
+    // Bottom left corner of the cursor, relative to the view.
+    pub fn cursor_bottom_coords(&mut self) -> (f32, f32) {
+        let (x, y) = self.pos_to_coord(self.cursor_pos);
+        let r = self.cursor_row();
+        (x - self.scroll_x, y + rows::row_height(self, r))
+    }
+
+    // The width includes the gutter.
     pub fn resize(&mut self, width: f32, height: f32) {
         self.width = width;
         self.height = height;
-        for i in 0..self.document.num_lines() {
-            *self.document.get_line_mut(i).data = None;
+        if self.wrap == Wrap::Window {
+            self.scroll_x = 0.0;
         }
+        self.invalidate_layouts();
     }
 
     fn draw_cursor(
         &self,
         x0: f32, y0: f32,
-        line: Line<&Option<TextLayout>>,
+        line: Line<&LineData>,
         rt: &ComPtr<ID2D1HwndRenderTarget>,
         brush: &ComPtr<ID2D1Brush>,
     ) {
         assert!(line.start <= self.cursor_pos && self.cursor_pos <= line.end);
-        let layout = line.data.as_ref().unwrap();
+        let layout = line.data.layout.as_ref().unwrap();
+        let heights = layout.row_heights();
+        let row = layout.row_of(self.cursor_pos - line.start);
         let (x, y) = layout.cursor_coords(self.cursor_pos - line.start);
         let x = x.floor();
         unsafe {
@@ -583,7 +1927,7 @@ impl ViewState {
                     y: y0 + y },
                 D2D1_POINT_2F {
                     x: x0 + x,
-                    y: y0 + y + layout.line_height,
+                    y: y0 + y + heights[row],
                 },
                 brush.as_raw(),
                 2.0,  // strokeWidth
@@ -605,7 +1949,7 @@ impl ViewState {
                     },
                     D2D1_POINT_2F {
                         x: x0 + x,
-                        y: y0 + y + layout.line_height,
+                        y: y0 + y + heights[row - 1],
                     },
                     brush.as_raw(),
                     2.0,  // strokeWidth
@@ -619,28 +1963,122 @@ impl ViewState {
         &mut self,
         origin: D2D1_POINT_2F,
         rt: &ComPtr<ID2D1HwndRenderTarget>,
-        brush: &ComPtr<ID2D1Brush>,
-        selection_brush: &ComPtr<ID2D1Brush>,
+        brushes: Brushes,
     ) {
+        let Brushes {
+            text: brush,
+            selection: selection_brush,
+            current_line: current_line_brush,
+            gutter: gutter_brush,
+            diagnostics: diagnostic_brushes,
+        } = brushes;
         let (anchor_line, anchor_line_y) = self.anchor_line_and_y();
         let (mut y0, line_no1, line_no2) =
             self.lines_on_screen(anchor_line, anchor_line_y);
         let selection_start = self.cursor_pos.min(self.selection_pos);
         let selection_end = self.cursor_pos.max(self.selection_pos);
+        let cursor_line = self.document.find_line(self.cursor_pos);
+        let x0 = origin.x - self.scroll_x;
+        // the matching one, or just the bracket in red if there is no match
+        let highlighted: Vec<(usize, &ComPtr<ID2D1Brush>)> = match self.matching_bracket() {
+            Some((p, Some(m))) => vec![(p, brush), (m, brush)],
+            Some((p, None)) => vec![(p, &diagnostic_brushes[Severity::Error as usize])],
+            None => vec![],
+        };
         for i in line_no1..line_no2 {
+            if self.document.get_line(i).data.hidden {
+                continue;
+            }
             self.ensure_layout(i);
             let line = self.document.get_line(i);
-            let layout = line.data.as_ref().unwrap();
+            let layout = line.data.layout.as_ref().unwrap();
+
+            if i == cursor_line {
+                let rect = D2D1_RECT_F {
+                    left: 0.0,
+                    top: origin.y + y0,
+                    right: origin.x + self.width,
+                    bottom: origin.y + y0 + layout.height,
+                };
+                unsafe {
+                    rt.FillRectangle(&rect, current_line_brush.as_raw());
+                }
+            }
+            let number = match self.line_numbers {
+                LineNumbers::Off => None,
+                LineNumbers::Relative if i != cursor_line => Some(i.abs_diff(cursor_line)),
+                _ => Some(i + 1),
+            };
+            if let Some(number) = number {
+                // only the first row of a wrapped line is numbered
+                let (_x, row_y) = layout.cursor_coords(layout.line_boundaries()[0]);
+                let number_layout = TextLayout::new(
+                    &number.to_string(), &self.dwrite_factory, &self.text_format, 1e6);
+                unsafe {
+                    rt.DrawTextLayout(
+                        D2D1_POINT_2F {
+                            x: origin.x - MARKERS_WIDTH - GUTTER_MARGIN - number_layout.width,
+                            y: origin.y + y0 + row_y,
+                        },
+                        number_layout.raw.as_raw(),
+                        gutter_brush.as_raw(),
+                        D2D1_DRAW_TEXT_OPTIONS_NONE,
+                    );
+                }
+            }
 
+            // gutter marker for the most severe diagnostic starting on the line
+            let worst = self.diagnostics.iter()
+                .filter(|d| line.start <= d.start && d.start <= line.end)
+                .map(|d| d.severity as usize)
+                .min();
+            if let Some(severity) = worst {
+                let rect = D2D1_RECT_F {
+                    left: origin.x - 4.0,
+                    top: origin.y + y0,
+                    right: origin.x - 1.0,
+                    bottom: origin.y + y0 + layout.line_height,
+                };
+                unsafe {
+                    rt.FillRectangle(&rect, diagnostic_brushes[severity].as_raw());
+                }
+            }
+            // a chevron for the folded lines
+            if line.data.folded.is_some() {
+                let x = origin.x - 12.0;
+                let h = layout.line_height;
+                let points = [(x, 0.3 * h), (x + 4.0, 0.5 * h), (x, 0.7 * h)];
+                for w in points.windows(2) {
+                    unsafe {
+                        rt.DrawLine(
+                            D2D1_POINT_2F { x: w[0].0, y: origin.y + y0 + w[0].1 },
+                            D2D1_POINT_2F { x: w[1].0, y: origin.y + y0 + w[1].1 },
+                            brush.as_raw(),
+                            1.5,  // strokeWidth
+                            null_mut(),  // strokeStyle
+                        );
+                    }
+                }
+            }
+            // the text scrolls under the markers
+            let clip = D2D1_RECT_F {
+                left: origin.x - 2.0,
+                top: origin.y + y0,
+                right: origin.x + self.text_width(),
+                bottom: origin.y + y0 + layout.height,
+            };
+            unsafe {
+                rt.PushAxisAlignedClip(&clip, D2D1_ANTIALIAS_MODE_ALIASED);
+            }
             let sel_start = selection_start.max(line.start);
             let sel_end = selection_end.min(line.end + 1);
             if sel_start < sel_end {
                 let rs = layout.get_selection_rects(sel_start - line.start, sel_end - line.start);
                 for (left, top, w, h) in rs {
                     let rect = D2D1_RECT_F {
-                        left: left + origin.x,
+                        left: left + x0,
                         top: top + y0 + origin.y,
-                        right: left + w + origin.x,
+                        right: left + w + x0,
                         bottom: top + h + y0 + origin.y,
                     };
                     unsafe {
@@ -649,29 +2087,69 @@ impl ViewState {
                 }
             }
 
-            unsafe {
-                rt.DrawTextLayout(
-                    D2D1_POINT_2F { x: origin.x, y: origin.y + y0},
-                    layout.raw.as_raw(),
-                    brush.as_raw(),
-                    D2D1_DRAW_TEXT_OPTIONS_NONE,
-                );
+            layout.draw(rt, x0, origin.y + y0, brush);
+            for d in &self.diagnostics {
+                let (start, end) = if d.start == d.end {
+                    // still has to be visible
+                    if d.start < line.start || d.start > line.end {
+                        continue;
+                    } else if d.start < line.end {
+                        (d.start, d.start + 1)
+                    } else if d.start > line.start {
+                        (d.start - 1, d.start)
+                    } else {
+                        continue;
+                    }
+                } else {
+                    let start = d.start.max(line.start);
+                    let end = d.end.min(line.end);
+                    if start >= end {
+                        continue;
+                    }
+                    (start, end)
+                };
+                let rs = layout.get_selection_rects(start - line.start, end - line.start);
+                for (left, top, w, h) in rs {
+                    draw_squiggle(
+                        rt, &diagnostic_brushes[d.severity as usize],
+                        x0 + left, x0 + left + w,
+                        origin.y + y0 + top + h - 3.0);
+                }
+            }
+            for &(p, b) in &highlighted {
+                if line.start <= p && p < line.end {
+                    for (left, top, w, h) in layout.get_selection_rects(p - line.start, p - line.start + 1) {
+                        let rect = D2D1_RECT_F {
+                            left: x0 + left,
+                            top: origin.y + y0 + top,
+                            right: x0 + left + w,
+                            bottom: origin.y + y0 + top + h,
+                        };
+                        unsafe {
+                            rt.DrawRectangle(&rect, b.as_raw(), 1.0, null_mut());
+                        }
+                    }
+                }
             }
             if line.start <= self.cursor_pos && self.cursor_pos <= line.end {
-                self.draw_cursor(origin.x, origin.y + y0, line, rt, brush);
+                self.draw_cursor(x0, origin.y + y0, line, rt, brush);
+            }
+            unsafe {
+                rt.PopAxisAlignedClip();
             }
             y0 += layout.height;
         }
+        self.evict_layouts(line_no1, line_no2);
         // TODO: remove, it's only for debugging
         let (x, y) = self.pos_to_coord(self.anchor_pos);
         unsafe {
             rt.DrawLine(
                 D2D1_POINT_2F {
-                    x: origin.x + x - 2.0,
+                    x: x0 + x - 2.0,
                     y: origin.y + y + 2.0,
                 },
                 D2D1_POINT_2F {
-                    x: origin.x + x + 2.0,
+                    x: x0 + x + 2.0,
                     y: origin.y + y + 2.0,
                 },
                 brush.as_raw(),
@@ -690,10 +2168,7 @@ impl ViewState {
         };
         let mut result = 0.0;
         for i in line_no1..line_no2 {
-            self.ensure_layout(i);
-            let line = self.document.get_line(i);
-            let layout = line.data.as_ref().unwrap();
-            result += layout.height;
+            result += self.visible_height(i);
         }
         result * sign
     }
@@ -705,7 +2180,7 @@ impl ViewState {
         let anchor_line = self.document.find_line(self.anchor_pos);
         self.ensure_layout(anchor_line);
         let line = self.document.get_line(anchor_line);
-        let layout = line.data.as_ref().unwrap();
+        let layout = line.data.layout.as_ref().unwrap();
         let (_x, y) = layout.cursor_coords(self.anchor_pos - line.start);
         let anchor_line_y = self.anchor_y - y;
         (anchor_line, anchor_line_y)
@@ -715,31 +2190,286 @@ impl ViewState {
         let mut i = line_no;
         let mut y = line_y;
         while i > 0 && y > 0.0 {
-            self.ensure_layout(i - 1);
-            let line = self.document.get_line(i - 1);
-            let layout = line.data.as_ref().unwrap();
             i -= 1;
-            y -= layout.height;
+            y -= self.visible_height(i);
         }
         while i < self.document.num_lines() {
-            self.ensure_layout(i);
-            let line = self.document.get_line(i);
-            let layout = line.data.as_ref().unwrap();
-            if y + layout.height > 0.0 {
+            let h = self.visible_height(i);
+            if y + h > 0.0 {
                 break;
             }
             i += 1;
-            y += layout.height;
+            y += h;
         }
         let start_y = y;
         let start_line = i;
         while i < self.document.num_lines() && y < self.height {
-            self.ensure_layout(i);
-            let line = self.document.get_line(i);
-            let layout = line.data.as_ref().unwrap();
+            y += self.visible_height(i);
             i += 1;
-            y += layout.height;
         }
         (start_y, start_line, i)
     }
 }
+
+impl Rows for ViewState {
+    fn num_lines(&self) -> usize {
+        self.document.num_lines()
+    }
+
+    fn row_heights(&mut self, line_no: usize) -> Vec<f32> {
+        if self.document.get_line(line_no).data.hidden {
+            return Vec::new();
+        }
+        self.ensure_layout(line_no);
+        self.document.get_line(line_no).data.layout.as_ref().unwrap().row_heights()
+    }
+}
+
+fn draw_squiggle(
+    rt: &ComPtr<ID2D1HwndRenderTarget>,
+    brush: &ComPtr<ID2D1Brush>,
+    x1: f32, x2: f32, y: f32,
+) {
+    const STEP: f32 = 2.0;
+    let mut x = x1;
+    let mut up = true;
+    while x < x2 {
+        let next_x = (x + STEP).min(x2);
+        let (y1, y2) = if up { (y + STEP, y) } else { (y, y + STEP) };
+        unsafe {
+            rt.DrawLine(
+                D2D1_POINT_2F { x, y: y1 },
+                D2D1_POINT_2F { x: next_x, y: y2 },
+                brush.as_raw(),
+                1.0,  // strokeWidth
+                null_mut(),  // strokeStyle
+            );
+        }
+        x = next_x;
+        up = !up;
+    }
+}
+
+// DirectWrite doesn't need a window, so the editing core
+// can be exercised in tests as is.
+#[cfg(test)]
+pub fn create_headless_view_state(width: f32, height: f32) -> ViewState {
+    use winapi::Interface;
+    use winapi::shared::winerror::S_OK;
+
+    let dwrite_factory: ComPtr<IDWriteFactory> = unsafe {
+        let mut dwrite_factory = null_mut();
+        let hr = DWriteCreateFactory(
+            DWRITE_FACTORY_TYPE_SHARED,
+            &IDWriteFactory::uuidof(),
+            &mut dwrite_factory,
+        );
+        assert!(hr == S_OK, "0x{:x}", hr);
+        ComPtr::from_raw(dwrite_factory as *mut _)
+    };
+    let text_format = super::create_text_format(&dwrite_factory, super::DEFAULT_FONT_SIZE);
+    ViewState::new(width, height, text_format, dwrite_factory)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn laid_out_lines(vs: &ViewState) -> usize {
+        (0..vs.document.num_lines())
+            .filter(|&i| vs.document.get_line(i).data.layout.is_some())
+            .count()
+    }
+
+    // (folded line, number of lines it hides) and the hidden lines
+    fn folds(vs: &ViewState) -> (Vec<(usize, usize)>, Vec<usize>) {
+        let lines = 0..vs.document.num_lines();
+        let hidden: Vec<usize> = lines.clone().filter(|&i| vs.document.get_line(i).data.hidden).collect();
+        assert_eq!(vs.hidden_lines, hidden.len());
+        (lines.filter_map(|i| vs.document.get_line(i).data.folded.map(|n| (i, n))).collect(), hidden)
+    }
+
+    const NESTED: &str = "fn f() {\n    if x {\n        a;\n    }\n}\nafter\n";
+
+    #[test]
+    fn nested_folds() {
+        let mut vs = create_headless_view_state(800.0, 600.0);
+        vs.load(NESTED, false);
+        vs.fold_all();
+        assert_eq!(folds(&vs), (vec![(0, 3), (1, 1)], vec![1, 2, 3]));
+
+        // the inner fold stays folded
+        assert!(vs.unfold());
+        assert_eq!(folds(&vs), (vec![(1, 1)], vec![2]));
+        // and the outer one folds over it
+        assert!(vs.fold());
+        assert_eq!(folds(&vs), (vec![(0, 3), (1, 1)], vec![1, 2, 3]));
+
+        vs.unfold_all();
+        assert_eq!(folds(&vs), (vec![], vec![]));
+        // innermost first
+        let pos = vs.line_col_to_pos(2, 0);
+        vs.set_selection(pos, pos);
+        assert!(vs.fold());
+        assert_eq!(folds(&vs), (vec![(1, 1)], vec![2]));
+        assert_eq!(vs.document.find_line(vs.cursor_pos), 1);
+        assert!(vs.fold());
+        assert_eq!(folds(&vs), (vec![(0, 3), (1, 1)], vec![1, 2, 3]));
+        assert_eq!(vs.document.find_line(vs.cursor_pos), 0);
+    }
+
+    #[test]
+    fn editing_folds() {
+        let mut vs = create_headless_view_state(800.0, 600.0);
+        vs.load(NESTED, false);
+        vs.make_undo_snapshot();
+
+        // typing on the folded line keeps the folds
+        vs.fold_all();
+        vs.replace_text(0, 0, "pub ");
+        assert_eq!(folds(&vs), (vec![(0, 3), (1, 1)], vec![1, 2, 3]));
+
+        // an edit inside unfolds everything around it
+        let pos = vs.line_col_to_pos(2, 8);
+        vs.replace_text(pos, pos + 1, "b");
+        assert_eq!(folds(&vs), (vec![], vec![]));
+        assert_eq!(vs.document.slice_string(pos, pos + 2), "b;");
+
+        // so does breaking the folded line, with the folds inside
+        vs.fold_all();
+        vs.replace_text(0, 0, "\n");
+        assert_eq!(folds(&vs), (vec![], vec![]));
+
+        // the folds below an edit move with their lines
+        let pos = vs.line_col_to_pos(3, 0);
+        vs.set_selection(pos, pos);
+        assert!(vs.fold());
+        vs.replace_text(0, 0, "// x\n");
+        assert_eq!(folds(&vs), (vec![(3, 1)], vec![4]));
+    }
+
+    #[test]
+    fn layout_count() {
+        let mut vs = create_headless_view_state(800.0, 600.0);
+        vs.load(&"a line\n".repeat(100), false);
+        vs.make_undo_snapshot();
+        let check = |vs: &ViewState| assert_eq!(vs.layout_cache.count(), laid_out_lines(vs));
+        for i in 0..50 {
+            vs.ensure_layout(i);
+        }
+        check(&vs);
+
+        // the edited lines lose their layouts
+        let (start, end) = (vs.line_col_to_pos(2, 0), vs.line_col_to_pos(5, 3));
+        vs.replace_text(start, end, "x\ny");
+        check(&vs);
+        vs.replace_text(0, 0, "\n\n");
+        check(&vs);
+        vs.undo();
+        check(&vs);
+        vs.append_loaded("more\n");
+        check(&vs);
+        vs.fold_all();
+        check(&vs);
+
+        vs.set_layout_cache_capacity(10);
+        vs.evict_layouts(0, 5);
+        check(&vs);
+        assert!(laid_out_lines(&vs) <= 10);
+    }
+
+    #[test]
+    fn exact_scroll_position() {
+        let mut vs = create_headless_view_state(800.0, 600.0);
+        let text = format!("{}\n{}", "word ".repeat(1000), "short\n".repeat(1000));
+        vs.load(&text, false);
+        // a wrapped line above the view counts with its height
+        let mut above = 0.0;
+        for i in 0..10 {
+            vs.ensure_layout(i);
+            above += vs.document.get_line(i).data.layout.as_ref().unwrap().height;
+        }
+        vs.anchor_pos = vs.line_col_to_pos(10, 0);
+        vs.anchor_y = 0.0;
+        let (_, top, _) = vs.scroll_position();
+        assert!((top - above).abs() < 0.01, "{} {}", top, above);
+
+        vs.scroll_to(top + 1.0);
+        let (_, top2, _) = vs.scroll_position();
+        assert!((top2 - top - 1.0).abs() < 0.01, "{} {}", top2, top);
+    }
+
+    #[test]
+    fn bounded_bracket_scan() {
+        let mut vs = create_headless_view_state(800.0, 600.0);
+        let n = 3 * MAX_BRACKET_SCAN_LINES;
+        let text: String = (0..n).map(|i| format!("f({});\n", i)).collect();
+        vs.load(&text, false);
+        let scanned = |vs: &ViewState| (0..vs.document.num_lines())
+            .filter(|&i| vs.document.get_line(i).data.scan.is_some())
+            .count();
+
+        // after the ) on the last line, nothing far above is scanned
+        let pos = vs.line_col_to_pos(n - 1, 8);
+        vs.set_selection(pos, pos);
+        assert_eq!(vs.matching_bracket(), Some((pos - 1, Some(pos - 7))));
+        assert_eq!(scanned(&vs), MAX_BRACKET_SCAN_LINES + 1);
+
+        // nor in between on the way back
+        let pos = vs.line_col_to_pos(0, 2);
+        vs.set_selection(pos, pos);
+        assert_eq!(vs.matching_bracket(), Some((pos - 1, Some(pos + 1))));
+        assert_eq!(scanned(&vs), MAX_BRACKET_SCAN_LINES + 2);
+    }
+
+    fn long_document(num_lines: usize) -> ViewState {
+        let mut vs = create_headless_view_state(800.0, 600.0);
+        let text: String = (0..num_lines)
+            .map(|i| format!("line {} of the (synthetic) document\n", i))
+            .collect();
+        vs.load(&text, false);
+        vs
+    }
+
+    fn go_to_line(vs: &mut ViewState, line_no: usize) {
+        let pos = vs.line_col_to_pos(line_no, 5);
+        vs.set_selection(pos, pos);
+    }
+
+    // The jump and what paint does after it.
+    fn jump(vs: &mut ViewState, f: impl FnOnce(&mut ViewState)) {
+        f(vs);
+        vs.matching_bracket();
+    }
+
+    #[test]
+    fn long_jumps() {
+        let mut vs = long_document(100_000);
+
+        // each jump lays out a few screens, not the lines in between
+        jump(&mut vs, ViewState::ctrl_end);
+        assert!(laid_out_lines(&vs) < 200, "{}", laid_out_lines(&vs));
+
+        jump(&mut vs, |vs| go_to_line(vs, 50_000));
+        assert!(laid_out_lines(&vs) < 350, "{}", laid_out_lines(&vs));
+
+        jump(&mut vs, ViewState::ctrl_home);
+        assert!(laid_out_lines(&vs) < 500, "{}", laid_out_lines(&vs));
+        assert_eq!(vs.document.find_line(vs.anchor_pos), 0);
+    }
+
+    // cargo test --release -- --ignored --nocapture long_jumps_benchmark
+    #[test]
+    #[ignore]
+    fn long_jumps_benchmark() {
+        let mut vs = long_document(500_000);
+        let mut timed = |name: &str, f: &dyn Fn(&mut ViewState)| {
+            let start = std::time::Instant::now();
+            jump(&mut vs, f);
+            println!("{}: {:?}", name, start.elapsed());
+        };
+        timed("Ctrl+End", &ViewState::ctrl_end);
+        timed("go to line", &|vs| go_to_line(vs, 250_000));
+        timed("Ctrl+Home", &ViewState::ctrl_home);
+    }
+}
@@ -4,10 +4,17 @@ use std::io::Error;
 use std::ptr::{null, null_mut};
 use std::cell::RefCell;
 use std::path::PathBuf;
+use std::fs::File;
+use std::os::windows::io::AsRawHandle;
 
+use winapi::shared::basetsd::INT_PTR;
 use winapi::shared::minwindef::*;
 use winapi::shared::windef::*;
-use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::um::libloaderapi::{GetModuleHandleW, GetProcAddress};
+use winapi::um::wingdi::{GetDeviceCaps, LOGPIXELSX};
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::memoryapi::{CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_READ};
+use winapi::um::winnt::PAGE_READONLY;
 use winapi::um::winbase::*;
 use winapi::um::winuser::*;
 use winapi::um::commdlg::*;
@@ -77,7 +84,7 @@ pub fn create_window(class_name: &str, title: &str, wnd_proc: WNDPROC) -> Result
             0,  // dwExStyle
             class_name.as_ptr(),  // lpClassName
             title.as_ptr(),  // lpWindowName
-            WS_OVERLAPPEDWINDOW | WS_VISIBLE,  // dwStyle
+            WS_OVERLAPPEDWINDOW | WS_VISIBLE | WS_VSCROLL | WS_HSCROLL,  // dwStyle
             CW_USEDEFAULT,  // x
             CW_USEDEFAULT,  // y
             CW_USEDEFAULT,  // nWidth
@@ -96,6 +103,66 @@ pub fn create_window(class_name: &str, title: &str, wnd_proc: WNDPROC) -> Result
     }
 }
 
+// A user32 function that older Windows don't have, linking to it
+// directly would fail to start there.
+fn user32_proc(name: &str) -> Option<*const c_void> {
+    let name = format!("{}\0", name);
+    unsafe {
+        let module = GetModuleHandleW(win32_string("user32.dll").as_ptr());
+        if module.is_null() {
+            return None;
+        }
+        let proc = GetProcAddress(module, name.as_ptr() as *const c_char);
+        (!proc.is_null()).then_some(proc as *const c_void)
+    }
+}
+
+// Before any windows are created. Returns false on Windows older than
+// 10 1703, where the system scales the window instead.
+pub fn enable_per_monitor_dpi_awareness() -> bool {
+    type SetContext = unsafe extern "system" fn(DPI_AWARENESS_CONTEXT) -> BOOL;
+    match user32_proc("SetProcessDpiAwarenessContext") {
+        Some(proc) => unsafe {
+            let set_context: SetContext = std::mem::transmute(proc);
+            set_context(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) != 0
+        }
+        None => false,
+    }
+}
+
+// Before Windows 10 1607 there is only the system DPI.
+pub fn get_dpi(hwnd: HWND) -> f32 {
+    type GetDpi = unsafe extern "system" fn(HWND) -> UINT;
+    let dpi = match user32_proc("GetDpiForWindow") {
+        Some(proc) => unsafe {
+            let get_dpi: GetDpi = std::mem::transmute(proc);
+            get_dpi(hwnd) as i32
+        }
+        None => unsafe {
+            let dc = GetDC(hwnd);
+            let dpi = GetDeviceCaps(dc, LOGPIXELSX);
+            ReleaseDC(hwnd, dc);
+            dpi
+        }
+    };
+    match dpi {
+        0 => 96.0,
+        dpi => dpi as f32,
+    }
+}
+
+// Can send WM_SIZE.
+pub fn set_window_rect(app_state: &mut Token<impl HasHwnd>, rect: &RECT) {
+    let hwnd = app_state.borrow_mut().hwnd();
+    let res = unsafe {
+        SetWindowPos(
+            hwnd, null_mut(),
+            rect.left, rect.top, rect.right - rect.left, rect.bottom - rect.top,
+            SWP_NOZORDER | SWP_NOACTIVATE)
+    };
+    assert!(res != 0, "{}", Error::last_os_error());
+}
+
 pub fn set_window_title(hwnd: HWND, title: &str) {
     unsafe {
         let res = SetWindowTextW(hwnd, win32_string(title).as_ptr());
@@ -110,6 +177,93 @@ pub fn invalidate_rect(hwnd: HWND) {
     }
 }
 
+pub fn set_timer(hwnd: HWND, id: usize, ms: u32) {
+    let res = unsafe { SetTimer(hwnd, id, ms, None) };
+    assert!(res != 0, "{}", Error::last_os_error());
+}
+
+// It's fine if the timer is not there.
+pub fn kill_timer(hwnd: HWND, id: usize) {
+    unsafe {
+        KillTimer(hwnd, id);
+    }
+}
+
+// Read-only view of a whole file. While it's mapped the file
+// can't be truncated, but other processes can still write to it.
+pub struct FileMapping {
+    view: *const u8,
+    len: usize,
+}
+
+impl FileMapping {
+    // The file can't be empty.
+    pub fn new(file: &File, len: usize) -> Result<FileMapping, Error> {
+        unsafe {
+            let mapping = CreateFileMappingW(
+                file.as_raw_handle() as *mut _,
+                null_mut(),  // lpFileMappingAttributes
+                PAGE_READONLY,
+                0, 0,  // dwMaximumSizeHigh, dwMaximumSizeLow: the whole file
+                null(),  // lpName
+            );
+            if mapping.is_null() {
+                return Err(Error::last_os_error());
+            }
+            let view = MapViewOfFile(mapping, FILE_MAP_READ, 0, 0, 0);
+            let err = Error::last_os_error();
+            // the view keeps the mapping alive
+            let res = CloseHandle(mapping);
+            assert!(res != 0, "{}", Error::last_os_error());
+            if view.is_null() {
+                return Err(err);
+            }
+            Ok(FileMapping { view: view as *const u8, len })
+        }
+    }
+}
+
+impl AsRef<[u8]> for FileMapping {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.view, self.len) }
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        let res = unsafe { UnmapViewOfFile(self.view as *mut _) };
+        assert!(res != 0, "{}", Error::last_os_error());
+    }
+}
+
+// Range and page are in the same units. The scroll bar stays
+// (disabled) when everything fits, so that the client area doesn't change.
+// Can send WM_SIZE.
+pub fn set_scroll_info(app_state: &mut Token<impl HasHwnd>, bar: UINT, max: i32, page: u32, pos: i32) {
+    let hwnd = app_state.borrow_mut().hwnd();
+    unsafe {
+        let mut si: SCROLLINFO = std::mem::zeroed();
+        si.cbSize = std::mem::size_of::<SCROLLINFO>() as UINT;
+        si.fMask = SIF_RANGE | SIF_PAGE | SIF_POS | SIF_DISABLENOSCROLL;
+        si.nMax = max;
+        si.nPage = page;
+        si.nPos = pos;
+        SetScrollInfo(hwnd, bar as c_int, &si, 1);
+    }
+}
+
+// The position in WM_VSCROLL and WM_HSCROLL is only 16 bits.
+pub fn get_scroll_track_pos(hwnd: HWND, bar: UINT) -> i32 {
+    unsafe {
+        let mut si: SCROLLINFO = std::mem::zeroed();
+        si.cbSize = std::mem::size_of::<SCROLLINFO>() as UINT;
+        si.fMask = SIF_TRACKPOS;
+        let res = GetScrollInfo(hwnd, bar as c_int, &mut si);
+        assert!(res != 0, "{}", Error::last_os_error());
+        si.nTrackPos
+    }
+}
+
 // Why not just write `p as *mut T2`?
 // Because then when casting from say *mut void to *mut u16,
 // Clippy complains about pointer alignment
@@ -314,3 +468,240 @@ pub fn send_message(
         SendMessageW(hwnd, msg, w_param, l_param)
     }
 }
+
+struct InputBoxState {
+    prompt: String,
+    text: String,
+    ok: bool,
+}
+
+const ID_INPUT_BOX_PROMPT: c_int = 100;
+const ID_INPUT_BOX_EDIT: c_int = 101;
+
+fn get_window_text(hwnd: HWND) -> String {
+    unsafe {
+        let len = GetWindowTextLengthW(hwnd);
+        let mut buf: Vec<u16> = vec![0; len as usize + 1];
+        let len = GetWindowTextW(hwnd, buf.as_mut_ptr(), buf.len() as c_int);
+        OsString::from_wide(&buf[..len as usize]).to_string_lossy().into_owned()
+    }
+}
+
+extern "system"
+fn input_box_proc(hwnd: HWND, msg: UINT, w_param: WPARAM, l_param: LPARAM) -> INT_PTR {
+    match msg {
+        WM_INITDIALOG => {
+            let state = unsafe { &*(l_param as *const InputBoxState) };
+            unsafe {
+                SetWindowLongPtrW(hwnd, GWLP_USERDATA, l_param);
+                let res = SetDlgItemTextW(
+                    hwnd, ID_INPUT_BOX_PROMPT, win32_string(&state.prompt).as_ptr());
+                assert!(res != 0, "{}", Error::last_os_error());
+                let res = SetDlgItemTextW(
+                    hwnd, ID_INPUT_BOX_EDIT, win32_string(&state.text).as_ptr());
+                assert!(res != 0, "{}", Error::last_os_error());
+                SendDlgItemMessageW(hwnd, ID_INPUT_BOX_EDIT, EM_SETSEL as UINT, 0, -1);
+            }
+            // let the system focus the edit control
+            TRUE as INT_PTR
+        }
+        WM_COMMAND => {
+            let id = LOWORD(w_param as u32) as c_int;
+            if id == IDOK || id == IDCANCEL {
+                unsafe {
+                    let state = &mut *(GetWindowLongPtrW(hwnd, GWLP_USERDATA) as *mut InputBoxState);
+                    if id == IDOK {
+                        state.text = get_window_text(GetDlgItem(hwnd, ID_INPUT_BOX_EDIT));
+                        state.ok = true;
+                    }
+                    let res = EndDialog(hwnd, id as INT_PTR);
+                    assert!(res != 0, "{}", Error::last_os_error());
+                }
+                TRUE as INT_PTR
+            } else {
+                FALSE as INT_PTR
+            }
+        }
+        _ => FALSE as INT_PTR,
+    }
+}
+
+// In-memory dialog template, see
+// https://docs.microsoft.com/en-us/windows/desktop/dlgbox/dlgtemplate
+// and
+// https://docs.microsoft.com/en-us/windows/desktop/dlgbox/dlgitemtemplate
+struct DialogTemplate(Vec<u16>);
+
+impl DialogTemplate {
+    fn new(style: DWORD, title: &str, num_items: u16, cx: i16, cy: i16) -> Self {
+        let mut t = DialogTemplate(Vec::new());
+        t.dword(style | DS_SETFONT);
+        t.dword(0);  // dwExtendedStyle
+        t.0.push(num_items);
+        t.0.extend([0, 0, cx as u16, cy as u16].iter());
+        t.0.push(0);  // no menu
+        t.0.push(0);  // default class
+        t.string(title);
+        t.0.push(9);  // font size
+        t.string("Segoe UI");
+        t
+    }
+
+    fn item(&mut self, style: DWORD, class_atom: u16, text: &str, id: c_int, rect: [i16; 4]) {
+        // items are DWORD-aligned
+        if self.0.len() % 2 == 1 {
+            self.0.push(0);
+        }
+        self.dword(style | WS_CHILD | WS_VISIBLE);
+        self.dword(0);  // dwExtendedStyle
+        self.0.extend(rect.iter().map(|&x| x as u16));
+        self.0.push(id as u16);
+        self.0.push(0xFFFF);
+        self.0.push(class_atom);
+        self.string(text);
+        self.0.push(0);  // no creation data
+    }
+
+    fn dword(&mut self, x: DWORD) {
+        self.0.push(x as u16);
+        self.0.push((x >> 16) as u16);
+    }
+
+    fn string(&mut self, s: &str) {
+        self.0.extend(win32_string(s));
+    }
+
+    // The template itself has to be DWORD-aligned.
+    fn to_aligned(&self) -> Vec<u32> {
+        self.0.chunks(2)
+            .map(|c| u32::from(c[0]) | u32::from(*c.get(1).unwrap_or(&0)) << 16)
+            .collect()
+    }
+}
+
+// Modal dialog with a single line text field.
+// Returns None if it was cancelled.
+pub fn input_box(
+    app_state: &mut Token<impl HasHwnd>,
+    title: &str,
+    prompt: &str,
+    text: &str,
+) -> Option<String> {
+    const BUTTON: u16 = 0x0080;
+    const EDIT: u16 = 0x0081;
+    const STATIC: u16 = 0x0082;
+    let mut t = DialogTemplate::new(
+        DS_MODALFRAME | DS_CENTER | WS_POPUP | WS_CAPTION | WS_SYSMENU,
+        title, 4, 220, 62);
+    t.item(0, STATIC, "", ID_INPUT_BOX_PROMPT, [7, 7, 206, 10]);
+    t.item(WS_BORDER | WS_TABSTOP | ES_AUTOHSCROLL, EDIT, "", ID_INPUT_BOX_EDIT, [7, 20, 206, 14]);
+    t.item(WS_TABSTOP | BS_DEFPUSHBUTTON, BUTTON, "OK", IDOK, [109, 41, 50, 14]);
+    t.item(WS_TABSTOP, BUTTON, "Cancel", IDCANCEL, [163, 41, 50, 14]);
+    let template = t.to_aligned();
+
+    let mut state = InputBoxState {
+        prompt: prompt.to_owned(),
+        text: text.to_owned(),
+        ok: false,
+    };
+    let hwnd = app_state.borrow_mut().hwnd();
+    let res = unsafe {
+        DialogBoxIndirectParamW(
+            GetModuleHandleW(null_mut()),
+            template.as_ptr() as LPCDLGTEMPLATEW,
+            hwnd,
+            Some(input_box_proc),
+            &mut state as *mut InputBoxState as LPARAM)
+    };
+    assert!(res != -1 && res != 0, "{}", Error::last_os_error());
+    if state.ok {
+        Some(state.text)
+    } else {
+        None
+    }
+}
+
+struct ListBoxState {
+    items: Vec<String>,
+    selected: Option<usize>,
+}
+
+const ID_LIST_BOX_LIST: c_int = 100;
+
+extern "system"
+fn list_box_proc(hwnd: HWND, msg: UINT, w_param: WPARAM, l_param: LPARAM) -> INT_PTR {
+    match msg {
+        WM_INITDIALOG => {
+            let state = unsafe { &*(l_param as *const ListBoxState) };
+            unsafe {
+                SetWindowLongPtrW(hwnd, GWLP_USERDATA, l_param);
+                for item in &state.items {
+                    let res = SendDlgItemMessageW(
+                        hwnd, ID_LIST_BOX_LIST, LB_ADDSTRING, 0,
+                        win32_string(item).as_ptr() as LPARAM);
+                    assert!(res >= 0);
+                }
+                let sel = state.selected.unwrap_or(0);
+                SendDlgItemMessageW(hwnd, ID_LIST_BOX_LIST, LB_SETCURSEL, sel, 0);
+            }
+            TRUE as INT_PTR
+        }
+        WM_COMMAND => {
+            let id = LOWORD(w_param as u32) as c_int;
+            let double_click =
+                id == ID_LIST_BOX_LIST && HIWORD(w_param as u32) == LBN_DBLCLK;
+            if id == IDOK || id == IDCANCEL || double_click {
+                unsafe {
+                    let state = &mut *(GetWindowLongPtrW(hwnd, GWLP_USERDATA) as *mut ListBoxState);
+                    state.selected = None;
+                    if id != IDCANCEL {
+                        let sel = SendDlgItemMessageW(hwnd, ID_LIST_BOX_LIST, LB_GETCURSEL, 0, 0);
+                        if sel >= 0 {
+                            state.selected = Some(sel as usize);
+                        }
+                    }
+                    let res = EndDialog(hwnd, id as INT_PTR);
+                    assert!(res != 0, "{}", Error::last_os_error());
+                }
+                TRUE as INT_PTR
+            } else {
+                FALSE as INT_PTR
+            }
+        }
+        _ => FALSE as INT_PTR,
+    }
+}
+
+// Modal dialog to pick one of the items.
+// Returns None if it was cancelled.
+pub fn list_box(
+    app_state: &mut Token<impl HasHwnd>,
+    title: &str,
+    items: Vec<String>,
+    selected: Option<usize>,
+) -> Option<usize> {
+    const BUTTON: u16 = 0x0080;
+    const LISTBOX: u16 = 0x0083;
+    let mut t = DialogTemplate::new(
+        DS_MODALFRAME | DS_CENTER | WS_POPUP | WS_CAPTION | WS_SYSMENU,
+        title, 3, 400, 200);
+    t.item(
+        WS_BORDER | WS_TABSTOP | WS_VSCROLL | LBS_NOTIFY | LBS_NOINTEGRALHEIGHT,
+        LISTBOX, "", ID_LIST_BOX_LIST, [7, 7, 386, 165]);
+    t.item(WS_TABSTOP | BS_DEFPUSHBUTTON, BUTTON, "OK", IDOK, [289, 179, 50, 14]);
+    t.item(WS_TABSTOP, BUTTON, "Cancel", IDCANCEL, [343, 179, 50, 14]);
+    let template = t.to_aligned();
+
+    let mut state = ListBoxState { items, selected };
+    let hwnd = app_state.borrow_mut().hwnd();
+    let res = unsafe {
+        DialogBoxIndirectParamW(
+            GetModuleHandleW(null_mut()),
+            template.as_ptr() as LPCDLGTEMPLATEW,
+            hwnd,
+            Some(list_box_proc),
+            &mut state as *mut ListBoxState as LPARAM)
+    };
+    assert!(res != -1 && res != 0, "{}", Error::last_os_error());
+    state.selected
+}